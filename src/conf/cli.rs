@@ -18,6 +18,10 @@ pub struct Cli {
     pub mountsource: Option<String>,
     #[arg(short = 'p', long = "partitions", value_delimiter = ',')]
     pub partitions: Vec<String>,
+    #[arg(short = 'r', long = "root", global = true)]
+    pub root: Option<PathBuf>,
+    #[arg(long = "replay", global = true)]
+    pub replay: Option<PathBuf>,
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -42,12 +46,51 @@ pub enum Commands {
         payload: String,
     },
     Modules,
+    Plan,
     Conflicts,
     Diagnostics,
+    Bake {
+        #[arg(short = 'o', long = "out")]
+        out: PathBuf,
+    },
+    Capture {
+        #[arg(short = 'o', long = "out")]
+        out: PathBuf,
+        #[arg(long)]
+        full: bool,
+    },
+    #[command(name = "magic-tree")]
+    MagicTree {
+        #[arg(long)]
+        json: bool,
+    },
+    Bugreport {
+        #[arg(short = 'o', long = "out")]
+        out: Option<PathBuf>,
+    },
+    #[command(name = "rebuild-image")]
+    RebuildImage,
+    #[command(name = "storage-stats")]
+    StorageStats,
+    #[command(name = "verify-storage")]
+    VerifyStorage,
+    Gc {
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Rollback {
+        module: String,
+        #[arg(long)]
+        to: Option<String>,
+    },
     Poaceae {
         #[arg(short, long, default_value = defs::POACEAE_MOUNT_POINT)]
         target: String,
 
+        /// Use an in-process stand-in keeping its rules as JSON in the `target` file
+        #[arg(long)]
+        fake: bool,
+
         #[command(subcommand)]
         action: PoaceaeAction,
     },
@@ -94,4 +137,9 @@ pub enum PoaceaeAction {
     Trust {
         gid: u32,
     },
+    List,
+    Sync {
+        #[arg(long)]
+        from: PathBuf,
+    },
 }
@@ -1,16 +1,30 @@
-use std::{fs::File, path::Path};
+use std::{collections::HashSet, fs, path::Path};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use serde::Serialize;
 
 use crate::{
     conf::{
         cli::{Cli, PoaceaeAction},
-        config::{self, Config},
+        config::{self, Config, PoaceaeRules, SpoofRule},
+    },
+    core::{
+        bugreport,
+        capture::{self, Replay},
+        inventory::{self, model as modules, versions},
+        ops::{
+            bake, dedup,
+            exclude::ExcludeSet,
+            gc::{self, Leftover},
+            manifest::{Drift, SyncManifest},
+            planner, poaceae as poaceae_rules,
+        },
+        state::RuntimeState,
+        storage,
     },
-    core::{inventory, inventory::model as modules, ops::planner},
     defs,
-    sys::poaceae,
+    mount::node::NodeSnapshot,
+    sys::{caps::Capabilities, mount, poaceae},
     utils,
 };
 
@@ -52,6 +66,25 @@ fn load_config(cli: &Cli) -> Result<Config> {
     }
 }
 
+fn load_final_config(cli: &Cli) -> Result<(Config, Option<Replay>)> {
+    let (mut config, replay) = match &cli.replay {
+        Some(archive) => {
+            let replay = Replay::open(archive)
+                .with_context(|| format!("Failed to replay capture {}", archive.display()))?;
+            (replay.config.clone(), Some(replay))
+        }
+        None => (load_config(cli)?, None),
+    };
+
+    config.merge_with_cli(
+        cli.moduledir.clone(),
+        cli.mountsource.clone(),
+        cli.partitions.clone(),
+        cli.root.clone(),
+    );
+    Ok((config, replay))
+}
+
 pub fn handle_gen_config(output: &Path) -> Result<()> {
     Config::default()
         .save_to_file(output)
@@ -111,13 +144,29 @@ pub fn handle_save_module_rules(module_id: &str, payload: &str) -> Result<()> {
 }
 
 pub fn handle_modules(cli: &Cli) -> Result<()> {
-    let config = load_config(cli)?;
+    let (config, _replay) = load_final_config(cli)?;
 
     modules::print_list(&config).context("Failed to list modules")
 }
 
+pub fn handle_plan(cli: &Cli) -> Result<()> {
+    let (config, _replay) = load_final_config(cli)?;
+
+    let module_list =
+        inventory::scan(&config.moduledir, &config).context("Failed to scan modules for plan")?;
+
+    let plan = planner::generate(&config, &module_list, &config.moduledir)
+        .context("Failed to generate mount plan")?;
+
+    let json = serde_json::to_string(&plan).context("Failed to serialize mount plan")?;
+
+    println!("{}", json);
+
+    Ok(())
+}
+
 pub fn handle_conflicts(cli: &Cli) -> Result<()> {
-    let config = load_config(cli)?;
+    let (config, _replay) = load_final_config(cli)?;
 
     let module_list = inventory::scan(&config.moduledir, &config)
         .context("Failed to scan modules for conflict analysis")?;
@@ -136,7 +185,7 @@ pub fn handle_conflicts(cli: &Cli) -> Result<()> {
 }
 
 pub fn handle_diagnostics(cli: &Cli) -> Result<()> {
-    let config = load_config(cli)?;
+    let (config, replay) = load_final_config(cli)?;
 
     let module_list = inventory::scan(&config.moduledir, &config)
         .context("Failed to scan modules for diagnostics")?;
@@ -144,7 +193,81 @@ pub fn handle_diagnostics(cli: &Cli) -> Result<()> {
     let plan = planner::generate(&config, &module_list, &config.moduledir)
         .context("Failed to generate plan for diagnostics")?;
 
-    let report = plan.analyze();
+    let mut report = plan.analyze();
+
+    let capabilities = match &replay {
+        Some(replay) => replay.capabilities.clone(),
+        None => Capabilities::probe(),
+    };
+
+    if matches!(config.overlay_mode, config::OverlayMode::Erofs)
+        && !capabilities.supports_fs("erofs")
+    {
+        report.diagnostics.push(planner::DiagnosticIssue {
+            level: planner::DiagnosticLevel::Warning,
+            context: "storage".to_string(),
+            message: "EROFS backend selected but kernel does not support erofs".to_string(),
+        });
+    }
+
+    if matches!(config.overlay_mode, config::OverlayMode::Squashfs)
+        && !capabilities.supports_fs("squashfs")
+    {
+        report.diagnostics.push(planner::DiagnosticIssue {
+            level: planner::DiagnosticLevel::Warning,
+            context: "storage".to_string(),
+            message: "SquashFS backend selected but kernel does not support squashfs".to_string(),
+        });
+    }
+
+    if matches!(config.overlay_mode, config::OverlayMode::Tmpfs) && !capabilities.tmpfs_xattr {
+        let fallback = if capabilities.supports_fs("squashfs") {
+            "squashfs"
+        } else {
+            "ext4"
+        };
+        report.diagnostics.push(planner::DiagnosticIssue {
+            level: planner::DiagnosticLevel::Warning,
+            context: "storage".to_string(),
+            message: format!(
+                "Tmpfs lacks xattr support (CONFIG_TMPFS_XATTR), {} image will be used",
+                fallback
+            ),
+        });
+    }
+
+    let poaceae_mounted = match &replay {
+        Some(replay) => replay.is_mounted(Path::new(defs::POACEAE_MOUNT_POINT)),
+        None => mount::is_mounted(defs::POACEAE_MOUNT_POINT),
+    };
+
+    if !poaceae_mounted && (!plan.poaceae_ops.is_empty() || !plan.poaceae.is_empty()) {
+        report.diagnostics.push(planner::DiagnosticIssue {
+            level: planner::DiagnosticLevel::Warning,
+            context: "poaceae".to_string(),
+            message: format!(
+                "PoaceaeFS is not mounted at {}, {} modules will use Magic Mount and {} rules will be skipped",
+                defs::POACEAE_MOUNT_POINT,
+                plan.poaceae_ops.len(),
+                plan.poaceae.len()
+            ),
+        });
+    }
+
+    // Only the live system knows what the last boot had to leave out.
+    if replay.is_none()
+        && let Ok(state) = RuntimeState::load()
+        && !state.skipped_modules.is_empty()
+    {
+        report.diagnostics.push(planner::DiagnosticIssue {
+            level: planner::DiagnosticLevel::Critical,
+            context: "storage".to_string(),
+            message: format!(
+                "Storage was too small, modules pinned to it were not mounted: {}",
+                state.skipped_modules.join(", ")
+            ),
+        });
+    }
 
     let json_issues: Vec<DiagnosticIssueJson> = report
         .diagnostics
@@ -167,25 +290,289 @@ pub fn handle_diagnostics(cli: &Cli) -> Result<()> {
     Ok(())
 }
 
-pub fn handle_poaceae(target_path: &str, action: &PoaceaeAction) -> Result<()> {
-    let file = File::open(target_path)
-        .with_context(|| format!("Failed to open PoaceaeFS root at {}", target_path))?;
+pub fn handle_bake(cli: &Cli, out: &Path) -> Result<()> {
+    let (config, _replay) = load_final_config(cli)?;
+
+    let module_list =
+        inventory::scan(&config.moduledir, &config).context("Failed to scan modules for bake")?;
+
+    let report = bake::bake(&config, &module_list, out)
+        .with_context(|| format!("Failed to bake modules into {}", out.display()))?;
+
+    println!(
+        "Baked {} overlay and {} magic modules into {} (partitions: {})",
+        report.overlay_module_ids.len(),
+        report.magic_module_ids.len(),
+        out.display(),
+        report.partitions.join(", ")
+    );
+    println!(
+        "Files: {}, Whiteouts: {}, Opaque dirs: {}",
+        report.files, report.whiteouts, report.opaque_dirs
+    );
+
+    Ok(())
+}
+
+pub fn handle_capture(cli: &Cli, out: &Path, full: bool) -> Result<()> {
+    let (config, _replay) = load_final_config(cli)?;
+
+    let manifest = capture::capture(&config, out, full)
+        .with_context(|| format!("Failed to capture environment into {}", out.display()))?;
+
+    println!(
+        "Captured {} partitions and {} modules into {}",
+        manifest.partitions.len(),
+        manifest.modules.len(),
+        out.display()
+    );
+
+    Ok(())
+}
+
+pub fn handle_magic_tree(json: bool) -> Result<()> {
+    let content = fs::read_to_string(defs::MAGIC_TREE_FILE).with_context(|| {
+        format!(
+            "No magic mount tree recorded at {} (Magic Mount not used this boot?)",
+            defs::MAGIC_TREE_FILE
+        )
+    })?;
+
+    if json {
+        println!("{}", content);
+        return Ok(());
+    }
+
+    let tree: NodeSnapshot =
+        serde_json::from_str(&content).context("Failed to parse magic mount tree")?;
+
+    print!("{}", tree);
+
+    Ok(())
+}
+
+pub fn handle_bugreport(cli: &Cli, out: Option<&Path>) -> Result<()> {
+    let (config, _replay) = load_final_config(cli)?;
+
+    let out = match out {
+        Some(path) => path.to_path_buf(),
+        None => Path::new(defs::BUGREPORT_DIR).join(format!(
+            "meta-hybrid-bugreport-{}.tar.gz",
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        )),
+    };
+
+    let summary = bugreport::create(&config, &out)
+        .with_context(|| format!("Failed to create bug report at {}", out.display()))?;
+
+    println!(
+        "Bug report written to {} ({} entries)",
+        out.display(),
+        summary.entries
+    );
+
+    for error in &summary.errors {
+        println!("  skipped: {}", error);
+    }
+
+    Ok(())
+}
+
+pub fn handle_rebuild_image() -> Result<()> {
+    if storage::invalidate_image_cache(Path::new(defs::MODULES_IMG_FILE))? {
+        println!("EROFS image cache invalidated, it will be rebuilt on next boot.");
+    } else {
+        println!("No cached EROFS image, it will be built on next boot.");
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct StorageStatsJson {
+    storage_mode: String,
+    mount_point: String,
+    #[serde(flatten)]
+    stats: dedup::DedupStats,
+}
+
+pub fn handle_storage_stats() -> Result<()> {
+    let state = RuntimeState::load().context("No runtime state, is the daemon mounted?")?;
+    let stats = dedup::stats(&state.mount_point);
+
+    let report = StorageStatsJson {
+        storage_mode: state.storage_mode.to_string(),
+        mount_point: state.mount_point.display().to_string(),
+        stats,
+    };
+
+    let json = serde_json::to_string(&report).context("Failed to serialize storage stats")?;
+    println!("{}", json);
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ModuleDriftJson {
+    module: String,
+    stale: Vec<String>,
+    drift: Vec<Drift>,
+}
+
+pub fn handle_verify_storage(cli: &Cli) -> Result<()> {
+    let (config, _replay) = load_final_config(cli)?;
+    let state = RuntimeState::load().context("No runtime state, is the daemon mounted?")?;
+
+    let module_list = inventory::scan(&config.moduledir, &config)
+        .context("Failed to scan modules for storage verification")?;
+
+    let mut report = Vec::new();
+    for module in module_list {
+        let synced = state.mount_point.join(&module.id);
+        let Some(stored) = SyncManifest::load(&synced) else {
+            continue;
+        };
+
+        let excludes = ExcludeSet::for_module(&module);
+        let mut current = SyncManifest::scan(&module.source_path, &excludes)
+            .with_context(|| format!("Failed to scan source of {}", module.id))?;
+        let diff = current.diff(&stored, &module.source_path, &synced);
+
+        let drift = stored.verify(&synced, None);
+        let stale: Vec<String> = diff.changed.union(&diff.removed).cloned().collect();
+
+        if !stale.is_empty() || !drift.is_empty() {
+            report.push(ModuleDriftJson {
+                module: module.id,
+                stale,
+                drift,
+            });
+        }
+    }
+
+    let json = serde_json::to_string(&report).context("Failed to serialize storage drift")?;
+    println!("{}", json);
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct GcJson {
+    dry_run: bool,
+    reclaimed_bytes: u64,
+    leftovers: Vec<Leftover>,
+}
+
+pub fn handle_gc(cli: &Cli, dry_run: bool) -> Result<()> {
+    let (config, _replay) = load_final_config(cli)?;
+    let state = RuntimeState::load().context("No runtime state, is the daemon mounted?")?;
+
+    let module_list =
+        inventory::scan(&config.moduledir, &config).context("Failed to scan modules for gc")?;
+    let active_ids: HashSet<&str> = module_list.iter().map(|m| m.id.as_str()).collect();
+
+    let leftovers = gc::collect(&state.mount_point, &active_ids, dry_run)?;
+    let reclaimed_bytes = leftovers
+        .iter()
+        .filter(|leftover| leftover.removed)
+        .map(|leftover| leftover.bytes)
+        .sum();
+
+    let report = GcJson {
+        dry_run,
+        reclaimed_bytes,
+        leftovers,
+    };
+
+    let json = serde_json::to_string(&report).context("Failed to serialize gc report")?;
+    println!("{}", json);
+
+    Ok(())
+}
+
+pub fn handle_rollback(cli: &Cli, module_id: &str, to: Option<&str>) -> Result<()> {
+    utils::validate_module_id(module_id)?;
+    let (config, _replay) = load_final_config(cli)?;
+    let module_dir = config.moduledir.join(module_id);
+    if !module_dir.is_dir() {
+        bail!("Module {} is not installed", module_id);
+    }
+
+    let target = versions::rollback(&module_dir, module_id, to)?;
+    println!(
+        "{} will mount version {} ({}) on next boot.",
+        module_id, target.version, target.version_code
+    );
+
+    Ok(())
+}
+
+fn open_poaceae(target: &Path, fake: bool) -> Result<Box<dyn poaceae::Device>> {
+    if fake {
+        return Ok(Box::new(poaceae::FakePoaceae::open(target)?));
+    }
+    poaceae::open(target)
+}
+
+pub fn handle_poaceae(target_path: &str, fake: bool, action: &PoaceaeAction) -> Result<()> {
+    let target = Path::new(target_path);
+
+    if let PoaceaeAction::List = action {
+        // The ledger still answers when PoaceaeFS is not mounted.
+        let dev = match open_poaceae(target, fake) {
+            Ok(dev) => Some(dev),
+            Err(e) if fake => return Err(e),
+            Err(_) => None,
+        };
+        let json = serde_json::to_string(&poaceae_rules::active(target, dev.as_deref()))
+            .context("Failed to serialize PoaceaeFS rules")?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    let dev = open_poaceae(target, fake)?;
 
     match action {
+        PoaceaeAction::Sync { from } => {
+            let content = fs::read_to_string(from)
+                .with_context(|| format!("Failed to read {}", from.display()))?;
+            let desired: PoaceaeRules = if from.extension().is_some_and(|ext| ext == "json") {
+                serde_json::from_str(&content)?
+            } else {
+                toml::from_str(&content)?
+            };
+
+            let report = poaceae_rules::sync(target, &*dev, &desired)?;
+            let json =
+                serde_json::to_string(&report).context("Failed to serialize PoaceaeFS sync")?;
+            println!("{}", json);
+        }
         PoaceaeAction::Hide { name } => {
-            poaceae::hide(&file, name)?;
+            poaceae::hide(&*dev, name)?;
+            poaceae_rules::record(target, &*dev, |rules| {
+                rules.hide.insert(name.clone());
+            })?;
             println!("Hidden: {}", name);
         }
         PoaceaeAction::Unhide { name } => {
-            poaceae::unhide(&file, name)?;
+            poaceae::unhide(&*dev, name)?;
+            poaceae_rules::record(target, &*dev, |rules| {
+                rules.hide.remove(name);
+            })?;
             println!("Unhidden: {}", name);
         }
         PoaceaeAction::Redirect { src, dst } => {
-            poaceae::redirect(&file, src, dst)?;
+            poaceae::redirect(&*dev, src, dst)?;
+            poaceae_rules::record(target, &*dev, |rules| {
+                rules.redirect.insert(src.clone(), dst.clone());
+            })?;
             println!("Redirected: {} -> {}", src, dst);
         }
         PoaceaeAction::Unredirect { src } => {
-            poaceae::unredirect(&file, src)?;
+            poaceae::unredirect(&*dev, src)?;
+            poaceae_rules::record(target, &*dev, |rules| {
+                rules.redirect.remove(src);
+            })?;
             println!("Removed redirect: {}", src);
         }
         PoaceaeAction::Spoof {
@@ -195,28 +582,48 @@ pub fn handle_poaceae(target_path: &str, action: &PoaceaeAction) -> Result<()> {
             mode,
             mtime,
         } => {
-            poaceae::spoof(&file, name, *uid, *gid, *mode, *mtime)?;
+            poaceae::spoof(&*dev, name, *uid, *gid, *mode, *mtime)?;
+            poaceae_rules::record(target, &*dev, |rules| {
+                let rule = SpoofRule {
+                    uid: *uid,
+                    gid: *gid,
+                    mode: *mode,
+                    mtime: *mtime,
+                };
+                rules.spoof.insert(name.clone(), rule);
+            })?;
             println!(
                 "Spoofed: {} (uid={}, gid={}, mode={:o})",
                 name, uid, gid, mode
             );
         }
         PoaceaeAction::Unspoof { name } => {
-            poaceae::unspoof(&file, name)?;
+            poaceae::unspoof(&*dev, name)?;
+            poaceae_rules::record(target, &*dev, |rules| {
+                rules.spoof.remove(name);
+            })?;
             println!("Removed spoof: {}", name);
         }
-        PoaceaeAction::Merge { src, target } => {
-            poaceae::merge(&file, src, target)?;
-            println!("Merged: {} -> {}", src, target);
+        PoaceaeAction::Merge { src, target: dst } => {
+            poaceae::merge(&*dev, src, dst)?;
+            poaceae_rules::record(target, &*dev, |rules| {
+                rules.merge.insert(src.clone(), dst.clone());
+            })?;
+            println!("Merged: {} -> {}", src, dst);
         }
         PoaceaeAction::Unmerge { src } => {
-            poaceae::unmerge(&file, src)?;
+            poaceae::unmerge(&*dev, src)?;
+            poaceae_rules::record(target, &*dev, |rules| {
+                rules.merge.remove(src);
+            })?;
             println!("Removed merge: {}", src);
         }
         PoaceaeAction::Trust { gid } => {
-            poaceae::set_trust(&file, *gid)?;
+            poaceae::set_trust(&*dev, *gid)?;
+            poaceae_rules::record(target, &*dev, |rules| rules.trusted_gid = Some(*gid))?;
             println!("Trusted GID set to: {}", gid);
         }
+        PoaceaeAction::List => unreachable!(),
     }
     Ok(())
 }
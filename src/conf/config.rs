@@ -1,6 +1,6 @@
 use std::{
-    collections::HashMap,
-    fs,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt, fs,
     path::{Path, PathBuf},
 };
 
@@ -34,13 +34,79 @@ impl Default for BackupConfig {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlacementConfig {
+    /// Modules at least this large (MiB) leave tmpfs, 0 keeps everything in RAM.
+    #[serde(default)]
+    pub large_module_threshold_mb: u64,
+    #[serde(default = "default_large_module_storage")]
+    pub large_module_storage: StoragePlacement,
+}
+
+fn default_large_module_storage() -> StoragePlacement {
+    StoragePlacement::Image
+}
+
+impl Default for PlacementConfig {
+    fn default() -> Self {
+        Self {
+            large_module_threshold_mb: 0,
+            large_module_storage: default_large_module_storage(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncConfig {
+    /// Modules copied at the same time.
+    #[serde(default = "default_io_concurrency")]
+    pub io_concurrency: usize,
+    /// Files are copied in pieces of this many MiB, reporting progress after each.
+    #[serde(default = "default_chunk_size_mb")]
+    pub chunk_size_mb: u64,
+    /// Earlier versions of each module kept on disk for `rollback`, besides the installed one.
+    #[serde(default)]
+    pub keep_versions: usize,
+}
+
+fn default_io_concurrency() -> usize {
+    2
+}
+
+fn default_chunk_size_mb() -> u64 {
+    8
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            io_concurrency: default_io_concurrency(),
+            chunk_size_mb: default_chunk_size_mb(),
+            keep_versions: 0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum OverlayMode {
     #[default]
     Tmpfs,
     Ext4,
     Erofs,
+    Squashfs,
+}
+
+impl fmt::Display for OverlayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Tmpfs => "tmpfs",
+            Self::Ext4 => "ext4",
+            Self::Erofs => "erofs",
+            Self::Squashfs => "squashfs",
+        };
+        f.write_str(name)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
@@ -49,6 +115,7 @@ pub enum DefaultMode {
     #[default]
     Overlay,
     Magic,
+    Poaceae,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
@@ -58,6 +125,83 @@ pub enum MountMode {
     Overlay,
     Magic,
     Ignore,
+    /// Redirect and merge rules on PoaceaeFS, magic mount when it is not mounted.
+    Poaceae,
+}
+
+/// Where a module's content is served from.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StoragePlacement {
+    /// Synced into the main storage backend.
+    #[default]
+    Tmpfs,
+    /// Packed into its own read-only image.
+    Image,
+    /// Used in place from the module directory.
+    Source,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct SpoofRule {
+    #[serde(default)]
+    pub uid: u32,
+    #[serde(default)]
+    pub gid: u32,
+    #[serde(default)]
+    pub mode: u16,
+    #[serde(default)]
+    pub mtime: u64,
+}
+
+/// PoaceaeFS rules applied once the mount plan has run.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct PoaceaeRules {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub hide: BTreeSet<String>,
+    /// Source path to the path it is redirected to.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub redirect: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub spoof: BTreeMap<String, SpoofRule>,
+    /// Source directory to the directory merged into it.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub merge: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trusted_gid: Option<u32>,
+}
+
+impl PoaceaeRules {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.hide.len()
+            + self.redirect.len()
+            + self.spoof.len()
+            + self.merge.len()
+            + usize::from(self.trusted_gid.is_some())
+    }
+
+    /// Adds `other`'s rules, keeping ours where both name the same path.
+    pub fn merge_from(&mut self, other: &PoaceaeRules) {
+        self.hide.extend(other.hide.iter().cloned());
+        for (src, dst) in &other.redirect {
+            self.redirect
+                .entry(src.clone())
+                .or_insert_with(|| dst.clone());
+        }
+        for (name, rule) in &other.spoof {
+            self.spoof.entry(name.clone()).or_insert(*rule);
+        }
+        for (src, target) in &other.merge {
+            self.merge
+                .entry(src.clone())
+                .or_insert_with(|| target.clone());
+        }
+        self.trusted_gid = self.trusted_gid.or(other.trusted_gid);
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -66,6 +210,12 @@ pub struct ModuleRules {
     pub default_mode: MountMode,
     #[serde(default)]
     pub paths: HashMap<String, MountMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StoragePlacement>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    #[serde(default, skip_serializing_if = "PoaceaeRules::is_empty")]
+    pub poaceae: PoaceaeRules,
 }
 
 impl ModuleRules {
@@ -88,21 +238,37 @@ pub struct Config {
     #[serde(default)]
     pub overlay_mode: OverlayMode,
     #[serde(default)]
+    pub storage_backends: Vec<OverlayMode>,
+    #[serde(default)]
     pub disable_umount: bool,
     #[serde(default)]
     pub allow_umount_coexistence: bool,
+    #[serde(default)]
+    pub repair_context: bool,
     #[serde(default, alias = "granary")]
     pub backup: BackupConfig,
     #[serde(default)]
+    pub placement: PlacementConfig,
+    #[serde(default)]
+    pub sync: SyncConfig,
+    #[serde(default)]
+    pub poaceae: PoaceaeRules,
+    #[serde(default)]
     pub default_mode: DefaultMode,
     #[serde(default)]
     pub rules: HashMap<String, ModuleRules>,
+    #[serde(default = "default_root")]
+    pub root: PathBuf,
 }
 
 fn default_moduledir() -> PathBuf {
     PathBuf::from(defs::MODULES_DIR)
 }
 
+fn default_root() -> PathBuf {
+    PathBuf::from("/")
+}
+
 fn default_mountsource() -> String {
     crate::sys::mount::detect_mount_source()
 }
@@ -135,11 +301,17 @@ impl Default for Config {
             mountsource: default_mountsource(),
             partitions: Vec::new(),
             overlay_mode: OverlayMode::default(),
+            storage_backends: Vec::new(),
             disable_umount: false,
             allow_umount_coexistence: false,
+            repair_context: false,
             backup: BackupConfig::default(),
+            placement: PlacementConfig::default(),
+            sync: SyncConfig::default(),
+            poaceae: PoaceaeRules::default(),
             default_mode: DefaultMode::default(),
             rules: HashMap::new(),
+            root: default_root(),
         }
     }
 }
@@ -169,11 +341,33 @@ impl Config {
         Ok(())
     }
 
+    pub fn storage_chain(&self) -> Vec<OverlayMode> {
+        if !self.storage_backends.is_empty() {
+            return self.storage_backends.clone();
+        }
+
+        // Squashfs is skipped at setup when the kernel does not list it.
+        match self.overlay_mode {
+            OverlayMode::Erofs => vec![
+                OverlayMode::Erofs,
+                OverlayMode::Tmpfs,
+                OverlayMode::Squashfs,
+                OverlayMode::Ext4,
+            ],
+            OverlayMode::Tmpfs => {
+                vec![OverlayMode::Tmpfs, OverlayMode::Squashfs, OverlayMode::Ext4]
+            }
+            OverlayMode::Squashfs => vec![OverlayMode::Squashfs, OverlayMode::Ext4],
+            OverlayMode::Ext4 => vec![OverlayMode::Ext4],
+        }
+    }
+
     pub fn merge_with_cli(
         &mut self,
         moduledir: Option<PathBuf>,
         mountsource: Option<String>,
         partitions: Vec<String>,
+        root: Option<PathBuf>,
     ) {
         if let Some(dir) = moduledir {
             self.moduledir = dir;
@@ -186,5 +380,9 @@ impl Config {
         if !partitions.is_empty() {
             self.partitions = partitions;
         }
+
+        if let Some(root) = root {
+            self.root = root;
+        }
     }
 }
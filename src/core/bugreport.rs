@@ -0,0 +1,206 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use flate2::{Compression, write::GzEncoder};
+use serde::Serialize;
+use tar::Builder;
+
+use crate::{
+    conf::config::Config,
+    core::{
+        capture::{self, TarWriter},
+        inventory,
+        ops::planner,
+        state::RuntimeState,
+    },
+    defs,
+    mount::magic_mount,
+    sys::caps::Capabilities,
+};
+
+const REDACTED: &str = "<redacted>";
+const PRIVATE_PATH_PREFIXES: &[&str] = &[
+    "/sdcard",
+    "/storage/",
+    "/data/media/",
+    "/data/user/",
+    "/data/data/",
+];
+
+#[derive(Serialize)]
+struct ModuleEntry {
+    id: String,
+    disabled: bool,
+    remove: bool,
+    skip_mount: bool,
+    has_rules: bool,
+}
+
+pub struct BugReportSummary {
+    pub entries: usize,
+    pub errors: Vec<String>,
+}
+
+struct Collector {
+    builder: TarWriter,
+    entries: usize,
+    errors: Vec<String>,
+}
+
+impl Collector {
+    fn add_bytes(&mut self, name: &str, data: &[u8]) {
+        match capture::append_bytes(&mut self.builder, name, data) {
+            Ok(()) => self.entries += 1,
+            Err(e) => self.errors.push(format!("{}: {:#}", name, e)),
+        }
+    }
+
+    fn add_file(&mut self, name: &str, path: &Path) {
+        match fs::read(path) {
+            Ok(data) => self.add_bytes(name, &data),
+            Err(e) => self.errors.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    fn add_json<T: Serialize>(&mut self, name: &str, value: &T) {
+        match serde_json::to_vec_pretty(value) {
+            Ok(data) => self.add_bytes(name, &data),
+            Err(e) => self.errors.push(format!("{}: {}", name, e)),
+        }
+    }
+}
+
+fn redact_value(value: &mut toml::Value) {
+    match value {
+        toml::Value::String(s) if PRIVATE_PATH_PREFIXES.iter().any(|p| s.starts_with(p)) => {
+            *s = REDACTED.to_string();
+        }
+        toml::Value::Array(items) => items.iter_mut().for_each(redact_value),
+        toml::Value::Table(table) => table.iter_mut().for_each(|(_, v)| redact_value(v)),
+        _ => {}
+    }
+}
+
+fn redacted_config(config: &Config) -> Result<String> {
+    let mut value = toml::Value::try_from(config).context("Failed to serialize config")?;
+    redact_value(&mut value);
+    Ok(toml::to_string_pretty(&value)?)
+}
+
+fn list_modules(moduledir: &Path) -> Result<Vec<ModuleEntry>> {
+    let mut entries = Vec::new();
+
+    for entry in fs::read_dir(moduledir)?.flatten() {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+
+        entries.push(ModuleEntry {
+            id: entry.file_name().to_string_lossy().to_string(),
+            disabled: path.join(defs::DISABLE_FILE_NAME).exists(),
+            remove: path.join(defs::REMOVE_FILE_NAME).exists(),
+            skip_mount: path.join(defs::SKIP_MOUNT_FILE_NAME).exists(),
+            has_rules: path.join("hybrid_rules.json").exists(),
+        });
+    }
+
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(entries)
+}
+
+pub fn create(config: &Config, out: &Path) -> Result<BugReportSummary> {
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let file = File::create(out)
+        .with_context(|| format!("Failed to create bug report {}", out.display()))?;
+
+    let mut c = Collector {
+        builder: Builder::new(GzEncoder::new(file, Compression::default())),
+        entries: 0,
+        errors: Vec::new(),
+    };
+
+    match redacted_config(config) {
+        Ok(content) => c.add_bytes("config.toml", content.as_bytes()),
+        Err(e) => c.errors.push(format!("config.toml: {:#}", e)),
+    }
+
+    c.add_file("daemon_state.json", Path::new(defs::STATE_FILE));
+    c.add_file("daemon.log", Path::new(defs::DAEMON_LOG_FILE));
+    c.add_file("mountinfo", Path::new("/proc/self/mountinfo"));
+
+    if Path::new(defs::MAGIC_TREE_FILE).exists() {
+        c.add_file("magic_tree.json", Path::new(defs::MAGIC_TREE_FILE));
+    }
+    c.add_json("capabilities.json", &Capabilities::probe());
+
+    let state = RuntimeState::load().unwrap_or_default();
+    c.add_json("try_umount.json", &state.umount_targets);
+
+    match list_modules(&config.moduledir) {
+        Ok(list) => {
+            for module in list.iter().filter(|m| m.has_rules) {
+                c.add_file(
+                    &format!("modules/{}/hybrid_rules.json", module.id),
+                    &config.moduledir.join(&module.id).join("hybrid_rules.json"),
+                );
+            }
+            c.add_json("modules.json", &list);
+        }
+        Err(e) => c.errors.push(format!("modules.json: {:#}", e)),
+    }
+
+    match inventory::scan(&config.moduledir, config) {
+        Ok(modules) => match planner::generate(config, &modules, &config.moduledir) {
+            Ok(plan) => {
+                let report = plan.analyze();
+                c.add_json("conflicts.json", &report.conflicts);
+                c.add_json("diagnostics.json", &report.diagnostics);
+
+                let magic_ids: HashSet<String> = plan.magic_module_ids.iter().cloned().collect();
+                if !magic_ids.is_empty() {
+                    match magic_mount::collect_module_files(
+                        &plan.module_paths,
+                        &config.root,
+                        &config.partitions,
+                        magic_ids,
+                    ) {
+                        Ok(Some(tree)) => c.add_json(
+                            "magic_tree_planned.json",
+                            &tree.snapshot(&config.root, &HashMap::new()),
+                        ),
+                        Ok(None) => {}
+                        Err(e) => c.errors.push(format!("magic_tree_planned.json: {:#}", e)),
+                    }
+                }
+
+                c.add_json("plan.json", &plan);
+            }
+            Err(e) => c.errors.push(format!("plan.json: {:#}", e)),
+        },
+        Err(e) => c.errors.push(format!("inventory: {:#}", e)),
+    }
+
+    if !c.errors.is_empty() {
+        let errors = c.errors.join("\n");
+        c.add_bytes("errors.txt", errors.as_bytes());
+    }
+
+    c.builder
+        .into_inner()
+        .context("Failed to finish bug report archive")?
+        .finish()
+        .context("Failed to flush bug report archive")?;
+
+    Ok(BugReportSummary {
+        entries: c.entries,
+        errors: c.errors,
+    })
+}
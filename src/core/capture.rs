@@ -0,0 +1,382 @@
+use std::{
+    fs::{self, File},
+    io,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail};
+use extattr::lgetxattr;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+use tar::{Archive, Builder, EntryType, Header};
+use walkdir::WalkDir;
+
+use crate::{conf::config::Config, defs, sys::caps::Capabilities, utils};
+
+const CAPTURE_VERSION: u32 = 1;
+const MANIFEST_ENTRY: &str = "manifest.json";
+const CONFIG_ENTRY: &str = "config.toml";
+const CAPABILITIES_ENTRY: &str = "capabilities.json";
+const MOUNTINFO_ENTRY: &str = "mountinfo";
+const ROOT_ENTRY: &str = "root";
+const MODULES_ENTRY: &str = "modules";
+const OPAQUE_PAX_KEY: &str = "SCHILY.xattr.trusted.overlay.opaque";
+
+const MODULE_META_FILES: &[&str] = &[
+    "module.prop",
+    "hybrid_rules.json",
+    defs::DISABLE_FILE_NAME,
+    defs::REMOVE_FILE_NAME,
+    defs::SKIP_MOUNT_FILE_NAME,
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CaptureManifest {
+    pub version: u32,
+    pub timestamp: u64,
+    pub full_modules: bool,
+    pub partitions: Vec<String>,
+    pub modules: Vec<String>,
+}
+
+pub type TarWriter = Builder<GzEncoder<File>>;
+
+pub fn capture(config: &Config, out: &Path, full_modules: bool) -> Result<CaptureManifest> {
+    let file = File::create(out)
+        .with_context(|| format!("Failed to create capture archive {}", out.display()))?;
+    let mut builder = Builder::new(GzEncoder::new(file, Compression::default()));
+    builder.follow_symlinks(false);
+
+    let mut partitions: Vec<String> = defs::BUILTIN_PARTITIONS
+        .iter()
+        .map(|s| s.to_string())
+        .chain(config.partitions.iter().cloned())
+        .collect();
+    partitions.sort();
+    partitions.dedup();
+    partitions.retain(|p| config.root.join(p).symlink_metadata().is_ok());
+
+    for partition in &partitions {
+        log::info!("Capturing partition skeleton: {}", partition);
+        append_skeleton(
+            &mut builder,
+            &config.root,
+            &config.root.join(partition),
+            Path::new(ROOT_ENTRY),
+        )?;
+    }
+
+    let mut modules = Vec::new();
+    if config.moduledir.is_dir() {
+        for entry in fs::read_dir(&config.moduledir)?.flatten() {
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let id = entry.file_name().to_string_lossy().to_string();
+            let archive_dir = Path::new(MODULES_ENTRY).join(&id);
+
+            if full_modules {
+                builder.append_dir_all(&archive_dir, entry.path())?;
+            } else {
+                append_module_metadata(&mut builder, &entry.path(), &archive_dir, config)?;
+            }
+
+            modules.push(id);
+        }
+    }
+    modules.sort();
+
+    let config_toml = toml::to_string_pretty(config).context("Failed to serialize config")?;
+    append_bytes(&mut builder, CONFIG_ENTRY, config_toml.as_bytes())?;
+
+    let capabilities = serde_json::to_vec_pretty(&Capabilities::probe())?;
+    append_bytes(&mut builder, CAPABILITIES_ENTRY, &capabilities)?;
+
+    let mountinfo = fs::read("/proc/self/mountinfo").unwrap_or_default();
+    append_bytes(&mut builder, MOUNTINFO_ENTRY, &mountinfo)?;
+
+    let manifest = CaptureManifest {
+        version: CAPTURE_VERSION,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        full_modules,
+        partitions,
+        modules,
+    };
+    append_bytes(
+        &mut builder,
+        MANIFEST_ENTRY,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    builder
+        .into_inner()
+        .context("Failed to finish capture archive")?
+        .finish()
+        .context("Failed to flush capture archive")?;
+
+    Ok(manifest)
+}
+
+pub fn append_bytes(builder: &mut TarWriter, name: &str, data: &[u8]) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+fn append_placeholder(
+    builder: &mut TarWriter,
+    path: &Path,
+    archive_path: &Path,
+    metadata: &fs::Metadata,
+) -> Result<()> {
+    let ft = metadata.file_type();
+    let mut header = Header::new_gnu();
+    header.set_mode(metadata.mode() & 0o7777);
+    header.set_uid(metadata.uid() as u64);
+    header.set_gid(metadata.gid() as u64);
+    header.set_mtime(metadata.mtime().max(0) as u64);
+    header.set_size(0);
+
+    if ft.is_dir() {
+        if let Ok(opaque) = lgetxattr(path, defs::REPLACE_DIR_XATTR) {
+            builder.append_pax_extensions([(OPAQUE_PAX_KEY, opaque.as_slice())])?;
+        }
+        header.set_entry_type(EntryType::Directory);
+        builder.append_data(&mut header, archive_path, io::empty())?;
+    } else if ft.is_symlink() {
+        header.set_entry_type(EntryType::Symlink);
+        builder.append_link(&mut header, archive_path, fs::read_link(path)?)?;
+    } else if ft.is_char_device() && metadata.rdev() == 0 {
+        header.set_entry_type(EntryType::Char);
+        header.set_device_major(0)?;
+        header.set_device_minor(0)?;
+        builder.append_data(&mut header, archive_path, io::empty())?;
+    } else if ft.is_file() {
+        header.set_entry_type(EntryType::Regular);
+        builder.append_data(&mut header, archive_path, io::empty())?;
+    }
+
+    Ok(())
+}
+
+fn append_skeleton(
+    builder: &mut TarWriter,
+    base: &Path,
+    start: &Path,
+    archive_base: &Path,
+) -> Result<()> {
+    for entry in WalkDir::new(start)
+        .follow_links(false)
+        .into_iter()
+        .flatten()
+    {
+        let Ok(relative) = entry.path().strip_prefix(base) else {
+            continue;
+        };
+        let Ok(metadata) = entry.path().symlink_metadata() else {
+            continue;
+        };
+
+        append_placeholder(
+            builder,
+            entry.path(),
+            &archive_base.join(relative),
+            &metadata,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn append_module_metadata(
+    builder: &mut TarWriter,
+    module_dir: &Path,
+    archive_dir: &Path,
+    config: &Config,
+) -> Result<()> {
+    append_placeholder(builder, module_dir, archive_dir, &module_dir.metadata()?)?;
+
+    for name in MODULE_META_FILES {
+        let path = module_dir.join(name);
+        if path.is_file() {
+            builder.append_path_with_name(&path, archive_dir.join(name))?;
+        }
+    }
+
+    let partitions = defs::BUILTIN_PARTITIONS
+        .iter()
+        .map(|s| s.to_string())
+        .chain(config.partitions.iter().cloned());
+
+    for partition in partitions {
+        let part_dir = module_dir.join(&partition);
+        if !part_dir.is_dir() {
+            continue;
+        }
+
+        for entry in WalkDir::new(&part_dir)
+            .follow_links(false)
+            .into_iter()
+            .flatten()
+        {
+            let Ok(relative) = entry.path().strip_prefix(module_dir) else {
+                continue;
+            };
+            let Ok(metadata) = entry.path().symlink_metadata() else {
+                continue;
+            };
+
+            append_placeholder(
+                builder,
+                entry.path(),
+                &archive_dir.join(relative),
+                &metadata,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+pub struct Replay {
+    pub dir: PathBuf,
+    pub config: Config,
+    pub capabilities: Capabilities,
+    mountinfo: String,
+}
+
+impl Replay {
+    pub fn open(archive: &Path) -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!("meta-hybrid-replay-{}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+
+        let file = File::open(archive)
+            .with_context(|| format!("Failed to open capture archive {}", archive.display()))?;
+        let mut tar = Archive::new(GzDecoder::new(file));
+
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+
+            match entry.header().entry_type() {
+                // Created without the captured mode so read-only dirs stay writable while unpacking.
+                EntryType::Directory => {
+                    let target = contained_path(&dir, &path)?;
+                    fs::create_dir_all(&target)?;
+                    restore_opaque(&mut entry, &target)?;
+                }
+                EntryType::Char | EntryType::Block => {
+                    if let Err(e) = entry.unpack_in(&dir) {
+                        log::warn!("Skipping device node {}: {}", path.display(), e);
+                    }
+                }
+                _ => {
+                    entry
+                        .unpack_in(&dir)
+                        .with_context(|| format!("Failed to unpack {}", path.display()))?;
+                }
+            }
+        }
+
+        let manifest: CaptureManifest =
+            serde_json::from_slice(&fs::read(dir.join(MANIFEST_ENTRY))?)
+                .context("Failed to parse capture manifest")?;
+
+        if manifest.version > CAPTURE_VERSION {
+            bail!(
+                "Capture archive version {} is newer than supported ({})",
+                manifest.version,
+                CAPTURE_VERSION
+            );
+        }
+
+        let mut config = Config::from_file(dir.join(CONFIG_ENTRY))?;
+        config.root = dir.join(ROOT_ENTRY);
+        config.moduledir = dir.join(MODULES_ENTRY);
+        fs::create_dir_all(&config.root)?;
+        fs::create_dir_all(&config.moduledir)?;
+
+        let capabilities = fs::read(dir.join(CAPABILITIES_ENTRY))
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+
+        let mountinfo = fs::read_to_string(dir.join(MOUNTINFO_ENTRY)).unwrap_or_default();
+
+        Ok(Self {
+            dir,
+            config,
+            capabilities,
+            mountinfo,
+        })
+    }
+
+    /// Whether `path` was a mount point on the captured device.
+    pub fn is_mounted(&self, path: &Path) -> bool {
+        let search = path.to_string_lossy();
+        let search = search.trim_end_matches('/');
+        self.mountinfo
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(4))
+            .any(|mount_point| mount_point == search)
+    }
+}
+
+// Only plain names are joined and no symlink unpacked earlier is followed, so the result stays
+// inside `base`.
+fn contained_path(base: &Path, path: &Path) -> Result<PathBuf> {
+    let mut target = base.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => target.push(name),
+            Component::CurDir => continue,
+            _ => bail!("Refusing unsafe archive path: {}", path.display()),
+        }
+        if target
+            .symlink_metadata()
+            .is_ok_and(|m| m.file_type().is_symlink())
+        {
+            bail!(
+                "Refusing archive path through a symlink: {}",
+                path.display()
+            );
+        }
+    }
+    Ok(target)
+}
+
+// Setting trusted.* xattrs needs root, elsewhere the equivalent `.replace` marker is created.
+fn restore_opaque<R: io::Read>(entry: &mut tar::Entry<R>, path: &Path) -> Result<()> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(());
+    };
+
+    for extension in extensions.flatten() {
+        if extension.key_bytes() == OPAQUE_PAX_KEY.as_bytes()
+            && utils::set_overlay_opaque(path).is_err()
+        {
+            fs::write(path.join(defs::REPLACE_DIR_FILE_NAME), "")?;
+        }
+    }
+
+    Ok(())
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            log::debug!("Failed to clean replay dir {}: {}", self.dir.display(), e);
+        }
+    }
+}
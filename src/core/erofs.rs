@@ -0,0 +1,985 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, Metadata},
+    io::Read,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use extattr::{lgetxattr, llistxattr};
+
+use crate::defs;
+
+const BLOCK_BITS: u8 = 12;
+const BLOCK_SIZE: usize = 1 << BLOCK_BITS;
+const SUPER_OFFSET: u64 = 1024;
+const SUPER_MAGIC: u32 = 0xE0F5_E1E2;
+const FEATURE_INCOMPAT_ZERO_PADDING: u32 = 0x1;
+
+const SLOT_SIZE: usize = 32;
+const INODE_SIZE: usize = 64;
+const LAYOUT_FLAT_PLAIN: u16 = 0;
+const LAYOUT_COMPRESSED_FULL: u16 = 1;
+
+const XATTR_HEADER_SIZE: usize = 12;
+const XATTR_INDEX_TRUSTED: u8 = 4;
+const XATTR_INDEX_SECURITY: u8 = 6;
+
+const DIRENT_SIZE: usize = 12;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_FIFO: u8 = 5;
+const FT_SOCK: u8 = 6;
+const FT_SYMLINK: u8 = 7;
+
+const LCLUSTER_TYPE_PLAIN: u16 = 0;
+const LCLUSTER_TYPE_HEAD: u16 = 1;
+const LCLUSTER_TYPE_NONHEAD: u16 = 2;
+// Map header plus the 8 reserved bytes that precede full lcluster indexes.
+const MAP_HEADER_SIZE: usize = 16;
+const MAX_EXTENT_CLUSTERS: usize = 16;
+
+enum Kind {
+    Dir(Vec<(OsString, usize)>),
+    File,
+    Symlink(Vec<u8>),
+    Special(u8),
+}
+
+enum Data {
+    None,
+    Plain { blkaddr: u32 },
+    Compressed { blocks: u32, indexes: Vec<[u8; 8]> },
+}
+
+struct Inode {
+    path: PathBuf,
+    parent: usize,
+    kind: Kind,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    mtime_nsec: u32,
+    rdev: u64,
+    size: u64,
+    nlink: u32,
+    xattrs: Vec<(u8, Vec<u8>, Vec<u8>)>,
+    data: Data,
+    nid: u64,
+}
+
+struct Builder {
+    image: File,
+    inodes: Vec<Inode>,
+    hardlinks: HashMap<(u64, u64), usize>,
+    next_block: u32,
+    compress: bool,
+}
+
+pub fn build_image(src_dir: &Path, image_path: &Path, compress: bool) -> Result<()> {
+    let image = File::create(image_path)
+        .with_context(|| format!("Failed to create {}", image_path.display()))?;
+
+    let mut builder = Builder {
+        image,
+        inodes: Vec::new(),
+        hardlinks: HashMap::new(),
+        // Block 0 holds the superblock.
+        next_block: 1,
+        compress,
+    };
+
+    builder.scan(src_dir)?;
+
+    for idx in 0..builder.inodes.len() {
+        builder
+            .write_data(idx)
+            .with_context(|| format!("Failed to pack {}", builder.inodes[idx].path.display()))?;
+    }
+
+    let meta_size = builder.assign_nids()?;
+
+    for idx in 0..builder.inodes.len() {
+        if matches!(builder.inodes[idx].kind, Kind::Dir(_)) {
+            builder.write_dir(idx)?;
+        }
+    }
+
+    let meta_blkaddr = builder.next_block;
+    for inode in &builder.inodes {
+        let record = inode.encode()?;
+        builder.image.write_all_at(
+            &record,
+            ((meta_blkaddr as u64) << BLOCK_BITS) + inode.nid * SLOT_SIZE as u64,
+        )?;
+    }
+
+    let total_blocks = meta_blkaddr + meta_size.div_ceil(BLOCK_SIZE) as u32;
+    builder.image.set_len((total_blocks as u64) << BLOCK_BITS)?;
+    builder.write_super(meta_blkaddr, total_blocks)?;
+    builder.image.sync_all()?;
+
+    log::info!(
+        "Packed {} inodes into {} blocks ({})",
+        builder.inodes.len(),
+        total_blocks,
+        image_path.display()
+    );
+
+    Ok(())
+}
+
+impl Builder {
+    fn scan(&mut self, root: &Path) -> Result<()> {
+        let metadata = root
+            .symlink_metadata()
+            .with_context(|| format!("Failed to stat {}", root.display()))?;
+        ensure!(metadata.is_dir(), "{} is not a directory", root.display());
+
+        self.add_inode(root, &metadata, 0)?;
+        self.scan_dir(0)
+    }
+
+    fn scan_dir(&mut self, idx: usize) -> Result<()> {
+        let dir = self.inodes[idx].path.clone();
+
+        let mut names: Vec<OsString> = fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<Result<_, _>>()?;
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+        let mut children = Vec::with_capacity(names.len());
+        let mut subdirs = 0;
+
+        for name in names {
+            ensure!(
+                name.len() <= 255,
+                "File name too long: {}",
+                name.to_string_lossy()
+            );
+
+            let path = dir.join(&name);
+            let metadata = path.symlink_metadata()?;
+
+            let child = if !metadata.is_dir()
+                && metadata.nlink() > 1
+                && let Some(&existing) = self.hardlinks.get(&(metadata.dev(), metadata.ino()))
+            {
+                self.inodes[existing].nlink += 1;
+                existing
+            } else {
+                let child = self.add_inode(&path, &metadata, idx)?;
+                if metadata.is_dir() {
+                    subdirs += 1;
+                    self.scan_dir(child)?;
+                } else if metadata.nlink() > 1 {
+                    self.hardlinks
+                        .insert((metadata.dev(), metadata.ino()), child);
+                }
+                child
+            };
+
+            children.push((name, child));
+        }
+
+        self.inodes[idx].nlink = 2 + subdirs;
+        self.inodes[idx].kind = Kind::Dir(children);
+        Ok(())
+    }
+
+    fn add_inode(&mut self, path: &Path, metadata: &Metadata, parent: usize) -> Result<usize> {
+        let ft = metadata.file_type();
+        let kind = if ft.is_dir() {
+            Kind::Dir(Vec::new())
+        } else if ft.is_file() {
+            Kind::File
+        } else if ft.is_symlink() {
+            Kind::Symlink(fs::read_link(path)?.as_os_str().as_bytes().to_vec())
+        } else if ft.is_char_device() {
+            Kind::Special(FT_CHRDEV)
+        } else if ft.is_block_device() {
+            Kind::Special(FT_BLKDEV)
+        } else if ft.is_fifo() {
+            Kind::Special(FT_FIFO)
+        } else if ft.is_socket() {
+            Kind::Special(FT_SOCK)
+        } else {
+            bail!("Unsupported file type: {}", path.display());
+        };
+
+        let size = match &kind {
+            Kind::File => metadata.len(),
+            Kind::Symlink(target) => target.len() as u64,
+            _ => 0,
+        };
+
+        self.inodes.push(Inode {
+            path: path.to_path_buf(),
+            parent,
+            kind,
+            mode: metadata.mode() as u16,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.mtime().max(0) as u64,
+            mtime_nsec: metadata.mtime_nsec() as u32,
+            rdev: metadata.rdev(),
+            size,
+            nlink: 1,
+            xattrs: collect_xattrs(path, ft.is_dir()),
+            data: Data::None,
+            nid: 0,
+        });
+
+        Ok(self.inodes.len() - 1)
+    }
+
+    fn alloc_blocks(&mut self, count: usize) -> u32 {
+        let blkaddr = self.next_block;
+        self.next_block += count as u32;
+        blkaddr
+    }
+
+    fn write_block(&mut self, data: &[u8]) -> Result<u32> {
+        let blkaddr = self.alloc_blocks(data.len().div_ceil(BLOCK_SIZE).max(1));
+        self.image
+            .write_all_at(data, (blkaddr as u64) << BLOCK_BITS)?;
+        Ok(blkaddr)
+    }
+
+    fn write_data(&mut self, idx: usize) -> Result<()> {
+        let data = match &self.inodes[idx].kind {
+            Kind::File if self.inodes[idx].size == 0 => Data::None,
+            Kind::File => {
+                let path = self.inodes[idx].path.clone();
+                let size = self.inodes[idx].size;
+
+                let compressed = if self.compress {
+                    let start = self.next_block;
+                    let data = self.write_compressed(&path, size)?;
+                    if data.is_none() {
+                        self.next_block = start;
+                    }
+                    data
+                } else {
+                    None
+                };
+
+                match compressed {
+                    Some(data) => data,
+                    None => self.write_plain(&path, size)?,
+                }
+            }
+            Kind::Symlink(target) => {
+                let target = target.clone();
+                Data::Plain {
+                    blkaddr: self.write_block(&target)?,
+                }
+            }
+            _ => Data::None,
+        };
+
+        self.inodes[idx].data = data;
+        Ok(())
+    }
+
+    fn write_plain(&mut self, path: &Path, size: u64) -> Result<Data> {
+        let blkaddr = self.alloc_blocks(size.div_ceil(BLOCK_SIZE as u64) as usize);
+
+        let mut file = File::open(path)?;
+        let mut buf = vec![0u8; BLOCK_SIZE * MAX_EXTENT_CLUSTERS];
+        let mut offset = 0u64;
+
+        while offset < size {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            self.image
+                .write_all_at(&buf[..read], ((blkaddr as u64) << BLOCK_BITS) + offset)?;
+            offset += read as u64;
+        }
+
+        ensure!(offset == size, "{} changed while packing", path.display());
+        Ok(Data::Plain { blkaddr })
+    }
+
+    fn write_compressed(&mut self, path: &Path, size: u64) -> Result<Option<Data>> {
+        let total_clusters = size.div_ceil(BLOCK_SIZE as u64) as usize;
+        let mut indexes = vec![[0u8; 8]; total_clusters];
+        let mut blocks = 0u32;
+
+        let mut file = File::open(path)?;
+        let mut window = Vec::with_capacity(BLOCK_SIZE * MAX_EXTENT_CLUSTERS);
+        let mut lcn = 0usize;
+
+        loop {
+            let wanted = BLOCK_SIZE * MAX_EXTENT_CLUSTERS - window.len();
+            (&mut file).take(wanted as u64).read_to_end(&mut window)?;
+            if window.is_empty() {
+                break;
+            }
+
+            let (consumed, block, lcluster_type) = pack_extent(&window);
+            let clusters = consumed.div_ceil(BLOCK_SIZE);
+            let blkaddr = self.write_block(&block)?;
+            blocks += 1;
+
+            ensure!(
+                lcn + clusters <= total_clusters,
+                "{} changed while packing",
+                path.display()
+            );
+
+            indexes[lcn] = lcluster_index(lcluster_type, blkaddr.to_le_bytes());
+            for i in 1..clusters {
+                let mut delta = [0u8; 4];
+                delta[..2].copy_from_slice(&(i as u16).to_le_bytes());
+                delta[2..].copy_from_slice(&((clusters - i) as u16).to_le_bytes());
+                indexes[lcn + i] = lcluster_index(LCLUSTER_TYPE_NONHEAD, delta);
+            }
+
+            lcn += clusters;
+            window.drain(..consumed);
+        }
+
+        ensure!(
+            lcn == total_clusters,
+            "{} changed while packing",
+            path.display()
+        );
+
+        if blocks as usize >= total_clusters {
+            return Ok(None);
+        }
+
+        Ok(Some(Data::Compressed { blocks, indexes }))
+    }
+
+    fn assign_nids(&mut self) -> Result<usize> {
+        let mut pos = 0usize;
+
+        // The root comes first so its nid fits the 16-bit superblock field.
+        for inode in &mut self.inodes {
+            let head = match inode.data {
+                Data::Compressed { .. } => inode.index_offset(),
+                _ => INODE_SIZE + inode.xattr_size(),
+            };
+            ensure!(
+                head <= BLOCK_SIZE,
+                "Too many xattrs on {}",
+                inode.path.display()
+            );
+
+            if pos % BLOCK_SIZE + head > BLOCK_SIZE {
+                pos = pos.next_multiple_of(BLOCK_SIZE);
+            }
+
+            inode.nid = (pos / SLOT_SIZE) as u64;
+            pos = (pos + inode.record_size()).next_multiple_of(SLOT_SIZE);
+        }
+
+        Ok(pos)
+    }
+
+    fn write_dir(&mut self, idx: usize) -> Result<()> {
+        let Kind::Dir(children) = &self.inodes[idx].kind else {
+            return Ok(());
+        };
+
+        let parent = self.inodes[idx].parent;
+
+        let mut entries: Vec<(&[u8], u64, u8)> = children
+            .iter()
+            .map(|(name, child)| {
+                let inode = &self.inodes[*child];
+                (name.as_bytes(), inode.nid, inode.file_type())
+            })
+            .collect();
+        entries.push((b".", self.inodes[idx].nid, FT_DIR));
+        entries.push((b"..", self.inodes[parent].nid, FT_DIR));
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut data = Vec::new();
+        let mut block_start = 0;
+
+        while block_start < entries.len() {
+            let mut count = 0;
+            let mut used = 0;
+            for entry in &entries[block_start..] {
+                if used + DIRENT_SIZE + entry.0.len() > BLOCK_SIZE {
+                    break;
+                }
+                used += DIRENT_SIZE + entry.0.len();
+                count += 1;
+            }
+
+            let block = &entries[block_start..block_start + count];
+            let mut buf = vec![0u8; used];
+            let mut nameoff = DIRENT_SIZE * count;
+
+            for (i, (name, nid, file_type)) in block.iter().enumerate() {
+                let de = &mut buf[i * DIRENT_SIZE..(i + 1) * DIRENT_SIZE];
+                de[0..8].copy_from_slice(&nid.to_le_bytes());
+                de[8..10].copy_from_slice(&(nameoff as u16).to_le_bytes());
+                de[10] = *file_type;
+
+                buf[nameoff..nameoff + name.len()].copy_from_slice(name);
+                nameoff += name.len();
+            }
+
+            data.resize(data.len().next_multiple_of(BLOCK_SIZE), 0);
+            data.extend_from_slice(&buf);
+            block_start += count;
+        }
+
+        let size = data.len() as u64;
+        let blkaddr = self.write_block(&data)?;
+
+        let inode = &mut self.inodes[idx];
+        inode.size = size;
+        inode.data = Data::Plain { blkaddr };
+        Ok(())
+    }
+
+    fn write_super(&self, meta_blkaddr: u32, total_blocks: u32) -> Result<()> {
+        let build_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut sb = [0u8; 128];
+        sb[0..4].copy_from_slice(&SUPER_MAGIC.to_le_bytes());
+        sb[12] = BLOCK_BITS;
+        sb[14..16].copy_from_slice(&(self.inodes[0].nid as u16).to_le_bytes());
+        sb[16..24].copy_from_slice(&(self.inodes.len() as u64).to_le_bytes());
+        sb[24..32].copy_from_slice(&build_time.as_secs().to_le_bytes());
+        sb[32..36].copy_from_slice(&build_time.subsec_nanos().to_le_bytes());
+        sb[36..40].copy_from_slice(&total_blocks.to_le_bytes());
+        sb[40..44].copy_from_slice(&meta_blkaddr.to_le_bytes());
+        sb[80..84].copy_from_slice(&FEATURE_INCOMPAT_ZERO_PADDING.to_le_bytes());
+
+        self.image.write_all_at(&sb, SUPER_OFFSET)?;
+        Ok(())
+    }
+}
+
+impl Inode {
+    fn file_type(&self) -> u8 {
+        match self.kind {
+            Kind::Dir(_) => FT_DIR,
+            Kind::File => FT_REG_FILE,
+            Kind::Symlink(_) => FT_SYMLINK,
+            Kind::Special(ft) => ft,
+        }
+    }
+
+    fn xattr_size(&self) -> usize {
+        if self.xattrs.is_empty() {
+            return 0;
+        }
+
+        XATTR_HEADER_SIZE
+            + self
+                .xattrs
+                .iter()
+                .map(|(_, name, value)| (4 + name.len() + value.len()).next_multiple_of(4))
+                .sum::<usize>()
+    }
+
+    fn index_offset(&self) -> usize {
+        (INODE_SIZE + self.xattr_size()).next_multiple_of(8) + MAP_HEADER_SIZE
+    }
+
+    fn record_size(&self) -> usize {
+        match &self.data {
+            Data::Compressed { indexes, .. } => self.index_offset() + indexes.len() * 8,
+            _ => INODE_SIZE + self.xattr_size(),
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.record_size()];
+
+        let (layout, i_u) = match &self.data {
+            Data::None => (LAYOUT_FLAT_PLAIN, encode_dev(self.rdev)),
+            Data::Plain { blkaddr, .. } => (LAYOUT_FLAT_PLAIN, *blkaddr),
+            Data::Compressed { blocks, .. } => (LAYOUT_COMPRESSED_FULL, *blocks),
+        };
+
+        let xattr_size = self.xattr_size();
+        let xattr_icount = if xattr_size == 0 {
+            0
+        } else {
+            (xattr_size - XATTR_HEADER_SIZE) / 4 + 1
+        };
+
+        buf[0..2].copy_from_slice(&(1 | (layout << 1)).to_le_bytes());
+        buf[2..4].copy_from_slice(&(xattr_icount as u16).to_le_bytes());
+        buf[4..6].copy_from_slice(&self.mode.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.size.to_le_bytes());
+        buf[16..20].copy_from_slice(&i_u.to_le_bytes());
+        buf[20..24].copy_from_slice(&(self.nid as u32).to_le_bytes());
+        buf[24..28].copy_from_slice(&self.uid.to_le_bytes());
+        buf[28..32].copy_from_slice(&self.gid.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.mtime.to_le_bytes());
+        buf[40..44].copy_from_slice(&self.mtime_nsec.to_le_bytes());
+        buf[44..48].copy_from_slice(&self.nlink.to_le_bytes());
+
+        let mut pos = INODE_SIZE + XATTR_HEADER_SIZE;
+        for (index, name, value) in &self.xattrs {
+            ensure!(
+                name.len() <= u8::MAX as usize && value.len() <= u16::MAX as usize,
+                "Xattr too large on {}",
+                self.path.display()
+            );
+
+            buf[pos] = name.len() as u8;
+            buf[pos + 1] = *index;
+            buf[pos + 2..pos + 4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+            pos += 4;
+            buf[pos..pos + name.len()].copy_from_slice(name);
+            pos += name.len();
+            buf[pos..pos + value.len()].copy_from_slice(value);
+            pos = (pos + value.len()).next_multiple_of(4);
+        }
+
+        if let Data::Compressed { indexes, .. } = &self.data {
+            // Map header: lz4, one block per logical cluster, no advise bits.
+            let mut pos = self.index_offset();
+            for index in indexes {
+                buf[pos..pos + 8].copy_from_slice(index);
+                pos += 8;
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+fn collect_xattrs(path: &Path, is_dir: bool) -> Vec<(u8, Vec<u8>, Vec<u8>)> {
+    let names = llistxattr(path).unwrap_or_default();
+
+    let mut xattrs: Vec<(u8, Vec<u8>, Vec<u8>)> = names
+        .iter()
+        .filter_map(|name| {
+            let bytes = name.as_bytes();
+            let (index, suffix) = if let Some(suffix) = bytes.strip_prefix(b"security.") {
+                (XATTR_INDEX_SECURITY, suffix)
+            } else if bytes.starts_with(b"trusted.overlay.") {
+                (XATTR_INDEX_TRUSTED, &bytes[b"trusted.".len()..])
+            } else {
+                return None;
+            };
+
+            let value = lgetxattr(path, name).ok()?;
+            Some((index, suffix.to_vec(), value))
+        })
+        .collect();
+
+    // Module sources mark opaque dirs with a file instead of the xattr.
+    let opaque = &defs::REPLACE_DIR_XATTR.as_bytes()[b"trusted.".len()..];
+    if is_dir
+        && !xattrs
+            .iter()
+            .any(|(index, name, _)| *index == XATTR_INDEX_TRUSTED && name == opaque)
+        && path.join(defs::REPLACE_DIR_FILE_NAME).is_file()
+    {
+        xattrs.push((XATTR_INDEX_TRUSTED, opaque.to_vec(), b"y".to_vec()));
+    }
+
+    xattrs.sort();
+    xattrs
+}
+
+fn pack_extent(window: &[u8]) -> (usize, Vec<u8>, u16) {
+    let max_clusters = window.len().div_ceil(BLOCK_SIZE);
+    let span = |clusters: usize| (clusters * BLOCK_SIZE).min(window.len());
+
+    let mut best: Option<(usize, Vec<u8>)> = None;
+    let (mut lo, mut hi) = (1, max_clusters);
+
+    while lo <= hi {
+        let mid = (lo + hi) / 2;
+        let compressed = lz4_flex::block::compress(&window[..span(mid)]);
+        if compressed.len() <= BLOCK_SIZE {
+            best = Some((span(mid), compressed));
+            lo = mid + 1;
+        } else {
+            hi = mid - 1;
+        }
+    }
+
+    match best {
+        // Zero padding puts the stream at the end of the block so its start is unambiguous.
+        Some((consumed, compressed)) => {
+            let mut block = vec![0u8; BLOCK_SIZE - compressed.len()];
+            block.extend_from_slice(&compressed);
+            (consumed, block, LCLUSTER_TYPE_HEAD)
+        }
+        None => {
+            let consumed = span(1);
+            (consumed, window[..consumed].to_vec(), LCLUSTER_TYPE_PLAIN)
+        }
+    }
+}
+
+fn lcluster_index(lcluster_type: u16, di_u: [u8; 4]) -> [u8; 8] {
+    let mut index = [0u8; 8];
+    index[0..2].copy_from_slice(&lcluster_type.to_le_bytes());
+    index[4..8].copy_from_slice(&di_u);
+    index
+}
+
+fn encode_dev(rdev: u64) -> u32 {
+    let major = ((rdev >> 8) & 0xfff) as u32;
+    let minor = ((rdev & 0xff) | ((rdev >> 12) & 0xfff00)) as u32;
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, os::unix::fs::symlink};
+
+    use rustix::{
+        fs::{FileType, Mode, makedev, mknodat},
+        mount::{MountFlags, UnmountFlags, unmount},
+    };
+
+    use super::*;
+    use crate::sys::loopdev;
+
+    const TEXT: &[u8] = b"persist.sys.usb.config=mtp\n";
+
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("erofs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    struct Fixture {
+        compressible: Vec<u8>,
+        random: Vec<u8>,
+        whiteout: bool,
+    }
+
+    fn fixture(root: &Path) -> Fixture {
+        let compressible: Vec<u8> = TEXT.iter().copied().cycle().take(150_000).collect();
+        let random: Vec<u8> = std::iter::repeat_with(|| fastrand::u8(..))
+            .take(3 * BLOCK_SIZE + 17)
+            .collect();
+
+        fs::create_dir_all(root.join("system/etc/opaque")).unwrap();
+        fs::create_dir_all(root.join("system/bin")).unwrap();
+        fs::write(root.join("system/etc/build.prop"), TEXT).unwrap();
+        fs::write(root.join("system/etc/big.prop"), &compressible).unwrap();
+        fs::write(root.join("system/etc/random.bin"), &random).unwrap();
+        fs::write(root.join("system/etc/empty"), b"").unwrap();
+        fs::write(
+            root.join("system/etc/opaque")
+                .join(defs::REPLACE_DIR_FILE_NAME),
+            b"",
+        )
+        .unwrap();
+        fs::hard_link(
+            root.join("system/etc/build.prop"),
+            root.join("system/bin/linked.prop"),
+        )
+        .unwrap();
+        symlink("/system/etc/build.prop", root.join("system/bin/prop")).unwrap();
+
+        // Whiteouts need CAP_MKNOD, they are left out elsewhere.
+        let whiteout = mknodat(
+            rustix::fs::CWD,
+            root.join("system/bin/removed"),
+            FileType::CharacterDevice,
+            Mode::from_raw_mode(0o644),
+            makedev(0, 0),
+        )
+        .is_ok();
+
+        Fixture {
+            compressible,
+            random,
+            whiteout,
+        }
+    }
+
+    struct Image(Vec<u8>);
+
+    impl Image {
+        fn u16(&self, at: usize) -> u16 {
+            u16::from_le_bytes(self.0[at..at + 2].try_into().unwrap())
+        }
+
+        fn u32(&self, at: usize) -> u32 {
+            u32::from_le_bytes(self.0[at..at + 4].try_into().unwrap())
+        }
+
+        fn u64(&self, at: usize) -> u64 {
+            u64::from_le_bytes(self.0[at..at + 8].try_into().unwrap())
+        }
+
+        fn block(&self, blkaddr: u32) -> usize {
+            (blkaddr as usize) << BLOCK_BITS
+        }
+
+        fn inode(&self, nid: u64) -> usize {
+            self.block(self.u32(SUPER_OFFSET as usize + 40)) + nid as usize * SLOT_SIZE
+        }
+
+        fn root(&self) -> u64 {
+            self.u16(SUPER_OFFSET as usize + 14) as u64
+        }
+
+        fn xattrs(&self, nid: u64) -> Vec<(u8, Vec<u8>, Vec<u8>)> {
+            let at = self.inode(nid);
+            let icount = self.u16(at + 2) as usize;
+            if icount == 0 {
+                return Vec::new();
+            }
+
+            let end = at + INODE_SIZE + XATTR_HEADER_SIZE + (icount - 1) * 4;
+            let mut pos = at + INODE_SIZE + XATTR_HEADER_SIZE;
+            let mut xattrs = Vec::new();
+            while pos < end {
+                let name_len = self.0[pos] as usize;
+                let index = self.0[pos + 1];
+                let value_len = self.u16(pos + 2) as usize;
+                let name = self.0[pos + 4..pos + 4 + name_len].to_vec();
+                let value = self.0[pos + 4 + name_len..pos + 4 + name_len + value_len].to_vec();
+                xattrs.push((index, name, value));
+                pos = (pos + 4 + name_len + value_len).next_multiple_of(4);
+            }
+            xattrs
+        }
+
+        fn data(&self, nid: u64) -> Vec<u8> {
+            let at = self.inode(nid);
+            let layout = (self.u16(at) >> 1) & 0x7;
+            let size = self.u64(at + 8) as usize;
+            let i_u = self.u32(at + 16);
+
+            match layout {
+                LAYOUT_FLAT_PLAIN => {
+                    let start = self.block(i_u);
+                    self.0[start..start + size].to_vec()
+                }
+                LAYOUT_COMPRESSED_FULL => {
+                    let xattr_size = match self.u16(at + 2) as usize {
+                        0 => 0,
+                        icount => XATTR_HEADER_SIZE + (icount - 1) * 4,
+                    };
+                    let indexes =
+                        at + (INODE_SIZE + xattr_size).next_multiple_of(8) + MAP_HEADER_SIZE;
+                    let clusters = size.div_ceil(BLOCK_SIZE);
+                    let index = |lcn: usize| indexes + lcn * 8;
+
+                    let mut out = Vec::with_capacity(size);
+                    let mut lcn = 0;
+                    while lcn < clusters {
+                        let lcluster_type = self.u16(index(lcn));
+                        assert_ne!(
+                            lcluster_type, LCLUSTER_TYPE_NONHEAD,
+                            "extent starts mid-way"
+                        );
+
+                        let mut extent = 1;
+                        while lcn + extent < clusters
+                            && self.u16(index(lcn + extent)) == LCLUSTER_TYPE_NONHEAD
+                        {
+                            assert_eq!(self.u16(index(lcn + extent) + 4) as usize, extent);
+                            extent += 1;
+                        }
+
+                        let len = (extent * BLOCK_SIZE).min(size - lcn * BLOCK_SIZE);
+                        let block = &self.0[self.block(self.u32(index(lcn) + 4))..][..BLOCK_SIZE];
+                        if lcluster_type == LCLUSTER_TYPE_HEAD {
+                            let start = block.iter().position(|&b| b != 0).unwrap();
+                            out.extend(lz4_flex::block::decompress(&block[start..], len).unwrap());
+                        } else {
+                            out.extend_from_slice(&block[..len]);
+                        }
+                        lcn += extent;
+                    }
+                    out
+                }
+                other => panic!("unexpected layout {}", other),
+            }
+        }
+
+        fn dir(&self, nid: u64) -> BTreeMap<Vec<u8>, (u64, u8)> {
+            let data = self.data(nid);
+            let mut entries = BTreeMap::new();
+
+            for block in data.chunks(BLOCK_SIZE) {
+                let count = u16::from_le_bytes([block[8], block[9]]) as usize / DIRENT_SIZE;
+                for i in 0..count {
+                    let de = &block[i * DIRENT_SIZE..(i + 1) * DIRENT_SIZE];
+                    let nameoff = u16::from_le_bytes([de[8], de[9]]) as usize;
+                    let end = if i + 1 < count {
+                        u16::from_le_bytes([
+                            block[(i + 1) * DIRENT_SIZE + 8],
+                            block[(i + 1) * DIRENT_SIZE + 9],
+                        ]) as usize
+                    } else {
+                        block[nameoff..]
+                            .iter()
+                            .position(|&b| b == 0)
+                            .map_or(block.len(), |len| nameoff + len)
+                    };
+                    let nid = u64::from_le_bytes(de[0..8].try_into().unwrap());
+                    entries.insert(block[nameoff..end].to_vec(), (nid, de[10]));
+                }
+            }
+            entries
+        }
+
+        fn lookup(&self, path: &str) -> (u64, u8) {
+            path.split('/')
+                .fold((self.root(), FT_DIR), |(nid, _), name| {
+                    self.dir(nid)[name.as_bytes()]
+                })
+        }
+    }
+
+    #[test]
+    fn image_layout_round_trips() {
+        let scratch = Scratch::new("layout");
+        let src = scratch.0.join("src");
+        let fixture = fixture(&src);
+        let image_path = scratch.0.join("modules.img");
+
+        build_image(&src, &image_path, true).unwrap();
+        let image = Image(fs::read(&image_path).unwrap());
+
+        assert_eq!(image.0.len() % BLOCK_SIZE, 0);
+        assert_eq!(image.u32(SUPER_OFFSET as usize), SUPER_MAGIC);
+        assert_eq!(image.0[SUPER_OFFSET as usize + 12], BLOCK_BITS);
+        assert_eq!(
+            image.u32(SUPER_OFFSET as usize + 36) as usize,
+            image.0.len() / BLOCK_SIZE
+        );
+
+        let root = image.dir(image.root());
+        assert_eq!(root[&b"."[..]].0, image.root());
+        assert_eq!(root[&b".."[..]].0, image.root());
+        assert_eq!(root[&b"system"[..]].1, FT_DIR);
+
+        let (nid, ft) = image.lookup("system/etc/build.prop");
+        assert_eq!(ft, FT_REG_FILE);
+        assert_eq!(image.data(nid), TEXT);
+
+        let (linked, _) = image.lookup("system/bin/linked.prop");
+        assert_eq!(linked, nid);
+        assert_eq!(image.u32(image.inode(nid) + 44), 2);
+
+        let (nid, _) = image.lookup("system/etc/big.prop");
+        assert_eq!(
+            (image.u16(image.inode(nid)) >> 1) & 0x7,
+            LAYOUT_COMPRESSED_FULL
+        );
+        assert_eq!(image.data(nid), fixture.compressible);
+
+        let (nid, _) = image.lookup("system/etc/random.bin");
+        assert_eq!((image.u16(image.inode(nid)) >> 1) & 0x7, LAYOUT_FLAT_PLAIN);
+        assert_eq!(image.data(nid), fixture.random);
+
+        let (nid, _) = image.lookup("system/etc/empty");
+        assert!(image.data(nid).is_empty());
+
+        let (nid, ft) = image.lookup("system/bin/prop");
+        assert_eq!(ft, FT_SYMLINK);
+        assert_eq!(image.data(nid), b"/system/etc/build.prop");
+
+        let (nid, _) = image.lookup("system/etc/opaque");
+        assert!(image.xattrs(nid).contains(&(
+            XATTR_INDEX_TRUSTED,
+            b"overlay.opaque".to_vec(),
+            b"y".to_vec()
+        )));
+
+        if fixture.whiteout {
+            let (nid, ft) = image.lookup("system/bin/removed");
+            assert_eq!(ft, FT_CHRDEV);
+            assert_eq!(image.u32(image.inode(nid) + 16), 0);
+        }
+    }
+
+    #[test]
+    fn image_mounts_as_erofs() {
+        let supported = fs::read_to_string("/proc/filesystems")
+            .is_ok_and(|filesystems| filesystems.lines().any(|line| line.ends_with("\terofs")));
+        if !rustix::process::geteuid().is_root() || !supported {
+            eprintln!("Skipping EROFS mount test: needs root and erofs support");
+            return;
+        }
+
+        let scratch = Scratch::new("mount");
+        let src = scratch.0.join("src");
+        let fixture = fixture(&src);
+        let image_path = scratch.0.join("modules.img");
+        let mnt = scratch.0.join("mnt");
+        fs::create_dir_all(&mnt).unwrap();
+
+        build_image(&src, &image_path, true).unwrap();
+        loopdev::mount_image(&image_path, &mnt, c"erofs", MountFlags::RDONLY, None).unwrap();
+
+        let checked = std::panic::catch_unwind(|| {
+            let etc = mnt.join("system/etc");
+            assert_eq!(fs::read(etc.join("build.prop")).unwrap(), TEXT);
+            assert_eq!(
+                fs::read(etc.join("big.prop")).unwrap(),
+                fixture.compressible
+            );
+            assert_eq!(fs::read(etc.join("random.bin")).unwrap(), fixture.random);
+            assert!(fs::read(etc.join("empty")).unwrap().is_empty());
+            assert_eq!(
+                lgetxattr(etc.join("opaque"), defs::REPLACE_DIR_XATTR).unwrap(),
+                b"y"
+            );
+
+            let bin = mnt.join("system/bin");
+            assert_eq!(
+                fs::read_link(bin.join("prop")).unwrap(),
+                Path::new("/system/etc/build.prop")
+            );
+            let linked = bin.join("linked.prop").metadata().unwrap();
+            assert_eq!(
+                linked.ino(),
+                etc.join("build.prop").metadata().unwrap().ino()
+            );
+            assert_eq!(linked.nlink(), 2);
+
+            if fixture.whiteout {
+                let removed = bin.join("removed").symlink_metadata().unwrap();
+                assert!(removed.file_type().is_char_device());
+                assert_eq!(removed.rdev(), 0);
+            }
+        });
+
+        unmount(&mnt, UnmountFlags::DETACH).unwrap();
+        if let Err(panic) = checked {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+};
+
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use super::scanner::Module;
+
+/// Digest of everything that shapes what the modules mount, stable across builds and boots.
+pub fn compute(modules: &[Module]) -> String {
+    let mut hasher = Sha256::new();
+
+    field(&mut hasher, env!("CARGO_PKG_VERSION").as_bytes());
+
+    let mut sorted: Vec<&Module> = modules.iter().collect();
+    sorted.sort_by(|a, b| a.id.cmp(&b.id));
+
+    for module in sorted {
+        field(&mut hasher, module.id.as_bytes());
+
+        field(
+            &mut hasher,
+            format!("{:?}", module.rules.default_mode).as_bytes(),
+        );
+        field(&mut hasher, format!("{:?}", module.placement).as_bytes());
+        hasher.update((module.rules.exclude.len() as u64).to_le_bytes());
+        for glob in &module.rules.exclude {
+            field(&mut hasher, glob.as_bytes());
+        }
+        let paths: BTreeMap<&String, String> = module
+            .rules
+            .paths
+            .iter()
+            .map(|(k, v)| (k, format!("{:?}", v)))
+            .collect();
+        hasher.update((paths.len() as u64).to_le_bytes());
+        for (path, mode) in paths {
+            field(&mut hasher, path.as_bytes());
+            field(&mut hasher, mode.as_bytes());
+        }
+
+        for entry in WalkDir::new(&module.source_path)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .flatten()
+        {
+            let Ok(metadata) = entry.path().symlink_metadata() else {
+                continue;
+            };
+
+            let relative = entry
+                .path()
+                .strip_prefix(&module.source_path)
+                .unwrap_or(entry.path());
+            field(&mut hasher, relative.as_os_str().as_bytes());
+            hasher.update(metadata.mode().to_le_bytes());
+            hasher.update(metadata.uid().to_le_bytes());
+            hasher.update(metadata.gid().to_le_bytes());
+            hasher.update(metadata.len().to_le_bytes());
+            hasher.update(metadata.mtime().to_le_bytes());
+            hasher.update(metadata.mtime_nsec().to_le_bytes());
+            hasher.update(metadata.ctime().to_le_bytes());
+            hasher.update(metadata.rdev().to_le_bytes());
+
+            if metadata.file_type().is_symlink()
+                && let Ok(target) = fs::read_link(entry.path())
+            {
+                field(&mut hasher, target.as_os_str().as_bytes());
+            }
+        }
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Length-prefixed so neighbouring fields cannot run into each other.
+fn field(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
@@ -1,5 +1,7 @@
+pub mod fingerprint;
 pub mod model;
 pub mod scanner;
+pub mod versions;
 
 pub use scanner::*;
 
@@ -12,7 +12,7 @@ use serde::Serialize;
 
 use super::scanner as inventory;
 use crate::{
-    conf::config::{self, MountMode},
+    conf::config::{self, MountMode, OverlayMode},
     core::state::RuntimeState,
     defs, utils,
 };
@@ -20,11 +20,12 @@ use crate::{
 static MODULE_PROP_REGEX: OnceLock<Regex> = OnceLock::new();
 
 #[derive(Default)]
-struct ModuleProp {
-    name: String,
-    version: String,
-    author: String,
-    description: String,
+pub struct ModuleProp {
+    pub name: String,
+    pub version: String,
+    pub version_code: String,
+    pub author: String,
+    pub description: String,
 }
 
 impl From<&Path> for ModuleProp {
@@ -43,6 +44,7 @@ impl From<&Path> for ModuleProp {
                     match k {
                         "name" => prop.name = v.to_string(),
                         "version" => prop.version = v.to_string(),
+                        "versionCode" => prop.version_code = v.to_string(),
                         "author" => prop.author = v.to_string(),
                         "description" => prop.description = v.to_string(),
                         _ => {}
@@ -74,6 +76,7 @@ impl ModuleInfo {
             MountMode::Overlay => "auto",
             MountMode::Magic => "magic",
             MountMode::Ignore => "ignore",
+            MountMode::Poaceae => "poaceae",
         };
 
         Self {
@@ -98,6 +101,7 @@ pub fn print_list(config: &config::Config) -> Result<()> {
         .overlay_modules
         .iter()
         .chain(state.magic_modules.iter())
+        .chain(state.poaceae_modules.iter())
         .map(|s| s.as_str())
         .collect();
 
@@ -111,7 +115,7 @@ pub fn print_list(config: &config::Config) -> Result<()> {
     Ok(())
 }
 
-pub fn update_description(storage_mode: &str, overlay_count: usize, magic_count: usize) {
+pub fn update_description(storage_mode: OverlayMode, overlay_count: usize, magic_count: usize) {
     let prop_path = Path::new(defs::MODULE_PROP_FILE);
 
     if !prop_path.exists() {
@@ -119,15 +123,17 @@ pub fn update_description(storage_mode: &str, overlay_count: usize, magic_count:
     }
 
     let mode_str = match storage_mode {
-        "tmpfs" => "Tmpfs",
-        "erofs" => "EROFS",
-        _ => "Ext4",
+        OverlayMode::Tmpfs => "Tmpfs",
+        OverlayMode::Erofs => "EROFS",
+        OverlayMode::Squashfs => "SquashFS",
+        OverlayMode::Ext4 => "Ext4",
     };
 
     let status_emoji = match storage_mode {
-        "tmpfs" => "🐾",
-        "erofs" => "🚀",
-        _ => "💿",
+        OverlayMode::Tmpfs => "🐾",
+        OverlayMode::Erofs => "🚀",
+        OverlayMode::Squashfs => "📦",
+        OverlayMode::Ext4 => "💿",
     };
 
     let desc_text = format!(
@@ -8,8 +8,9 @@ use anyhow::Result;
 use rayon::prelude::*;
 use serde::Deserialize;
 
+use super::versions;
 use crate::{
-    conf::config::{self, ModuleRules, MountMode},
+    conf::config::{self, ModuleRules, MountMode, PoaceaeRules, StoragePlacement},
     defs,
 };
 
@@ -17,6 +18,9 @@ use crate::{
 struct PartialRules {
     default_mode: Option<MountMode>,
     paths: Option<HashMap<String, MountMode>>,
+    storage: Option<StoragePlacement>,
+    exclude: Option<Vec<String>>,
+    poaceae: Option<PoaceaeRules>,
 }
 
 fn load_module_rules(module_dir: &Path, module_id: &str, cfg: &config::Config) -> ModuleRules {
@@ -24,6 +28,7 @@ fn load_module_rules(module_dir: &Path, module_id: &str, cfg: &config::Config) -
         default_mode: match cfg.default_mode {
             config::DefaultMode::Overlay => MountMode::Overlay,
             config::DefaultMode::Magic => MountMode::Magic,
+            config::DefaultMode::Poaceae => MountMode::Poaceae,
         },
         ..Default::default()
     };
@@ -40,6 +45,15 @@ fn load_module_rules(module_dir: &Path, module_id: &str, cfg: &config::Config) -
                     if let Some(paths) = partial.paths {
                         rules.paths = paths;
                     }
+                    if partial.storage.is_some() {
+                        rules.storage = partial.storage;
+                    }
+                    if let Some(exclude) = partial.exclude {
+                        rules.exclude = exclude;
+                    }
+                    if let Some(poaceae) = partial.poaceae {
+                        rules.poaceae = poaceae;
+                    }
                 }
                 Err(e) => {
                     log::warn!("Failed to parse rules for module '{}': {}", module_id, e)
@@ -52,6 +66,13 @@ fn load_module_rules(module_dir: &Path, module_id: &str, cfg: &config::Config) -
     if let Some(global_rules) = cfg.rules.get(module_id) {
         rules.default_mode = global_rules.default_mode.clone();
         rules.paths.extend(global_rules.paths.clone());
+        if global_rules.storage.is_some() {
+            rules.storage = global_rules.storage;
+        }
+        rules.exclude.extend(global_rules.exclude.iter().cloned());
+        let mut poaceae = global_rules.poaceae.clone();
+        poaceae.merge_from(&rules.poaceae);
+        rules.poaceae = poaceae;
     }
 
     rules
@@ -62,6 +83,7 @@ pub struct Module {
     pub id: String,
     pub source_path: PathBuf,
     pub rules: ModuleRules,
+    pub placement: StoragePlacement,
 }
 
 pub fn scan(source_dir: &Path, cfg: &config::Config) -> Result<Vec<Module>> {
@@ -98,11 +120,15 @@ pub fn scan(source_dir: &Path, cfg: &config::Config) -> Result<Vec<Module>> {
 
             let rules = load_module_rules(&path, &id, cfg);
 
-            Some(Module {
+            let mut module = Module {
                 id,
                 source_path: path,
+                placement: rules.storage.unwrap_or_default(),
                 rules,
-            })
+            };
+            versions::resolve_rollback(&mut module);
+
+            Some(module)
         })
         .collect();
 
@@ -0,0 +1,247 @@
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{model::ModuleProp, scanner::Module};
+use crate::{core::ops::exclude::ExcludeSet, defs, utils};
+
+const INDEX_FILE_NAME: &str = "index.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetainedVersion {
+    pub key: String,
+    pub version: String,
+    pub version_code: String,
+    pub synced_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Rollback {
+    /// Retained version mounted in place of the installed one.
+    key: String,
+    /// Version installed when the rollback was requested, an update clears the rollback.
+    installed: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct VersionIndex {
+    #[serde(default)]
+    versions: Vec<RetainedVersion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rollback: Option<Rollback>,
+}
+
+impl VersionIndex {
+    fn load(module_id: &str) -> Self {
+        fs::read_to_string(module_versions_dir(module_id).join(INDEX_FILE_NAME))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, module_id: &str) -> Result<()> {
+        let dir = module_versions_dir(module_id);
+        if self.versions.is_empty() {
+            if dir.exists() {
+                fs::remove_dir_all(&dir)?;
+            }
+            return Ok(());
+        }
+
+        let content = serde_json::to_string_pretty(self)?;
+        utils::atomic_write(dir.join(INDEX_FILE_NAME), content)
+            .with_context(|| format!("Failed to write version index of {}", module_id))
+    }
+
+    fn active_rollback(&self, installed: Option<&str>) -> Option<&Rollback> {
+        self.rollback
+            .as_ref()
+            .filter(|rollback| Some(rollback.installed.as_str()) == installed)
+    }
+}
+
+fn module_versions_dir(module_id: &str) -> PathBuf {
+    Path::new(defs::MODULE_VERSIONS_DIR).join(module_id)
+}
+
+fn version_of(module_dir: &Path) -> Option<RetainedVersion> {
+    let prop = ModuleProp::from(module_dir.join("module.prop").as_path());
+    if prop.version.is_empty() && prop.version_code.is_empty() {
+        return None;
+    }
+
+    let key = format!("{}-{}", prop.version_code, prop.version)
+        .replace(|c: char| c == '/' || c.is_whitespace(), "_");
+
+    Some(RetainedVersion {
+        key,
+        version: prop.version,
+        version_code: prop.version_code,
+        synced_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    })
+}
+
+/// Points the module at a retained copy when a rollback is pending for its installed version.
+pub fn resolve_rollback(module: &mut Module) {
+    let index = VersionIndex::load(&module.id);
+    if index.rollback.is_none() {
+        return;
+    }
+
+    let installed = version_of(&module.source_path).map(|v| v.key);
+    let Some(rollback) = index.active_rollback(installed.as_deref()) else {
+        return;
+    };
+
+    let retained = module_versions_dir(&module.id).join(&rollback.key);
+    if retained.is_dir() {
+        log::debug!("Module {} rolled back to {}", module.id, rollback.key);
+        module.source_path = retained;
+    }
+}
+
+/// Keeps a copy of each installed module version and the newest `keep` earlier ones.
+///
+/// Rollbacks made stale by a module update are dropped, as are versions of uninstalled modules.
+pub fn retain(modules: &[Module], moduledir: &Path, keep: usize) {
+    modules.par_iter().for_each(|module| {
+        if let Err(e) = retain_module(module, &moduledir.join(&module.id), keep) {
+            log::warn!("Failed to retain versions of {}: {:#}", module.id, e);
+        }
+    });
+
+    let Ok(entries) = fs::read_dir(defs::MODULE_VERSIONS_DIR) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if !moduledir.join(entry.file_name()).exists() {
+            log::info!(
+                "Removing versions of uninstalled module: {}",
+                entry.file_name().to_string_lossy()
+            );
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+fn retain_module(module: &Module, module_dir: &Path, keep: usize) -> Result<()> {
+    let mut index = VersionIndex::load(&module.id);
+    let current = version_of(module_dir);
+    let installed = current.as_ref().map(|v| v.key.clone());
+
+    if index.rollback.is_some() && index.active_rollback(installed.as_deref()).is_none() {
+        log::info!("Module {} was updated, clearing its rollback", module.id);
+        index.rollback = None;
+    }
+
+    let dir = module_versions_dir(&module.id);
+    if let Some(current) = current
+        && keep > 0
+        && !index.versions.iter().any(|v| v.key == current.key)
+    {
+        let tmp = dir.join(format!(".tmp_{}", current.key));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+
+        log::info!("Retaining version {} of {}", current.key, module.id);
+        let excludes = ExcludeSet::for_module(module);
+        let copied = utils::sync_dir_filtered(module_dir, &tmp, None, &|relative| {
+            excludes.is_excluded(relative)
+        });
+        if let Err(e) = copied {
+            let _ = fs::remove_dir_all(&tmp);
+            return Err(e);
+        }
+        fs::rename(&tmp, dir.join(&current.key))?;
+
+        index.versions.insert(0, current);
+    }
+
+    index.versions.sort_by_key(|v| Reverse(v.synced_at));
+    // The installed version does not count towards `keep`, so an update never prunes the
+    // version it replaced.
+    let pinned = index.rollback.as_ref().map(|r| r.key.clone());
+    let mut previous = 0;
+    let (kept, pruned): (Vec<_>, Vec<_>) = index.versions.drain(..).partition(|v| {
+        if keep > 0 && Some(&v.key) == installed.as_ref() {
+            return true;
+        }
+        if previous < keep {
+            previous += 1;
+            return true;
+        }
+        Some(&v.key) == pinned.as_ref()
+    });
+
+    for version in pruned {
+        log::debug!("Pruning version {} of {}", version.key, module.id);
+        let _ = fs::remove_dir_all(dir.join(&version.key));
+    }
+    index.versions = kept;
+
+    index.save(&module.id)
+}
+
+/// Mounts a retained version of the module from the next boot on.
+///
+/// Without `to`, the version retained before the one currently mounted is picked. Rolling
+/// back to the installed version clears the rollback.
+pub fn rollback(module_dir: &Path, module_id: &str, to: Option<&str>) -> Result<RetainedVersion> {
+    let mut index = VersionIndex::load(module_id);
+    if index.versions.is_empty() {
+        bail!("No versions of {} are retained", module_id);
+    }
+
+    let installed = version_of(module_dir)
+        .map(|v| v.key)
+        .with_context(|| format!("Module {} has no version in module.prop", module_id))?;
+    let mounted = index
+        .active_rollback(Some(&installed))
+        .map_or(installed.clone(), |rollback| rollback.key.clone());
+
+    let target = match to {
+        Some(to) => index
+            .versions
+            .iter()
+            .find(|v| v.version == to || v.version_code == to || v.key == to)
+            .with_context(|| format!("Version {} of {} is not retained", to, module_id))?,
+        None => {
+            let mounted_at = index
+                .versions
+                .iter()
+                .find(|v| v.key == mounted)
+                .map_or(u64::MAX, |v| v.synced_at);
+            index
+                .versions
+                .iter()
+                .filter(|v| v.synced_at < mounted_at)
+                .max_by_key(|v| v.synced_at)
+                .with_context(|| format!("No earlier version of {} is retained", module_id))?
+        }
+    }
+    .clone();
+
+    let retained = module_versions_dir(module_id).join(&target.key);
+    if target.key != installed && !retained.is_dir() {
+        bail!("Retained copy of {} {} is missing", module_id, target.key);
+    }
+
+    index.rollback = (target.key != installed).then(|| Rollback {
+        key: target.key.clone(),
+        installed,
+    });
+    index.save(module_id)?;
+
+    Ok(target)
+}
@@ -3,34 +3,35 @@ use std::path::{Path, PathBuf};
 use anyhow::Result;
 
 use crate::{
-    conf::config::Config,
+    conf::config::{Config, OverlayMode, StoragePlacement},
     core::{
-        inventory,
-        inventory::model as modules,
-        ops::{executor, planner, sync},
-        state, storage,
-        storage::StorageHandle,
+        inventory::{self, model as modules, versions},
+        ops::{dedup, executor, planner, sync},
+        state,
+        storage::{self, StorageBackend, StorageContext, placement},
     },
+    defs,
 };
 
 pub struct Init;
 
 pub struct StorageReady {
-    pub handle: StorageHandle,
+    pub storage: Box<dyn StorageBackend>,
 }
 
 pub struct ModulesReady {
-    pub handle: StorageHandle,
+    pub storage: Box<dyn StorageBackend>,
     pub modules: Vec<inventory::Module>,
+    pub skipped_modules: Vec<String>,
 }
 
 pub struct Planned {
-    pub handle: StorageHandle,
+    pub storage: Box<dyn StorageBackend>,
     pub plan: planner::MountPlan,
 }
 
 pub struct Executed {
-    pub handle: StorageHandle,
+    pub storage: Box<dyn StorageBackend>,
     pub plan: planner::MountPlan,
     pub result: executor::ExecutionResult,
 }
@@ -58,27 +59,37 @@ impl MountController<Init> {
         mnt_base: &Path,
         img_path: &Path,
     ) -> Result<MountController<StorageReady>> {
-        let handle = storage::setup(
-            mnt_base,
-            img_path,
-            &self.config.moduledir,
-            matches!(
-                self.config.overlay_mode,
-                crate::conf::config::OverlayMode::Ext4
-            ),
-            matches!(
-                self.config.overlay_mode,
-                crate::conf::config::OverlayMode::Erofs
-            ),
-            &self.config.mountsource,
-            self.config.disable_umount,
-        )?;
+        let chain = self.config.storage_chain();
+
+        let fingerprint = if chain.contains(&OverlayMode::Erofs) {
+            let modules = inventory::scan(&self.config.moduledir, &self.config)?;
+            Some(inventory::fingerprint::compute(&modules))
+        } else {
+            None
+        };
 
-        log::info!(">> Storage Backend: [{}]", handle.mode.to_uppercase());
+        let ctx = StorageContext {
+            mnt_base: mnt_base.to_path_buf(),
+            img_path: img_path.to_path_buf(),
+            moduledir: self.config.moduledir.clone(),
+            mount_source: self.config.mountsource.clone(),
+            disable_umount: self.config.disable_umount,
+            fingerprint,
+        };
+
+        let storage = storage::setup(&ctx, &chain)?;
+
+        log::info!(
+            ">> Storage Backend: [{}]",
+            storage.mode().to_string().to_uppercase()
+        );
+        if let Some(bytes) = storage.capacity() {
+            log::info!(">> Storage capacity: {} MiB available", bytes / 1024 / 1024);
+        }
 
         Ok(MountController {
             config: self.config,
-            state: StorageReady { handle },
+            state: StorageReady { storage },
             tempdir: self.tempdir,
         })
     }
@@ -86,57 +97,124 @@ impl MountController<Init> {
 
 impl MountController<StorageReady> {
     pub fn scan_and_sync(mut self) -> Result<MountController<ModulesReady>> {
-        let modules = inventory::scan(&self.config.moduledir, &self.config)?;
+        let mut modules = inventory::scan(&self.config.moduledir, &self.config)?;
 
         log::info!(
             ">> Inventory Scan: Found {} enabled modules.",
             modules.len()
         );
 
-        sync::perform_sync(&modules, &self.state.handle.mount_point)?;
+        versions::retain(
+            &modules,
+            &self.config.moduledir,
+            self.config.sync.keep_versions,
+        );
 
-        if self.state.handle.mode == "erofs_staging" {
-            let needs_magic = modules.iter().any(|m| {
-                m.rules.default_mode == inventory::MountMode::Magic
-                    || m.rules
-                        .paths
-                        .values()
-                        .any(|v| *v == inventory::MountMode::Magic)
-            });
+        placement::resolve(
+            &mut modules,
+            self.state.storage.mode(),
+            &self.config.placement,
+        );
 
-            if needs_magic {
-                let magic_ws = self.state.handle.mount_point.join("magic_workspace");
-                if !magic_ws.exists() {
-                    let _ = std::fs::create_dir(magic_ws);
+        let skipped_modules = match self.sync_storage(&mut modules) {
+            Ok(skipped) => skipped,
+            Err(e) => {
+                if let Err(teardown_err) = self.state.storage.teardown() {
+                    log::warn!("Failed to tear down storage: {:#}", teardown_err);
                 }
+                return Err(e);
             }
-        }
-
-        self.state.handle.commit(self.config.disable_umount)?;
+        };
 
         Ok(MountController {
             config: self.config,
             state: ModulesReady {
-                handle: self.state.handle,
+                storage: self.state.storage,
                 modules,
+                skipped_modules,
             },
             tempdir: self.tempdir,
         })
     }
+
+    /// Returns the ids of modules left out because storage could not fit them.
+    fn sync_storage(&mut self, modules: &mut Vec<inventory::Module>) -> Result<Vec<String>> {
+        let storage = &mut self.state.storage;
+
+        if storage.is_current() {
+            log::info!(">> Storage content unchanged, skipping sync.");
+            return Ok(Vec::new());
+        }
+
+        let skipped = placement::fit_to_capacity(storage.as_mut(), modules, &self.config.placement);
+        placement::mount_images(modules, storage.mount_point(), self.config.disable_umount);
+
+        let synced: Vec<inventory::Module> = modules
+            .iter()
+            .filter(|m| m.placement == StoragePlacement::Tmpfs)
+            .cloned()
+            .collect();
+
+        let repair_root = self
+            .config
+            .repair_context
+            .then_some(self.config.root.as_path());
+        sync::perform_sync(
+            &synced,
+            storage.mount_point(),
+            repair_root,
+            &self.config.sync,
+        )?;
+
+        match dedup::deduplicate(storage.mount_point()) {
+            Ok(saved) if saved > 0 => {
+                log::info!(">> Deduplicated module files, saved {} KiB", saved / 1024)
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to deduplicate storage: {:#}", e),
+        }
+
+        if storage.is_read_only() {
+            // Links are kept by the image writer, the store itself need not be packed.
+            let _ = std::fs::remove_dir_all(storage.mount_point().join(defs::DEDUP_STORE_DIR_NAME));
+
+            // PoaceaeFS modules fall back to magic mount when it is not available.
+            let needs_magic = modules.iter().any(|m| {
+                let magic = |mode: &inventory::MountMode| {
+                    matches!(
+                        mode,
+                        inventory::MountMode::Magic | inventory::MountMode::Poaceae
+                    )
+                };
+                magic(&m.rules.default_mode) || m.rules.paths.values().any(magic)
+            });
+
+            if needs_magic {
+                let magic_ws = storage.mount_point().join("magic_workspace");
+                if !magic_ws.exists() {
+                    let _ = std::fs::create_dir(magic_ws);
+                }
+            }
+        }
+
+        storage.commit()?;
+        Ok(skipped)
+    }
 }
 
 impl MountController<ModulesReady> {
     pub fn generate_plan(self) -> Result<MountController<Planned>> {
-        let plan = planner::generate(
+        let mut plan = planner::generate(
             &self.config,
             &self.state.modules,
-            &self.state.handle.mount_point,
+            self.state.storage.mount_point(),
         )?;
+        plan.skipped_modules = self.state.skipped_modules;
 
         Ok(MountController {
             config: self.config,
             state: Planned {
-                handle: self.state.handle,
+                storage: self.state.storage,
                 plan,
             },
             tempdir: self.tempdir,
@@ -153,7 +231,7 @@ impl MountController<Planned> {
         Ok(MountController {
             config: self.config,
             state: Executed {
-                handle: self.state.handle,
+                storage: self.state.storage,
                 plan: self.state.plan,
                 result,
             },
@@ -165,7 +243,7 @@ impl MountController<Planned> {
 impl MountController<Executed> {
     pub fn finalize(self) -> Result<()> {
         modules::update_description(
-            &self.state.handle.mode,
+            self.state.storage.mode(),
             self.state.result.overlay_module_ids.len(),
             self.state.result.magic_module_ids.len(),
         );
@@ -181,13 +259,16 @@ impl MountController<Executed> {
         active_mounts.sort();
         active_mounts.dedup();
 
-        let state = state::RuntimeState::new(
-            self.state.handle.mode,
-            self.state.handle.mount_point,
+        let mut state = state::RuntimeState::new(
+            self.state.storage.mode(),
+            self.state.storage.mount_point().to_path_buf(),
             self.state.result.overlay_module_ids,
             self.state.result.magic_module_ids,
             active_mounts,
         );
+        state.poaceae_modules = self.state.result.poaceae_module_ids;
+        state.poaceae = self.state.result.poaceae;
+        state.skipped_modules = self.state.plan.skipped_modules;
 
         if let Err(e) = state.save() {
             log::error!("Failed to save runtime state: {:#}", e);
@@ -1,6 +1,10 @@
+pub mod bugreport;
+pub mod capture;
+pub mod erofs;
 pub mod inventory;
 pub mod manager;
 pub mod ops;
+pub mod squashfs;
 pub mod state;
 pub mod storage;
 
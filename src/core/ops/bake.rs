@@ -0,0 +1,264 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::{self, Metadata},
+    os::unix::fs::{FileTypeExt, MetadataExt, symlink},
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use rustix::fs::{AtFlags, CWD, Gid, Uid, chownat};
+
+use crate::{
+    conf::config::Config,
+    core::{inventory::Module, ops::planner},
+    defs,
+    mount::{
+        magic_mount,
+        node::{Node, NodeFileType},
+    },
+    utils,
+};
+
+#[derive(Debug, Default)]
+pub struct BakeReport {
+    pub partitions: Vec<String>,
+    pub overlay_module_ids: Vec<String>,
+    pub magic_module_ids: Vec<String>,
+    pub files: usize,
+    pub whiteouts: usize,
+    pub opaque_dirs: usize,
+}
+
+pub fn bake(config: &Config, modules: &[Module], out: &Path) -> Result<BakeReport> {
+    let root = config.root.as_path();
+
+    ensure!(
+        !utils::is_host_root(root),
+        "Refusing to bake into the live system, pass --root <extracted tree>"
+    );
+
+    let root = root
+        .canonicalize()
+        .with_context(|| format!("Root tree {} is not accessible", root.display()))?;
+
+    utils::ensure_dir_exists(out)?;
+    let out = out.canonicalize()?;
+
+    ensure!(
+        !out.starts_with(&root) && !root.starts_with(&out),
+        "Output directory {} overlaps the root tree {}",
+        out.display(),
+        root.display()
+    );
+    ensure!(
+        fs::read_dir(&out)?.next().is_none(),
+        "Output directory {} is not empty",
+        out.display()
+    );
+
+    let mut config = config.clone();
+    config.root = root.clone();
+
+    let plan = planner::generate(&config, modules, &config.moduledir)
+        .context("Failed to generate plan for bake")?;
+
+    let magic_ids: HashSet<String> = plan.magic_module_ids.iter().cloned().collect();
+    let magic_root = if magic_ids.is_empty() {
+        None
+    } else {
+        magic_mount::collect_module_files(&plan.module_paths, &root, &config.partitions, magic_ids)?
+    };
+
+    let mut partitions: BTreeSet<String> = plan
+        .overlay_ops
+        .iter()
+        .map(|op| op.partition_name.clone())
+        .collect();
+
+    if let Some(node) = &magic_root {
+        partitions.extend(node.children.keys().cloned());
+    }
+
+    for partition in &partitions {
+        let stock = root.join(partition);
+        if !stock.is_dir() {
+            continue;
+        }
+
+        log::info!("Copying stock partition: {}", partition);
+        utils::sync_dir(&stock, &out.join(partition), None)
+            .with_context(|| format!("Failed to copy stock partition {}", partition))?;
+    }
+
+    let mut report = BakeReport {
+        partitions: partitions.into_iter().collect(),
+        overlay_module_ids: plan.overlay_module_ids.clone(),
+        magic_module_ids: plan.magic_module_ids.clone(),
+        ..Default::default()
+    };
+
+    for op in &plan.overlay_ops {
+        let target = Path::new(&op.target);
+        let relative = target
+            .strip_prefix(&root)
+            .with_context(|| format!("Overlay target {} escapes the root tree", op.target))?;
+        let dst = out.join(relative);
+
+        log::info!(
+            "Baking {} [OVERLAY] (Layers: {})",
+            relative.display(),
+            op.lowerdirs.len()
+        );
+
+        // lowerdirs are ordered top-most first, so apply them from the bottom up.
+        for layer in op.lowerdirs.iter().rev() {
+            apply_layer(layer, &dst, target, &mut report)
+                .with_context(|| format!("Failed to bake layer {}", layer.display()))?;
+        }
+    }
+
+    if let Some(node) = &magic_root {
+        log::info!(">> Baking Magic Mount modules...");
+        for (name, child) in &node.children {
+            apply_node(child, &out.join(name), &root.join(name), &mut report)?;
+        }
+    }
+
+    Ok(report)
+}
+
+fn apply_layer(layer: &Path, dst: &Path, stock: &Path, report: &mut BakeReport) -> Result<()> {
+    for entry in fs::read_dir(layer)?.flatten() {
+        let name = entry.file_name();
+        if name == defs::REPLACE_DIR_FILE_NAME {
+            continue;
+        }
+
+        let src_path = entry.path();
+        let dst_path = dst.join(&name);
+        let stock_path = stock.join(&name);
+        let metadata = src_path.symlink_metadata()?;
+        let ft = metadata.file_type();
+
+        if ft.is_char_device() && metadata.rdev() == 0 {
+            remove_entry(&dst_path)?;
+            report.whiteouts += 1;
+        } else if ft.is_dir() {
+            if Node::dir_is_replace(&src_path) {
+                remove_entry(&dst_path)?;
+                report.opaque_dirs += 1;
+            } else if !is_real_dir(&dst_path) {
+                remove_entry(&dst_path)?;
+            }
+
+            if !is_real_dir(&dst_path) {
+                fs::create_dir(&dst_path)?;
+            }
+            copy_metadata(&src_path, &dst_path, &stock_path, &metadata);
+
+            apply_layer(&src_path, &dst_path, &stock_path, report)?;
+        } else {
+            place_entry(&src_path, &dst_path, &stock_path, &metadata)?;
+            report.files += 1;
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_node(node: &Node, dst: &Path, stock: &Path, report: &mut BakeReport) -> Result<()> {
+    if node.name == defs::REPLACE_DIR_FILE_NAME {
+        return Ok(());
+    }
+
+    match node.file_type {
+        NodeFileType::Whiteout => {
+            remove_entry(dst)?;
+            report.whiteouts += 1;
+        }
+        NodeFileType::RegularFile | NodeFileType::Symlink => {
+            let Some(src) = &node.module_path else {
+                bail!("cannot bake root file {}!", dst.display());
+            };
+            place_entry(src, dst, stock, &src.symlink_metadata()?)?;
+            report.files += 1;
+        }
+        NodeFileType::Directory => {
+            if node.replace && node.module_path.is_some() {
+                remove_entry(dst)?;
+                report.opaque_dirs += 1;
+            } else if !is_real_dir(dst) {
+                remove_entry(dst)?;
+            }
+
+            if !is_real_dir(dst) {
+                fs::create_dir(dst)?;
+            }
+            if let Some(src) = &node.module_path {
+                copy_metadata(src, dst, stock, &src.symlink_metadata()?);
+            }
+
+            for (name, child) in &node.children {
+                apply_node(child, &dst.join(name), &stock.join(name), report)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn is_real_dir(path: &Path) -> bool {
+    path.symlink_metadata()
+        .map(|m| m.file_type().is_dir())
+        .unwrap_or(false)
+}
+
+fn remove_entry(path: &Path) -> Result<()> {
+    match path.symlink_metadata() {
+        Ok(m) if m.file_type().is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+fn place_entry(src: &Path, dst: &Path, stock: &Path, metadata: &Metadata) -> Result<()> {
+    remove_entry(dst)?;
+
+    let ft = metadata.file_type();
+    if ft.is_symlink() {
+        symlink(fs::read_link(src)?, dst)?;
+    } else if ft.is_file() {
+        utils::reflink_or_copy(src, dst)?;
+        fs::set_permissions(dst, metadata.permissions())?;
+    } else {
+        log::warn!("Skipping unsupported file type: {}", src.display());
+        return Ok(());
+    }
+
+    copy_metadata(src, dst, stock, metadata);
+    Ok(())
+}
+
+fn copy_metadata(src: &Path, dst: &Path, stock: &Path, metadata: &Metadata) {
+    if metadata.file_type().is_dir() {
+        let _ = fs::set_permissions(dst, metadata.permissions());
+    }
+
+    let _ = chownat(
+        CWD,
+        dst,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+        AtFlags::SYMLINK_NOFOLLOW,
+    );
+
+    let context = utils::lgetfilecon(src)
+        .ok()
+        .filter(|c| !c.is_empty())
+        .or_else(|| utils::lgetfilecon(stock).ok().filter(|c| !c.is_empty()));
+
+    if let Some(context) = context {
+        let _ = utils::lsetfilecon(dst, &context);
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::HashSet,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use extattr::{lgetxattr, llistxattr};
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::{defs, utils};
+
+#[derive(Debug, Default, Serialize)]
+pub struct DedupStats {
+    pub files: u64,
+    pub shared_files: u64,
+    pub logical_bytes: u64,
+    pub physical_bytes: u64,
+    pub saved_bytes: u64,
+}
+
+/// Hard links identical module files to one copy in the content-addressed store.
+///
+/// Files only share an inode when content, mode, owner, mtime and xattrs all match, so
+/// linking never changes what a lowerdir exposes.
+pub fn deduplicate(storage_root: &Path) -> Result<u64> {
+    let store = storage_root.join(defs::DEDUP_STORE_DIR_NAME);
+    utils::ensure_dir_exists(&store)?;
+
+    let candidates: Vec<(PathBuf, u64)> = module_files(storage_root)
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            (metadata.len() > 0 && metadata.nlink() == 1)
+                .then(|| (entry.into_path(), metadata.len()))
+        })
+        .collect();
+
+    let keyed: Vec<(PathBuf, u64, String)> = candidates
+        .into_par_iter()
+        .filter_map(|(path, size)| match content_key(&path) {
+            Ok(key) => Some((path, size, key)),
+            Err(e) => {
+                log::debug!("Skipping dedup of {}: {:#}", path.display(), e);
+                None
+            }
+        })
+        .collect();
+
+    let mut saved = 0;
+    for (path, size, key) in keyed {
+        let stored = store.join(&key[..2]).join(&key);
+
+        if stored.exists() {
+            match replace_with_link(&stored, &path) {
+                Ok(()) => saved += size,
+                Err(e) => log::debug!("Failed to link {}: {:#}", path.display(), e),
+            }
+        } else {
+            utils::ensure_dir_exists(stored.parent().unwrap_or(&store))?;
+            if let Err(e) = fs::hard_link(&path, &stored) {
+                log::debug!("Failed to store {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    prune_store(&store);
+    Ok(saved)
+}
+
+pub fn stats(storage_root: &Path) -> DedupStats {
+    let mut stats = DedupStats::default();
+    let mut seen = HashSet::new();
+
+    for entry in module_files(storage_root) {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+
+        stats.files += 1;
+        stats.logical_bytes += metadata.len();
+
+        if seen.insert((metadata.dev(), metadata.ino())) {
+            stats.physical_bytes += metadata.len();
+        } else {
+            stats.shared_files += 1;
+        }
+    }
+
+    stats.saved_bytes = stats.logical_bytes - stats.physical_bytes;
+    stats
+}
+
+// Regular files of synced modules, leaving out the store, in-flight copies and other mounts.
+fn module_files(storage_root: &Path) -> impl Iterator<Item = walkdir::DirEntry> {
+    WalkDir::new(storage_root)
+        .min_depth(1)
+        .same_file_system(true)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() > 1 || !entry.file_name().to_string_lossy().starts_with('.')
+        })
+        .flatten()
+        .filter(|entry| {
+            entry.file_type().is_file() && entry.file_name() != defs::SYNC_MANIFEST_FILE_NAME
+        })
+}
+
+fn content_key(path: &Path) -> Result<String> {
+    let metadata = path.symlink_metadata()?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(metadata.mode().to_le_bytes());
+    hasher.update(metadata.uid().to_le_bytes());
+    hasher.update(metadata.gid().to_le_bytes());
+    hasher.update(metadata.mtime().to_le_bytes());
+    hasher.update(metadata.mtime_nsec().to_le_bytes());
+
+    let mut names = llistxattr(path).unwrap_or_default();
+    names.sort();
+    for name in names {
+        let value = lgetxattr(path, &name).unwrap_or_default();
+        hasher.update(name.as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(&value);
+    }
+
+    let attrs: String = hasher
+        .finalize()
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    Ok(format!("{}-{}", utils::hash_file(path)?, attrs))
+}
+
+fn replace_with_link(stored: &Path, path: &Path) -> Result<()> {
+    let file_name = path.file_name().context("Path has no file name")?;
+    let tmp = path.with_file_name(format!(".dedup_{}", file_name.to_string_lossy()));
+
+    let _ = fs::remove_file(&tmp);
+    fs::hard_link(stored, &tmp)?;
+
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn prune_store(store: &Path) {
+    for entry in WalkDir::new(store)
+        .min_depth(1)
+        .contents_first(true)
+        .into_iter()
+        .flatten()
+    {
+        let path = entry.path();
+        if entry.file_type().is_dir() {
+            let _ = fs::remove_dir(path);
+        } else if entry.metadata().is_ok_and(|m| m.nlink() == 1) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::*;
+
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("dedup-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn file(&self, relative: &str, content: &[u8], mtime: u64) -> PathBuf {
+            let path = self.0.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, content).unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+                .unwrap();
+            path
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn ino(path: &Path) -> u64 {
+        path.metadata().unwrap().ino()
+    }
+
+    #[test]
+    fn identical_files_share_an_inode() {
+        let storage = Scratch::new("identical");
+        let a = storage.file("mod_a/system/etc/hosts", b"127.0.0.1 localhost\n", 1_000);
+        let b = storage.file("mod_b/system/etc/hosts", b"127.0.0.1 localhost\n", 1_000);
+
+        assert!(deduplicate(&storage.0).unwrap() > 0);
+        assert_eq!(ino(&a), ino(&b));
+        assert_eq!(stats(&storage.0).shared_files, 1);
+    }
+
+    #[test]
+    fn files_differing_only_in_mtime_stay_separate() {
+        let storage = Scratch::new("mtime");
+        let a = storage.file("mod_a/system/etc/hosts", b"127.0.0.1 localhost\n", 1_000);
+        let b = storage.file("mod_b/system/etc/hosts", b"127.0.0.1 localhost\n", 2_000);
+
+        assert_eq!(deduplicate(&storage.0).unwrap(), 0);
+        assert_ne!(ino(&a), ino(&b));
+        assert_eq!(a.metadata().unwrap().mtime(), 1_000);
+        assert_eq!(b.metadata().unwrap().mtime(), 2_000);
+    }
+}
@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use regex_lite::Regex;
+
+use crate::{core::inventory::Module, defs};
+
+/// Module content that is never mounted and so is kept out of storage and overlay layers.
+pub struct ExcludeSet {
+    patterns: Vec<Regex>,
+}
+
+impl ExcludeSet {
+    pub fn for_module(module: &Module) -> Self {
+        let globs = defs::SYNC_EXCLUDES
+            .iter()
+            .copied()
+            .chain(module.rules.exclude.iter().map(String::as_str));
+
+        let patterns = globs
+            .filter_map(|glob| match Regex::new(&glob_to_regex(glob)) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    log::warn!(
+                        "Ignoring invalid exclude '{}' for {}: {}",
+                        glob,
+                        module.id,
+                        e
+                    );
+                    None
+                }
+            })
+            .collect();
+
+        Self { patterns }
+    }
+
+    /// Whether `relative`, a path inside the module root, is excluded.
+    pub fn is_excluded(&self, relative: &Path) -> bool {
+        let Some(path) = relative.to_str() else {
+            return false;
+        };
+        !path.is_empty() && self.patterns.iter().any(|re| re.is_match(path))
+    }
+}
+
+// Gitignore-like: a leading or inner `/` anchors the glob at the module root, otherwise it
+// matches a name at any depth. `*` and `?` stay within one component, `**` spans several.
+fn glob_to_regex(glob: &str) -> String {
+    let trimmed = glob.trim().trim_end_matches('/');
+    let anchored = trimmed.contains('/');
+    let body = trimmed.trim_start_matches('/');
+
+    let mut re = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+    let mut chars = body.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex_lite::escape(&c.to_string())),
+        }
+    }
+
+    re.push('$');
+    re
+}
@@ -1,22 +1,27 @@
 use std::{collections::HashSet, path::Path};
 
 use anyhow::Result;
+use rustix::fs::{StatVfsMountFlags, statvfs};
 
 use crate::{
-    conf::config,
-    core::ops::planner::MountPlan,
+    conf::config::{self, PoaceaeRules},
+    core::ops::{planner::MountPlan, poaceae},
     defs,
     mount::{
         magic_mount,
         overlayfs::{self, utils::umount_dir},
         umount_mgr,
     },
+    sys::mount,
     utils,
 };
 
 pub struct ExecutionResult {
     pub overlay_module_ids: Vec<String>,
     pub magic_module_ids: Vec<String>,
+    pub poaceae_module_ids: Vec<String>,
+    /// PoaceaeFS rules active once the plan has run.
+    pub poaceae: PoaceaeRules,
 }
 
 pub fn execute<P>(plan: &MountPlan, config: &config::Config, tempdir: P) -> Result<ExecutionResult>
@@ -26,6 +31,18 @@ where
     let mut final_magic_ids: HashSet<String> = plan.magic_module_ids.iter().cloned().collect();
     let mut final_overlay_ids: HashSet<String> = HashSet::new();
 
+    let poaceae_ready =
+        !plan.poaceae_ops.is_empty() && mount::is_mounted(defs::POACEAE_MOUNT_POINT);
+    if !plan.poaceae_ops.is_empty() && !poaceae_ready {
+        log::warn!(
+            "PoaceaeFS is not mounted at {}. Fallback to Magic Mount.",
+            defs::POACEAE_MOUNT_POINT
+        );
+        for op in &plan.poaceae_ops {
+            final_magic_ids.insert(op.module_id.clone());
+        }
+    }
+
     log::info!(">> Phase 1: OverlayFS Execution...");
 
     for op in &plan.overlay_ops {
@@ -99,6 +116,10 @@ where
     let mut magic_queue: Vec<String> = final_magic_ids.iter().cloned().collect();
     magic_queue.sort();
 
+    if Path::new(defs::MAGIC_TREE_FILE).exists() {
+        let _ = std::fs::remove_file(defs::MAGIC_TREE_FILE);
+    }
+
     if !magic_queue.is_empty() {
         let magic_ws_path = tempdir.as_ref().join("magic_workspace");
         let _ = umount_mgr::TMPFS.set(magic_ws_path.to_string_lossy().to_string());
@@ -108,7 +129,11 @@ where
             magic_ws_path.display()
         );
 
-        if matches!(config.overlay_mode, config::OverlayMode::Erofs) {
+        let storage_read_only = statvfs(tempdir.as_ref())
+            .map(|stat| stat.f_flag.contains(StatVfsMountFlags::RDONLY))
+            .unwrap_or(false);
+
+        if storage_read_only {
             if magic_ws_path.exists() {
                 crate::sys::mount::mount_tmpfs(&magic_ws_path, "magic_ws")?;
                 #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -116,18 +141,18 @@ where
                     log::warn!("Failed to schedule unmount for magic_ws: {}", e);
                 }
             } else {
-                log::error!("Magic Mount anchor missing in EROFS image!");
+                log::error!("Magic Mount anchor missing in read-only storage image!");
             }
         } else if !magic_ws_path.exists() {
             std::fs::create_dir_all(&magic_ws_path)?;
         }
 
-        let module_dir = tempdir.as_ref();
         let magic_need_ids: HashSet<String> = magic_queue.iter().cloned().collect();
 
         if let Err(e) = magic_mount::magic_mount(
             &magic_ws_path,
-            module_dir,
+            &plan.module_paths,
+            &config.root,
             &config.mountsource,
             &config.partitions,
             magic_need_ids,
@@ -156,14 +181,28 @@ where
         }
     }
 
+    let mut desired = plan.poaceae.clone();
+    let mut result_poaceae = Vec::new();
+    if poaceae_ready {
+        log::info!(">> Phase 3: PoaceaeFS rules...");
+        for op in &plan.poaceae_ops {
+            desired.merge_from(&op.rules);
+            result_poaceae.push(op.module_id.clone());
+        }
+    }
+    let active_poaceae = poaceae::apply(&desired);
+
     let mut result_overlay: Vec<String> = final_overlay_ids.into_iter().collect();
     let mut result_magic: Vec<String> = final_magic_ids.into_iter().collect();
 
     result_overlay.sort();
     result_magic.sort();
+    result_poaceae.sort();
 
     Ok(ExecutionResult {
         overlay_module_ids: result_overlay,
         magic_module_ids: result_magic,
+        poaceae_module_ids: result_poaceae,
+        poaceae: active_poaceae,
     })
 }
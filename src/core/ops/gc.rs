@@ -0,0 +1,120 @@
+use std::{collections::HashSet, fs, os::unix::fs::MetadataExt, path::Path};
+
+use anyhow::Result;
+use rayon::prelude::*;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use super::journal::Journal;
+use crate::defs;
+
+const KEPT_ENTRIES: &[&str] = &["lost+found", "meta-hybrid", "magic_workspace"];
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LeftoverKind {
+    /// Unfinished `.tmp_<id>` copy.
+    Staging,
+    /// `.backup_<id>` of a completed swap.
+    Backup,
+    /// Storage of a module that is gone or no longer synced.
+    Orphan,
+    /// Deduplication store entry nothing links to anymore.
+    StoreEntry,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Leftover {
+    pub path: String,
+    pub kind: LeftoverKind,
+    pub bytes: u64,
+    pub removed: bool,
+}
+
+/// Finds storage entries no module owns and, unless `dry_run`, removes them.
+///
+/// Copies of swaps still recorded in the journal are left alone.
+pub fn collect(
+    target_base: &Path,
+    active_ids: &HashSet<&str>,
+    dry_run: bool,
+) -> Result<Vec<Leftover>> {
+    if !target_base.exists() {
+        return Ok(Vec::new());
+    }
+
+    let in_flight = Journal::open(target_base).pending();
+    let mut found = Vec::new();
+
+    for entry in fs::read_dir(target_base)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        let kind = if let Some(id) = name.strip_prefix(".tmp_") {
+            (!in_flight.contains_key(id)).then_some(LeftoverKind::Staging)
+        } else if let Some(id) = name.strip_prefix(".backup_") {
+            (!in_flight.contains_key(id)).then_some(LeftoverKind::Backup)
+        } else if name.starts_with('.') || KEPT_ENTRIES.contains(&name.as_str()) {
+            None
+        } else {
+            (!active_ids.contains(name.as_str())).then_some(LeftoverKind::Orphan)
+        };
+
+        if let Some(kind) = kind {
+            found.push((entry.path(), kind));
+        }
+    }
+
+    let store = target_base.join(defs::DEDUP_STORE_DIR_NAME);
+    for entry in WalkDir::new(&store).min_depth(2).into_iter().flatten() {
+        if entry
+            .metadata()
+            .is_ok_and(|m| m.is_file() && m.nlink() == 1)
+        {
+            found.push((entry.into_path(), LeftoverKind::StoreEntry));
+        }
+    }
+
+    let leftovers = found
+        .into_par_iter()
+        .map(|(path, kind)| {
+            let bytes = entry_size(&path);
+            let removed = !dry_run && remove(&path, kind);
+            Leftover {
+                path: path.display().to_string(),
+                kind,
+                bytes,
+                removed,
+            }
+        })
+        .collect();
+
+    Ok(leftovers)
+}
+
+fn remove(path: &Path, kind: LeftoverKind) -> bool {
+    log::info!("Removing {:?} leftover: {}", kind, path.display());
+
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("Failed to remove {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+fn entry_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
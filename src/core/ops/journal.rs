@@ -0,0 +1,240 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{defs, utils};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SwapPhase {
+    /// `.tmp_<id>` is being built, the live copy is untouched.
+    Staging,
+    /// `.tmp_<id>` is complete and verified, the renames into place have started.
+    Swapping,
+}
+
+/// Records module swaps in flight, so a sync cut short can be settled at the next boot.
+pub struct Journal {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, SwapPhase>>,
+}
+
+impl Journal {
+    pub fn open(target_base: &Path) -> Self {
+        let path = target_base.join(defs::SYNC_JOURNAL_FILE_NAME);
+        let entries = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    pub fn pending(&self) -> BTreeMap<String, SwapPhase> {
+        self.entries.lock().map(|e| e.clone()).unwrap_or_default()
+    }
+
+    pub fn record(&self, module_id: &str, phase: SwapPhase) -> Result<()> {
+        self.update(|entries| {
+            entries.insert(module_id.to_string(), phase);
+        })
+    }
+
+    pub fn clear(&self, module_id: &str) -> Result<()> {
+        self.update(|entries| {
+            entries.remove(module_id);
+        })
+    }
+
+    fn update(&self, change: impl FnOnce(&mut BTreeMap<String, SwapPhase>)) -> Result<()> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock sync journal"))?;
+        change(&mut entries);
+
+        if entries.is_empty() {
+            if self.path.exists() {
+                fs::remove_file(&self.path).context("Failed to remove sync journal")?;
+            }
+            return Ok(());
+        }
+
+        let content = serde_json::to_string(&*entries)?;
+        utils::atomic_write(&self.path, content).context("Failed to write sync journal")
+    }
+
+    /// Completes or rolls back every swap left in the journal.
+    pub fn recover(&self, target_base: &Path) {
+        for (module_id, phase) in self.pending() {
+            match recover_swap(target_base, &module_id, phase) {
+                Ok(outcome) => log::warn!("Interrupted sync of {}: {}", module_id, outcome),
+                Err(e) => log::error!(
+                    "Failed to recover interrupted sync of {}: {:#}",
+                    module_id,
+                    e
+                ),
+            }
+
+            if let Err(e) = self.clear(&module_id) {
+                log::warn!("{:#}", e);
+            }
+        }
+    }
+}
+
+pub fn staging_path(target_base: &Path, module_id: &str) -> PathBuf {
+    target_base.join(format!(".tmp_{}", module_id))
+}
+
+pub fn backup_path(target_base: &Path, module_id: &str) -> PathBuf {
+    target_base.join(format!(".backup_{}", module_id))
+}
+
+fn recover_swap(target_base: &Path, module_id: &str, phase: SwapPhase) -> Result<&'static str> {
+    let dst = target_base.join(module_id);
+    let tmp = staging_path(target_base, module_id);
+    let backup = backup_path(target_base, module_id);
+
+    let outcome = match phase {
+        SwapPhase::Staging => {
+            if tmp.exists() {
+                fs::remove_dir_all(&tmp)?;
+            }
+            "discarded unfinished copy"
+        }
+        // Neither rename happened, the new copy is dropped and synced again.
+        SwapPhase::Swapping if dst.exists() && tmp.exists() => {
+            fs::remove_dir_all(&tmp)?;
+            "rolled back to previous copy"
+        }
+        SwapPhase::Swapping if !dst.exists() && tmp.exists() => {
+            fs::rename(&tmp, &dst).context("Failed to move verified copy into place")?;
+            "completed swap"
+        }
+        SwapPhase::Swapping if !dst.exists() && backup.exists() => {
+            fs::rename(&backup, &dst).context("Failed to restore previous copy")?;
+            "restored previous copy"
+        }
+        SwapPhase::Swapping => "swap already completed",
+    };
+
+    if dst.exists() && backup.exists() {
+        fs::remove_dir_all(&backup)?;
+    }
+
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "demo";
+
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("journal-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn copy(&self, dir: &Path, version: &str) {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("module.prop"), version).unwrap();
+        }
+
+        fn live(&self) -> PathBuf {
+            self.0.join(ID)
+        }
+
+        /// Records `phase`, reopens the journal as the next boot would and recovers.
+        fn recover(&self, phase: SwapPhase) {
+            Journal::open(&self.0).record(ID, phase).unwrap();
+            Journal::open(&self.0).recover(&self.0);
+        }
+
+        fn assert_settled(&self, version: &str) {
+            assert_eq!(
+                fs::read_to_string(self.live().join("module.prop")).unwrap(),
+                version
+            );
+            assert!(!staging_path(&self.0, ID).exists());
+            assert!(!backup_path(&self.0, ID).exists());
+            assert!(Journal::open(&self.0).pending().is_empty());
+            assert!(!self.0.join(defs::SYNC_JOURNAL_FILE_NAME).exists());
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn staging_discards_the_unfinished_copy() {
+        let base = Scratch::new("staging");
+        base.copy(&base.live(), "old");
+        base.copy(&staging_path(&base.0, ID), "new");
+
+        base.recover(SwapPhase::Staging);
+
+        base.assert_settled("old");
+    }
+
+    #[test]
+    fn swapping_before_any_rename_rolls_back() {
+        let base = Scratch::new("before");
+        base.copy(&base.live(), "old");
+        base.copy(&staging_path(&base.0, ID), "new");
+
+        base.recover(SwapPhase::Swapping);
+
+        base.assert_settled("old");
+    }
+
+    #[test]
+    fn swapping_between_renames_completes_the_swap() {
+        let base = Scratch::new("between");
+        base.copy(&backup_path(&base.0, ID), "old");
+        base.copy(&staging_path(&base.0, ID), "new");
+
+        base.recover(SwapPhase::Swapping);
+
+        base.assert_settled("new");
+    }
+
+    #[test]
+    fn swapping_without_a_new_copy_restores_the_backup() {
+        let base = Scratch::new("restore");
+        base.copy(&backup_path(&base.0, ID), "old");
+
+        base.recover(SwapPhase::Swapping);
+
+        base.assert_settled("old");
+    }
+
+    #[test]
+    fn swapping_after_both_renames_drops_the_backup() {
+        let base = Scratch::new("after");
+        base.copy(&base.live(), "new");
+        base.copy(&backup_path(&base.0, ID), "old");
+
+        base.recover(SwapPhase::Swapping);
+
+        base.assert_settled("new");
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use extattr::{lgetxattr, llistxattr};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use super::exclude::ExcludeSet;
+use crate::{defs, utils};
+
+const MANIFEST_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Dir,
+    File,
+    Symlink,
+    Special,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub ino: u64,
+    /// Digest of the extended attributes, empty when there are none.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub xattrs: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// Per-file snapshot of a module source, stored next to its synced copy.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncManifest {
+    pub version: u32,
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncDiff {
+    /// Entries to copy from the source, parents before children.
+    pub changed: BTreeSet<String>,
+    /// Entries to drop from the previous copy, including ones whose type changed.
+    pub removed: BTreeSet<String>,
+    /// Files with unchanged content whose timestamps moved.
+    pub retimed: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DriftKind {
+    Missing,
+    Corrupted,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Drift {
+    pub path: String,
+    pub kind: DriftKind,
+}
+
+impl SyncDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty() && self.retimed.is_empty()
+    }
+}
+
+impl SyncManifest {
+    pub fn scan(root: &Path, excludes: &ExcludeSet) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+
+        let walker = WalkDir::new(root)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|entry| {
+                entry
+                    .path()
+                    .strip_prefix(root)
+                    .is_ok_and(|relative| !excludes.is_excluded(relative))
+            });
+
+        for entry in walker {
+            let entry = entry?;
+            let relative = entry.path().strip_prefix(root)?;
+            let relative = relative
+                .to_str()
+                .with_context(|| format!("Non UTF-8 path: {}", entry.path().display()))?;
+
+            let metadata = entry.path().symlink_metadata()?;
+            let ft = metadata.file_type();
+            let kind = if ft.is_dir() {
+                EntryKind::Dir
+            } else if ft.is_file() {
+                EntryKind::File
+            } else if ft.is_symlink() {
+                EntryKind::Symlink
+            } else {
+                EntryKind::Special
+            };
+
+            entries.insert(
+                relative.to_string(),
+                ManifestEntry {
+                    kind,
+                    mode: metadata.mode(),
+                    uid: metadata.uid(),
+                    gid: metadata.gid(),
+                    size: metadata.len(),
+                    mtime: metadata.mtime(),
+                    mtime_nsec: metadata.mtime_nsec(),
+                    ino: metadata.ino(),
+                    xattrs: xattr_digest(entry.path()),
+                    hash: None,
+                },
+            );
+        }
+
+        Ok(Self {
+            version: MANIFEST_VERSION,
+            entries,
+        })
+    }
+
+    /// Bytes of regular file content the manifest covers.
+    pub fn total_size(&self) -> u64 {
+        self.entries
+            .values()
+            .filter(|entry| entry.kind == EntryKind::File)
+            .map(|entry| entry.size)
+            .sum()
+    }
+
+    pub fn load(module_dir: &Path) -> Option<Self> {
+        let content = fs::read_to_string(module_dir.join(defs::SYNC_MANIFEST_FILE_NAME)).ok()?;
+        serde_json::from_str::<Self>(&content)
+            .ok()
+            .filter(|manifest| manifest.version == MANIFEST_VERSION)
+    }
+
+    pub fn save(&self, module_dir: &Path) -> Result<()> {
+        let content = serde_json::to_string(self)?;
+        utils::atomic_write(module_dir.join(defs::SYNC_MANIFEST_FILE_NAME), content)
+    }
+
+    /// Hashes every file that has no recorded hash yet.
+    pub fn hash_files(&mut self, src_dir: &Path) -> Result<()> {
+        self.entries
+            .par_iter_mut()
+            .filter(|(_, entry)| entry.kind == EntryKind::File && entry.hash.is_none())
+            .try_for_each(|(path, entry)| -> Result<()> {
+                entry.hash = Some(
+                    utils::hash_file(&src_dir.join(path))
+                        .with_context(|| format!("Failed to hash {}", path))?,
+                );
+                Ok(())
+            })
+    }
+
+    /// Checks files under `root` against their recorded hashes, limited to `only` when given.
+    pub fn verify(&self, root: &Path, only: Option<&BTreeSet<String>>) -> Vec<Drift> {
+        let mut drift: Vec<Drift> = self
+            .entries
+            .par_iter()
+            .filter(|(path, entry)| {
+                entry.kind == EntryKind::File && only.is_none_or(|only| only.contains(*path))
+            })
+            .filter_map(|(path, entry)| {
+                let expected = entry.hash.as_deref()?;
+                let kind = match utils::hash_file(&root.join(path)) {
+                    Ok(actual) if actual == expected => return None,
+                    Ok(_) => DriftKind::Corrupted,
+                    Err(_) => DriftKind::Missing,
+                };
+                Some(Drift {
+                    path: path.clone(),
+                    kind,
+                })
+            })
+            .collect();
+
+        drift.sort_by(|a, b| a.path.cmp(&b.path));
+        drift
+    }
+
+    /// Compares this source snapshot against the one `synced_dir` was built from.
+    ///
+    /// Files whose metadata moved but whose size did not are hashed before being
+    /// called changed, and the hashes are kept for the next comparison.
+    pub fn diff(&mut self, previous: &SyncManifest, src_dir: &Path, synced_dir: &Path) -> SyncDiff {
+        let mut diff = SyncDiff::default();
+
+        for (path, entry) in &mut self.entries {
+            let Some(old) = previous.entries.get(path) else {
+                diff.changed.insert(path.clone());
+                continue;
+            };
+
+            if old.kind != entry.kind {
+                diff.removed.insert(path.clone());
+                diff.changed.insert(path.clone());
+                continue;
+            }
+
+            let same_attrs = old.mode == entry.mode
+                && old.uid == entry.uid
+                && old.gid == entry.gid
+                && old.xattrs == entry.xattrs;
+            let same_stamp = old.size == entry.size
+                && old.mtime == entry.mtime
+                && old.mtime_nsec == entry.mtime_nsec
+                && old.ino == entry.ino;
+
+            let unchanged = match entry.kind {
+                EntryKind::Dir => same_attrs,
+                EntryKind::File if same_attrs && same_stamp => {
+                    entry.hash = old.hash.clone();
+                    true
+                }
+                EntryKind::File if same_attrs && old.size == entry.size => {
+                    let same = same_content(path, entry, old, src_dir, synced_dir);
+                    if same {
+                        diff.retimed.insert(path.clone());
+                    }
+                    same
+                }
+                _ => same_attrs && same_stamp,
+            };
+
+            if !unchanged {
+                diff.changed.insert(path.clone());
+            }
+        }
+
+        for path in previous.entries.keys() {
+            if !self.entries.contains_key(path) {
+                diff.removed.insert(path.clone());
+            }
+        }
+
+        diff
+    }
+}
+
+fn xattr_digest(path: &Path) -> String {
+    let mut names = llistxattr(path).unwrap_or_default();
+    if names.is_empty() {
+        return String::new();
+    }
+    names.sort();
+
+    let mut hasher = Sha256::new();
+    for name in names {
+        let value = lgetxattr(path, &name).unwrap_or_default();
+        hasher.update(name.as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(&value);
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn same_content(
+    path: &str,
+    entry: &mut ManifestEntry,
+    old: &ManifestEntry,
+    src_dir: &Path,
+    synced_dir: &Path,
+) -> bool {
+    let Ok(new_hash) = utils::hash_file(&src_dir.join(path)) else {
+        return false;
+    };
+
+    let old_hash = match &old.hash {
+        Some(hash) => Some(hash.clone()),
+        None => utils::hash_file(&synced_dir.join(path)).ok(),
+    };
+
+    let same = old_hash.as_deref() == Some(new_hash.as_str());
+    entry.hash = Some(new_hash);
+    same
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        path::PathBuf,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use extattr::{Flags, lsetxattr};
+
+    use super::*;
+    use crate::{conf::config::ModuleRules, core::inventory::Module};
+
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("manifest-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, relative: &str, content: &[u8]) {
+            let path = self.0.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        fn retime(&self, relative: &str, secs: u64) {
+            File::options()
+                .write(true)
+                .open(self.0.join(relative))
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        }
+
+        fn scan(&self) -> SyncManifest {
+            let module = Module {
+                id: "test".to_string(),
+                source_path: self.0.clone(),
+                rules: ModuleRules::default(),
+                placement: Default::default(),
+            };
+            SyncManifest::scan(&self.0, &ExcludeSet::for_module(&module)).unwrap()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn set(paths: &[&str]) -> BTreeSet<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn diff_sorts_changes_by_kind() {
+        let src = Scratch::new("diff");
+        for name in ["kept", "gone", "edited", "touched"] {
+            src.write(&format!("system/etc/{}", name), b"ro.debuggable=0\n");
+            src.retime(&format!("system/etc/{}", name), 1_000);
+        }
+        let mut previous = src.scan();
+        previous.hash_files(&src.0).unwrap();
+
+        fs::remove_file(src.0.join("system/etc/gone")).unwrap();
+        src.write("system/etc/added", b"new\n");
+        src.write("system/etc/edited", b"ro.debuggable=1\n");
+        src.retime("system/etc/touched", 2_000);
+
+        let diff = src.scan().diff(&previous, &src.0, &src.0);
+
+        assert_eq!(
+            diff.changed,
+            set(&["system/etc/added", "system/etc/edited"])
+        );
+        assert_eq!(diff.removed, set(&["system/etc/gone"]));
+        assert_eq!(diff.retimed, set(&["system/etc/touched"]));
+    }
+
+    #[test]
+    fn diff_notices_xattr_changes() {
+        let src = Scratch::new("xattr");
+        src.write("system/etc/hosts", b"127.0.0.1 localhost\n");
+        let previous = src.scan();
+
+        lsetxattr(
+            src.0.join("system/etc/hosts"),
+            "user.meta",
+            b"1",
+            Flags::empty(),
+        )
+        .unwrap();
+        lsetxattr(src.0.join("system"), "user.meta", b"1", Flags::empty()).unwrap();
+
+        let diff = src.scan().diff(&previous, &src.0, &src.0);
+
+        assert_eq!(diff.changed, set(&["system", "system/etc/hosts"]));
+        assert!(diff.removed.is_empty());
+    }
+}
@@ -1,3 +1,11 @@
+pub mod bake;
+pub mod dedup;
+pub mod exclude;
 pub mod executor;
+pub mod gc;
+pub mod journal;
+pub mod manifest;
 pub mod planner;
+pub mod poaceae;
+pub mod progress;
 pub mod sync;
@@ -1,6 +1,7 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
     path::{Path, PathBuf},
 };
 
@@ -11,22 +12,40 @@ use walkdir::WalkDir;
 
 use crate::{
     conf::config,
-    core::inventory::{Module, MountMode},
+    core::{
+        inventory::{Module, MountMode},
+        ops::exclude::ExcludeSet,
+        storage,
+    },
     defs, utils,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct OverlayOperation {
     pub partition_name: String,
     pub target: String,
     pub lowerdirs: Vec<PathBuf>,
 }
 
-#[derive(Debug, Default)]
+/// A module mounted by PoaceaeFS rules pointing into its source directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct PoaceaeOperation {
+    pub module_id: String,
+    pub rules: config::PoaceaeRules,
+}
+
+#[derive(Debug, Default, Serialize)]
 pub struct MountPlan {
+    pub root: PathBuf,
     pub overlay_ops: Vec<OverlayOperation>,
     pub overlay_module_ids: Vec<String>,
     pub magic_module_ids: Vec<String>,
+    pub poaceae_ops: Vec<PoaceaeOperation>,
+    pub poaceae: config::PoaceaeRules,
+    /// Where each module's content is read from, whatever strategy mounts it.
+    pub module_paths: BTreeMap<String, PathBuf>,
+    /// Modules left out because storage could not fit them.
+    pub skipped_modules: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -85,7 +104,7 @@ impl MountPlan {
                         if entry.path_is_symlink()
                             && let Ok(target) = std::fs::read_link(entry.path())
                             && target.is_absolute()
-                            && !target.exists()
+                            && !utils::reroot(&self.root, &target).exists()
                         {
                             local_diagnostics.push(DiagnosticIssue {
                                 level: DiagnosticLevel::Warning,
@@ -139,6 +158,73 @@ impl MountPlan {
     }
 }
 
+// Files present on the system are redirected to the module copy, new entries come in by merging
+// their parent directory, whiteouts hide the file and `.replace` directories are redirected whole.
+fn express_as_poaceae(
+    root: &Path,
+    module_root: &Path,
+    partition: &str,
+    excludes: &ExcludeSet,
+    rules: &mut config::PoaceaeRules,
+) {
+    let visible = |relative: &Path| Path::new("/").join(relative).to_string_lossy().to_string();
+    let source = |path: &Path| path.to_string_lossy().to_string();
+
+    let mut walker = WalkDir::new(module_root.join(partition))
+        .min_depth(1)
+        .into_iter();
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let Ok(relative) = entry.path().strip_prefix(module_root) else {
+            continue;
+        };
+        let is_dir = entry.file_type().is_dir();
+
+        if excludes.is_excluded(relative) || entry.file_name() == defs::REPLACE_DIR_FILE_NAME {
+            if is_dir {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+
+        let system_path = root.join(relative);
+        let parent = relative.parent().unwrap_or(relative);
+        let module_parent = entry.path().parent().unwrap_or(entry.path());
+
+        if is_dir {
+            if entry.path().join(defs::REPLACE_DIR_FILE_NAME).exists() {
+                rules
+                    .redirect
+                    .insert(visible(relative), source(entry.path()));
+                walker.skip_current_dir();
+            } else if !system_path.is_dir() {
+                rules.merge.insert(visible(parent), source(module_parent));
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+
+        let is_whiteout = entry
+            .metadata()
+            .is_ok_and(|m| m.file_type().is_char_device() && m.rdev() == 0);
+        let on_system = system_path.symlink_metadata().is_ok();
+
+        if is_whiteout {
+            if on_system {
+                rules.hide.insert(visible(relative));
+            }
+        } else if on_system {
+            rules
+                .redirect
+                .insert(visible(relative), source(entry.path()));
+        } else {
+            rules.merge.insert(visible(parent), source(module_parent));
+        }
+    }
+}
+
 struct ProcessingItem {
     module_source: PathBuf,
     system_target: PathBuf,
@@ -150,7 +236,11 @@ pub fn generate(
     modules: &[Module],
     storage_root: &Path,
 ) -> Result<MountPlan> {
-    let mut plan = MountPlan::default();
+    let root = config.root.as_path();
+    let mut plan = MountPlan {
+        root: root.to_path_buf(),
+        ..Default::default()
+    };
 
     let mut overlay_groups: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
 
@@ -159,14 +249,28 @@ pub fn generate(
 
     let sensitive_partitions: HashSet<&str> = defs::SENSITIVE_PARTITIONS.iter().cloned().collect();
 
+    // Global rules win over module ones naming the same path.
+    plan.poaceae = config.poaceae.clone();
+    let mut by_id: Vec<&Module> = modules.iter().collect();
+    by_id.sort_by(|a, b| a.id.cmp(&b.id));
+    for module in by_id {
+        plan.poaceae.merge_from(&module.rules.poaceae);
+    }
+    let mut poaceae_claimed = plan.poaceae.clone();
+
     for module in modules {
-        let mut content_path = storage_root.join(&module.id);
+        let mut content_path = storage::placement::content_path(storage_root, module);
         if !content_path.exists() {
             content_path = module.source_path.clone();
         }
         if !content_path.exists() {
             continue;
         }
+        plan.module_paths
+            .insert(module.id.clone(), content_path.clone());
+
+        let excludes = ExcludeSet::for_module(module);
+        let mut poaceae_rules = config::PoaceaeRules::default();
 
         if let Ok(entries) = fs::read_dir(&content_path) {
             for entry in entries.flatten() {
@@ -183,6 +287,10 @@ pub fn generate(
                     continue;
                 }
 
+                if excludes.is_excluded(Path::new(&dir_name)) {
+                    continue;
+                }
+
                 let mode = module.rules.get_mode(&dir_name);
                 if matches!(mode, MountMode::Magic) {
                     magic_ids.insert(module.id.clone());
@@ -191,13 +299,23 @@ pub fn generate(
                 if matches!(mode, MountMode::Ignore) {
                     continue;
                 }
+                if matches!(mode, MountMode::Poaceae) {
+                    express_as_poaceae(
+                        root,
+                        &module.source_path,
+                        &dir_name,
+                        &excludes,
+                        &mut poaceae_rules,
+                    );
+                    continue;
+                }
 
                 overlay_ids.insert(module.id.clone());
 
                 let mut queue = VecDeque::new();
                 queue.push_back(ProcessingItem {
                     module_source: path.clone(),
-                    system_target: PathBuf::from("/").join(&dir_name),
+                    system_target: root.join(&dir_name),
                     partition_label: dir_name.clone(),
                 });
 
@@ -208,32 +326,22 @@ pub fn generate(
                         partition_label,
                     } = item;
 
-                    if !system_target.exists() {
-                        continue;
-                    }
-
                     let resolved_target = match fs::read_link(&system_target) {
                         Ok(target) => {
                             if target.is_absolute() {
-                                target
+                                utils::reroot(root, &target)
                             } else {
-                                system_target
-                                    .parent()
-                                    .unwrap_or(Path::new("/"))
-                                    .join(target)
+                                system_target.parent().unwrap_or(root).join(target)
                             }
                         }
                         Err(_) => system_target.clone(),
                     };
 
-                    let canonical_target = if resolved_target.exists() {
-                        match resolved_target.canonicalize() {
-                            Ok(p) => p,
-                            Err(_) => resolved_target,
-                        }
-                    } else {
-                        resolved_target
-                    };
+                    let canonical_target = utils::resolve_in_root(root, &resolved_target);
+
+                    if !canonical_target.exists() {
+                        continue;
+                    }
 
                     let target_name = canonical_target
                         .file_name()
@@ -247,7 +355,11 @@ pub fn generate(
                         if let Ok(sub_entries) = fs::read_dir(&module_source) {
                             for sub_entry in sub_entries.flatten() {
                                 let sub_path = sub_entry.path();
-                                if !sub_path.is_dir() {
+                                if !sub_path.is_dir()
+                                    || sub_path
+                                        .strip_prefix(&content_path)
+                                        .is_ok_and(|relative| excludes.is_excluded(relative))
+                                {
                                     continue;
                                 }
                                 let sub_name = sub_entry.file_name();
@@ -268,6 +380,35 @@ pub fn generate(
                 }
             }
         }
+
+        if poaceae_rules.is_empty() {
+            continue;
+        }
+
+        // PoaceaeFS holds one redirect or merge per path. Declared rules and earlier modules keep
+        // theirs, a module claiming the same path again is magic mounted.
+        let claimed = poaceae_rules
+            .redirect
+            .keys()
+            .any(|path| poaceae_claimed.redirect.contains_key(path))
+            || poaceae_rules
+                .merge
+                .keys()
+                .any(|path| poaceae_claimed.merge.contains_key(path));
+        if claimed {
+            log::warn!(
+                "PoaceaeFS rules of {} overlap declared rules or another module, using magic mount",
+                module.id
+            );
+            magic_ids.insert(module.id.clone());
+            continue;
+        }
+
+        poaceae_claimed.merge_from(&poaceae_rules);
+        plan.poaceae_ops.push(PoaceaeOperation {
+            module_id: module.id.clone(),
+            rules: poaceae_rules,
+        });
     }
 
     for (target_path, layers) in overlay_groups {
@@ -278,8 +419,9 @@ pub fn generate(
         }
 
         let partition_name = target_path
-            .iter()
-            .nth(1)
+            .strip_prefix(root)
+            .ok()
+            .and_then(|p| p.iter().next())
             .map(|s| s.to_string_lossy().to_string())
             .unwrap_or_else(|| "unknown".to_string());
 
@@ -0,0 +1,390 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conf::config::PoaceaeRules,
+    defs,
+    sys::poaceae::{self, Device},
+    utils,
+};
+
+/// Rules set through each PoaceaeFS mount during this boot.
+///
+/// The kernel filesystem has no list ioctl, so every successful call is recorded here instead. Rules do
+/// not survive a reboot, a ledger written under another boot id is treated as empty.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Ledger {
+    boot_id: String,
+    #[serde(default)]
+    mounts: BTreeMap<String, PoaceaeRules>,
+}
+
+impl Ledger {
+    fn load() -> Self {
+        let boot_id = current_boot_id();
+        fs::read_to_string(defs::POACEAE_LEDGER_FILE)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Ledger>(&content).ok())
+            .filter(|ledger| ledger.boot_id == boot_id)
+            .unwrap_or(Ledger {
+                boot_id,
+                mounts: BTreeMap::new(),
+            })
+    }
+
+    fn save(&self) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        utils::atomic_write(defs::POACEAE_LEDGER_FILE, content)
+            .context("Failed to write PoaceaeFS rule ledger")
+    }
+}
+
+fn current_boot_id() -> String {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .map(|id| id.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Rules currently active on the PoaceaeFS mounted at `target`.
+///
+/// They are asked from `dev` when it can list them, the ledger is used otherwise.
+pub fn active(target: &Path, dev: Option<&dyn Device>) -> PoaceaeRules {
+    if let Some(rules) = dev.and_then(|dev| dev.list()) {
+        return rules;
+    }
+
+    Ledger::load()
+        .mounts
+        .remove(&target.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Records a rule change made directly through `sys::poaceae` on `target`.
+///
+/// Nothing is recorded for a device that lists its own rules.
+pub fn record(
+    target: &Path,
+    dev: &dyn Device,
+    change: impl FnOnce(&mut PoaceaeRules),
+) -> Result<()> {
+    if dev.list().is_some() {
+        return Ok(());
+    }
+
+    let mut ledger = Ledger::load();
+    change(
+        ledger
+            .mounts
+            .entry(target.to_string_lossy().to_string())
+            .or_default(),
+    );
+    ledger.save()
+}
+
+/// Changes turning one rule set into another; a changed entry is removed, then added back.
+#[derive(Debug, Default, Serialize)]
+pub struct RuleDiff {
+    pub add: PoaceaeRules,
+    pub remove: PoaceaeRules,
+}
+
+impl RuleDiff {
+    pub fn new(actual: &PoaceaeRules, desired: &PoaceaeRules) -> Self {
+        let mut diff = Self::default();
+
+        diff.remove.hide = actual.hide.difference(&desired.hide).cloned().collect();
+        diff.add.hide = desired.hide.difference(&actual.hide).cloned().collect();
+        diff.remove.redirect = changed(&actual.redirect, &desired.redirect);
+        diff.add.redirect = changed(&desired.redirect, &actual.redirect);
+        diff.remove.spoof = changed(&actual.spoof, &desired.spoof);
+        diff.add.spoof = changed(&desired.spoof, &actual.spoof);
+        diff.remove.merge = changed(&actual.merge, &desired.merge);
+        diff.add.merge = changed(&desired.merge, &actual.merge);
+
+        // There is no ioctl to reset the trusted gid, it is only ever replaced.
+        if desired.trusted_gid.is_some() && desired.trusted_gid != actual.trusted_gid {
+            diff.add.trusted_gid = desired.trusted_gid;
+        }
+
+        diff
+    }
+}
+
+// Entries of `from` that `to` lacks or maps differently.
+fn changed<V: Clone + PartialEq>(
+    from: &BTreeMap<String, V>,
+    to: &BTreeMap<String, V>,
+) -> BTreeMap<String, V> {
+    from.iter()
+        .filter(|(key, value)| to.get(*key) != Some(*value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    pub applied: RuleDiff,
+    pub failed: usize,
+}
+
+/// Brings the PoaceaeFS mounted at `target` to `desired` through `dev`, issuing only the
+/// ioctls that differ from the active rules.
+pub fn sync(target: &Path, dev: &dyn Device, desired: &PoaceaeRules) -> Result<SyncReport> {
+    let key = target.to_string_lossy().to_string();
+    let mut ledger = Ledger::load();
+    let listed = dev.list();
+    let tracked = listed.is_none();
+    let mut rules = listed.unwrap_or_else(|| ledger.mounts.remove(&key).unwrap_or_default());
+
+    let diff = RuleDiff::new(&rules, desired);
+    let mut applied = RuleDiff::default();
+
+    for name in &diff.remove.hide {
+        if took(poaceae::unhide(dev, name), "remove hide", name) {
+            rules.hide.remove(name);
+            applied.remove.hide.insert(name.clone());
+        }
+    }
+    for (src, dst) in &diff.remove.redirect {
+        if took(poaceae::unredirect(dev, src), "remove redirect", src) {
+            rules.redirect.remove(src);
+            applied.remove.redirect.insert(src.clone(), dst.clone());
+        }
+    }
+    for (name, rule) in &diff.remove.spoof {
+        if took(poaceae::unspoof(dev, name), "remove spoof", name) {
+            rules.spoof.remove(name);
+            applied.remove.spoof.insert(name.clone(), *rule);
+        }
+    }
+    for (src, target) in &diff.remove.merge {
+        if took(poaceae::unmerge(dev, src), "remove merge", src) {
+            rules.merge.remove(src);
+            applied.remove.merge.insert(src.clone(), target.clone());
+        }
+    }
+
+    if let Some(gid) = diff.add.trusted_gid
+        && took(poaceae::set_trust(dev, gid), "set trust", &gid.to_string())
+    {
+        rules.trusted_gid = Some(gid);
+        applied.add.trusted_gid = Some(gid);
+    }
+    for name in &diff.add.hide {
+        if took(poaceae::hide(dev, name), "add hide", name) {
+            rules.hide.insert(name.clone());
+            applied.add.hide.insert(name.clone());
+        }
+    }
+    // A changed entry whose removal failed is left as it was.
+    for (src, dst) in &diff.add.redirect {
+        if !rules.redirect.contains_key(src)
+            && took(poaceae::redirect(dev, src, dst), "add redirect", src)
+        {
+            rules.redirect.insert(src.clone(), dst.clone());
+            applied.add.redirect.insert(src.clone(), dst.clone());
+        }
+    }
+    for (name, rule) in &diff.add.spoof {
+        if !rules.spoof.contains_key(name)
+            && took(
+                poaceae::spoof(dev, name, rule.uid, rule.gid, rule.mode, rule.mtime),
+                "add spoof",
+                name,
+            )
+        {
+            rules.spoof.insert(name.clone(), *rule);
+            applied.add.spoof.insert(name.clone(), *rule);
+        }
+    }
+    for (src, target) in &diff.add.merge {
+        if !rules.merge.contains_key(src)
+            && took(poaceae::merge(dev, src, target), "add merge", src)
+        {
+            rules.merge.insert(src.clone(), target.clone());
+            applied.add.merge.insert(src.clone(), target.clone());
+        }
+    }
+
+    let failed = (diff.add.len() + diff.remove.len())
+        .saturating_sub(applied.add.len() + applied.remove.len());
+
+    if tracked {
+        ledger.mounts.insert(key, rules);
+        ledger.save()?;
+    }
+
+    Ok(SyncReport { applied, failed })
+}
+
+fn took(result: Result<()>, action: &str, name: &str) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("Failed to {} PoaceaeFS rule for {}: {:#}", action, name, e);
+            false
+        }
+    }
+}
+
+/// Applies `rules` through the PoaceaeFS mounted at [`defs::POACEAE_MOUNT_POINT`].
+///
+/// Failing rules are logged and skipped, the rules active afterwards are returned.
+pub fn apply(rules: &PoaceaeRules) -> PoaceaeRules {
+    let target = Path::new(defs::POACEAE_MOUNT_POINT);
+    let dev = match poaceae::open(target) {
+        Ok(dev) => dev,
+        Err(e) => {
+            if !rules.is_empty() {
+                log::warn!(
+                    "PoaceaeFS is not available, skipping {} rules: {:#}",
+                    rules.len(),
+                    e
+                );
+            }
+            return active(target, None);
+        }
+    };
+
+    if rules.is_empty() {
+        return active(target, Some(&*dev));
+    }
+
+    match sync(target, &*dev, rules) {
+        Ok(report) => {
+            log::info!(
+                ">> PoaceaeFS: {} rules added, {} removed, {} failed.",
+                report.applied.add.len(),
+                report.applied.remove.len(),
+                report.failed
+            );
+        }
+        Err(e) => log::warn!("Failed to sync {} PoaceaeFS rules: {:#}", rules.len(), e),
+    }
+
+    active(target, Some(&*dev))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{conf::config::SpoofRule, sys::poaceae::FakePoaceae};
+
+    struct State(std::path::PathBuf);
+
+    impl State {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "poaceae-sync-{}-{}.json",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for State {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn rules(hide: &[&str], redirect: &[(&str, &str)]) -> PoaceaeRules {
+        PoaceaeRules {
+            hide: hide.iter().map(|s| s.to_string()).collect(),
+            redirect: redirect
+                .iter()
+                .map(|(src, dst)| (src.to_string(), dst.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn diff_replaces_changed_entries() {
+        let actual = rules(&["/a", "/b"], &[("/r", "/old"), ("/s", "/same")]);
+        let desired = rules(&["/b", "/c"], &[("/r", "/new"), ("/s", "/same")]);
+
+        let diff = RuleDiff::new(&actual, &desired);
+        assert_eq!(diff.remove, rules(&["/a"], &[("/r", "/old")]));
+        assert_eq!(diff.add, rules(&["/c"], &[("/r", "/new")]));
+    }
+
+    #[test]
+    fn diff_only_ever_sets_the_trusted_gid() {
+        let with_gid = PoaceaeRules {
+            trusted_gid: Some(3005),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            RuleDiff::new(&with_gid, &PoaceaeRules::default())
+                .add
+                .trusted_gid,
+            None
+        );
+        assert_eq!(RuleDiff::new(&with_gid, &with_gid).add.trusted_gid, None);
+        assert_eq!(
+            RuleDiff::new(&PoaceaeRules::default(), &with_gid)
+                .add
+                .trusted_gid,
+            Some(3005)
+        );
+    }
+
+    #[test]
+    fn sync_brings_the_filesystem_to_the_desired_rules() {
+        let state = State::new("converge");
+        let dev = FakePoaceae::open(&state.0).unwrap();
+        poaceae::hide(&dev, "/system/xbin/su").unwrap();
+        poaceae::redirect(&dev, "/system/etc/hosts", "/data/old_hosts").unwrap();
+        poaceae::spoof(&dev, "/system/bin/app", 0, 0, 0o644, 0).unwrap();
+
+        let mut desired = rules(
+            &["/system/bin/magisk"],
+            &[("/system/etc/hosts", "/data/hosts")],
+        );
+        desired
+            .merge
+            .insert("/system/fonts".into(), "/data/fonts".into());
+        desired.spoof.insert(
+            "/system/bin/app".into(),
+            SpoofRule {
+                uid: 0,
+                gid: 2000,
+                mode: 0o755,
+                mtime: 0,
+            },
+        );
+        desired.trusted_gid = Some(3005);
+
+        let report = sync(&state.0, &dev, &desired).unwrap();
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.applied.remove.hide.len(), 1);
+        assert_eq!(report.applied.remove.redirect.len(), 1);
+        assert_eq!(report.applied.remove.spoof.len(), 1);
+        assert_eq!(dev.list().unwrap(), desired);
+        assert_eq!(active(&state.0, Some(&dev)), desired);
+
+        // Reopening reads the same state, so a second sync has nothing to do.
+        let dev = FakePoaceae::open(&state.0).unwrap();
+        let report = sync(&state.0, &dev, &desired).unwrap();
+        assert_eq!(report.failed, 0);
+        assert!(report.applied.add.is_empty() && report.applied.remove.is_empty());
+    }
+
+    #[test]
+    fn sync_reports_rules_the_filesystem_refuses() {
+        let state = State::new("refused");
+        let dev = FakePoaceae::open(&state.0).unwrap();
+
+        let desired = rules(&["/system/xbin/su", ""], &[("/system/a|b", "/data/x")]);
+        let report = sync(&state.0, &dev, &desired).unwrap();
+
+        assert_eq!(report.failed, 2);
+        assert_eq!(dev.list().unwrap(), rules(&["/system/xbin/su"], &[]));
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{defs, utils};
+
+const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+// Each synced file is hashed at the source, copied, then hashed again to verify it.
+const PASSES_PER_BYTE: u64 = 3;
+
+#[derive(Debug, Serialize)]
+struct ProgressSnapshot<'a> {
+    total_bytes: u64,
+    copied_bytes: u64,
+    hashed_bytes: u64,
+    modules_total: usize,
+    modules_done: usize,
+    current: &'a [String],
+    elapsed_secs: u64,
+    eta_secs: Option<u64>,
+    finished: bool,
+}
+
+/// Sync progress, written to [`defs::SYNC_PROGRESS_FILE`] for the WebUI to poll.
+pub struct SyncProgress {
+    total_bytes: u64,
+    modules_total: usize,
+    copied_baseline: u64,
+    hashed_baseline: u64,
+    started: Instant,
+    modules_done: AtomicUsize,
+    current: Mutex<Vec<String>>,
+    stopped: AtomicBool,
+}
+
+impl SyncProgress {
+    pub fn new(total_bytes: u64, modules_total: usize) -> Self {
+        Self {
+            total_bytes,
+            modules_total,
+            copied_baseline: utils::copied_bytes(),
+            hashed_baseline: utils::hashed_bytes(),
+            started: Instant::now(),
+            modules_done: AtomicUsize::new(0),
+            current: Mutex::new(Vec::new()),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn begin(&self, module_id: &str) {
+        if let Ok(mut current) = self.current.lock() {
+            current.push(module_id.to_string());
+        }
+    }
+
+    pub fn end(&self, module_id: &str) {
+        if let Ok(mut current) = self.current.lock() {
+            current.retain(|id| id != module_id);
+        }
+        self.modules_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Rewrites the progress file until [`SyncProgress::stop`] is called.
+    pub fn report_until_stopped(&self) {
+        while !self.stopped.load(Ordering::Relaxed) {
+            self.write(false);
+            thread::sleep(REPORT_INTERVAL);
+        }
+        self.write(true);
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    fn write(&self, finished: bool) {
+        let copied = utils::copied_bytes().saturating_sub(self.copied_baseline);
+        let hashed = utils::hashed_bytes().saturating_sub(self.hashed_baseline);
+        let elapsed = self.started.elapsed();
+
+        let done = copied + hashed;
+        let eta_secs = (done > 0 && !finished).then(|| {
+            let remaining = (self.total_bytes * PASSES_PER_BYTE).saturating_sub(done);
+            (elapsed.as_secs_f64() * remaining as f64 / done as f64) as u64
+        });
+
+        let current = self.current.lock().map(|c| c.clone()).unwrap_or_default();
+        let snapshot = ProgressSnapshot {
+            total_bytes: self.total_bytes,
+            copied_bytes: copied.min(self.total_bytes),
+            hashed_bytes: hashed,
+            modules_total: self.modules_total,
+            modules_done: self.modules_done.load(Ordering::Relaxed),
+            current: &current,
+            elapsed_secs: elapsed.as_secs(),
+            eta_secs,
+            finished,
+        };
+
+        if let Ok(json) = serde_json::to_string(&snapshot)
+            && let Err(e) = utils::atomic_write(defs::SYNC_PROGRESS_FILE, json)
+        {
+            log::debug!("Failed to write sync progress: {:#}", e);
+        }
+    }
+}
@@ -1,149 +1,371 @@
-use std::{collections::HashSet, fs, path::Path};
+use std::{cmp::Reverse, collections::HashSet, fs, path::Path, thread};
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use rayon::prelude::*;
 use walkdir::WalkDir;
 
-use crate::{core::inventory::Module, defs, utils};
-
-pub fn perform_sync(modules: &[Module], target_base: &Path) -> Result<()> {
+use super::{
+    exclude::ExcludeSet,
+    gc,
+    journal::{self, Journal, SwapPhase},
+    manifest::{EntryKind, SyncDiff, SyncManifest},
+    progress::SyncProgress,
+};
+use crate::{conf::config::SyncConfig, core::inventory::Module, defs, utils};
+
+/// Copies module content into storage, relabelling entries after the stock files under
+/// `repair_root` when it is set.
+///
+/// Modules are diffed first, then copied at most `io_concurrency` at a time, largest first.
+pub fn perform_sync(
+    modules: &[Module],
+    target_base: &Path,
+    repair_root: Option<&Path>,
+    options: &SyncConfig,
+) -> Result<()> {
     log::info!("Starting smart module sync to {}", target_base.display());
 
-    prune_orphaned_modules(modules, target_base)?;
+    let journal = Journal::open(target_base);
+    journal.recover(target_base);
+
+    let active_ids: HashSet<&str> = modules.iter().map(|m| m.id.as_str()).collect();
+    gc::collect(target_base, &active_ids, false)?;
+
+    utils::set_copy_chunk_size(options.chunk_size_mb.saturating_mul(1024 * 1024));
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.io_concurrency.max(1))
+        .build()
+        .context("Failed to create sync thread pool")?;
+
+    let mut jobs: Vec<SyncJob> = pool.install(|| {
+        modules
+            .par_iter()
+            .filter_map(|module| match plan_module(module, target_base) {
+                Ok(job) => job,
+                Err(e) => {
+                    log::error!("Failed to sync module {}: {:#}", module.id, e);
+                    None
+                }
+            })
+            .collect()
+    });
+
+    if jobs.is_empty() {
+        return Ok(());
+    }
 
-    modules.par_iter().for_each(|module| {
-        let dst = target_base.join(&module.id);
-        let dst_backup = target_base.join(format!(".backup_{}", module.id));
+    jobs.sort_by_key(|job| Reverse(job.bytes));
+    let progress = SyncProgress::new(jobs.iter().map(|job| job.bytes).sum(), jobs.len());
 
-        let has_content = defs::BUILTIN_PARTITIONS.iter().any(|p| {
-            let part_path = module.source_path.join(p);
+    thread::scope(|scope| {
+        scope.spawn(|| progress.report_until_stopped());
 
-            part_path.exists() && has_files_recursive(&part_path)
+        // par_bridge hands jobs out in order, so the largest ones start first.
+        pool.install(|| {
+            jobs.into_iter().par_bridge().for_each(|job| {
+                let module = job.module;
+                progress.begin(&module.id);
+                if let Err(e) = run_job(job, target_base, repair_root, &journal) {
+                    log::error!("Failed to sync module {}: {:#}", module.id, e);
+                }
+                progress.end(&module.id);
+            })
         });
 
-        if has_content && should_sync(&module.source_path, &dst) {
-            log::info!("Syncing module: {} (Updated/New)", module.id);
+        progress.stop();
+    });
 
-            let tmp_dst = target_base.join(format!(".tmp_{}", module.id));
+    Ok(())
+}
 
-            if tmp_dst.exists() {
-                let _ = fs::remove_dir_all(&tmp_dst);
-            }
+struct SyncJob<'a> {
+    module: &'a Module,
+    excludes: ExcludeSet,
+    manifest: SyncManifest,
+    /// Absent when there is no usable previous copy to update.
+    diff: Option<SyncDiff>,
+    bytes: u64,
+}
 
-            if let Err(e) = utils::sync_dir(&module.source_path, &tmp_dst, true) {
-                log::error!("Failed to sync module {}: {}", module.id, e);
-                let _ = fs::remove_dir_all(&tmp_dst);
-                return;
-            }
+fn plan_module<'a>(module: &'a Module, target_base: &Path) -> Result<Option<SyncJob<'a>>> {
+    let has_content = defs::BUILTIN_PARTITIONS.iter().any(|p| {
+        let part_path = module.source_path.join(p);
 
-            if let Err(e) = utils::prune_empty_dirs(&tmp_dst) {
-                log::warn!("Failed to prune empty dirs for {}: {}", module.id, e);
-            }
+        part_path.exists() && has_files_recursive(&part_path)
+    });
 
-            if let Err(e) = apply_overlay_opaque_flags(&tmp_dst) {
-                log::warn!(
-                    "Failed to apply overlay opaque xattrs for {}: {}",
-                    module.id,
-                    e
-                );
-            }
+    if !has_content {
+        log::debug!("Skipping module: {}", module.id);
+        return Ok(None);
+    }
 
-            let mut backup_created = false;
-            if dst.exists() {
-                if let Err(e) = fs::rename(&dst, &dst_backup) {
-                    log::error!("Failed to backup existing module {}: {}", module.id, e);
-                    let _ = fs::remove_dir_all(&tmp_dst);
-                    return;
-                }
-                backup_created = true;
+    let dst = target_base.join(&module.id);
+    let excludes = ExcludeSet::for_module(module);
+    let mut manifest = SyncManifest::scan(&module.source_path, &excludes)?;
+    let previous = if dst.exists() {
+        SyncManifest::load(&dst)
+    } else {
+        None
+    };
+
+    let diff = match &previous {
+        Some(previous) => {
+            let diff = manifest.diff(previous, &module.source_path, &dst);
+            if diff.is_empty() {
+                log::debug!("Skipping module: {} (Unchanged)", module.id);
+                return Ok(None);
             }
+            Some(diff)
+        }
+        None => None,
+    };
+
+    let bytes = manifest
+        .entries
+        .iter()
+        .filter(|(path, entry)| {
+            entry.kind == EntryKind::File
+                && diff
+                    .as_ref()
+                    .is_none_or(|diff| diff.changed.contains(*path))
+        })
+        .map(|(_, entry)| entry.size)
+        .sum();
+
+    Ok(Some(SyncJob {
+        module,
+        excludes,
+        manifest,
+        diff,
+        bytes,
+    }))
+}
 
-            if let Err(e) = fs::rename(&tmp_dst, &dst) {
-                log::error!("Failed to commit atomic sync for {}: {}", module.id, e);
-                if backup_created {
-                    let _ = fs::rename(&dst_backup, &dst);
-                }
-                let _ = fs::remove_dir_all(&tmp_dst);
-                return;
-            }
+fn run_job(
+    job: SyncJob,
+    target_base: &Path,
+    repair_root: Option<&Path>,
+    journal: &Journal,
+) -> Result<()> {
+    let SyncJob {
+        module,
+        excludes,
+        mut manifest,
+        diff,
+        ..
+    } = job;
+
+    let dst = target_base.join(&module.id);
+    let dst_backup = journal::backup_path(target_base, &module.id);
+    let tmp_dst = journal::staging_path(target_base, &module.id);
+
+    if tmp_dst.exists() {
+        let _ = fs::remove_dir_all(&tmp_dst);
+    }
 
-            if backup_created && let Err(e) = fs::remove_dir_all(&dst_backup) {
-                log::warn!("Failed to clean up backup for {}: {}", module.id, e);
-            }
-        } else {
-            log::debug!("Skipping module: {}", module.id);
+    manifest.hash_files(&module.source_path)?;
+    journal.record(&module.id, SwapPhase::Staging)?;
+
+    let staged = match &diff {
+        Some(diff) => {
+            log::info!(
+                "Syncing module: {} ({} changed, {} removed)",
+                module.id,
+                diff.changed.len(),
+                diff.removed.len()
+            );
+            stage_incremental(&module.source_path, &dst, &tmp_dst, diff, repair_root)
         }
-    });
+        None => {
+            log::info!("Syncing module: {} (Updated/New)", module.id);
+            utils::sync_dir_filtered(&module.source_path, &tmp_dst, repair_root, &|relative| {
+                excludes.is_excluded(relative)
+            })
+        }
+    };
 
-    Ok(())
-}
+    if let Err(e) = staged {
+        discard_staging(&module.id, &tmp_dst, journal);
+        return Err(e);
+    }
 
-fn apply_overlay_opaque_flags(root: &Path) -> Result<()> {
-    for entry in WalkDir::new(root).min_depth(1).into_iter().flatten() {
-        if entry.file_type().is_file()
-            && entry.file_name() == defs::REPLACE_DIR_FILE_NAME
-            && let Some(parent) = entry.path().parent()
-        {
-            utils::set_overlay_opaque(parent)?;
-            log::debug!("Set overlay opaque xattr on: {}", parent.display());
+    // Linked entries were checked when first written, only fresh copies need reading back.
+    let drift = manifest.verify(&tmp_dst, diff.as_ref().map(|diff| &diff.changed));
+    if !drift.is_empty() {
+        discard_staging(&module.id, &tmp_dst, journal);
+        for entry in &drift {
+            log::debug!("  {} ({:?})", entry.path, entry.kind);
         }
+        bail!(
+            "{} files failed verification after copy, keeping the previous copy",
+            drift.len()
+        );
     }
-    Ok(())
-}
 
-fn prune_orphaned_modules(modules: &[Module], target_base: &Path) -> Result<()> {
-    if !target_base.exists() {
-        return Ok(());
+    if let Err(e) = utils::prune_empty_dirs(&tmp_dst) {
+        log::warn!("Failed to prune empty dirs for {}: {}", module.id, e);
     }
 
-    let active_ids: HashSet<&str> = modules.iter().map(|m| m.id.as_str()).collect();
+    if let Err(e) = apply_overlay_opaque_flags(&tmp_dst) {
+        log::warn!(
+            "Failed to apply overlay opaque xattrs for {}: {}",
+            module.id,
+            e
+        );
+    }
 
-    let entries: Vec<_> = fs::read_dir(target_base)?.filter_map(|e| e.ok()).collect();
+    match utils::verify_metadata(&module.source_path, &tmp_dst) {
+        Ok(mismatches) if !mismatches.is_empty() => {
+            log::warn!(
+                "Metadata of {} entries in {} differs from the source",
+                mismatches.len(),
+                module.id
+            );
+            for mismatch in mismatches {
+                log::debug!("  {}", mismatch);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Failed to verify metadata for {}: {:#}", module.id, e),
+    }
 
-    entries.par_iter().for_each(|entry| {
-        let path = entry.path();
+    if let Err(e) = manifest.save(&tmp_dst) {
+        log::warn!("Failed to save sync manifest for {}: {:#}", module.id, e);
+    }
 
-        let name_os = entry.file_name();
+    journal.record(&module.id, SwapPhase::Swapping)?;
 
-        let name = name_os.to_string_lossy();
+    let mut backup_created = false;
+    if dst.exists() {
+        if let Err(e) = fs::rename(&dst, &dst_backup) {
+            discard_staging(&module.id, &tmp_dst, journal);
+            return Err(e).context("Failed to backup existing module");
+        }
+        backup_created = true;
+    }
 
-        if name != "lost+found"
-            && name != "meta-hybrid"
-            && !name.starts_with('.')
-            && !active_ids.contains(name.as_ref())
-        {
-            log::info!("Pruning orphaned module storage: {}", name);
+    if let Err(e) = fs::rename(&tmp_dst, &dst) {
+        if backup_created {
+            let _ = fs::rename(&dst_backup, &dst);
+        }
+        discard_staging(&module.id, &tmp_dst, journal);
+        return Err(e).context("Failed to commit atomic sync");
+    }
 
-            if path.is_dir() {
-                if let Err(e) = fs::remove_dir_all(&path) {
-                    log::warn!("Failed to remove orphan dir {}: {}", name, e);
-                }
-            } else if let Err(e) = fs::remove_file(&path) {
-                log::warn!("Failed to remove orphan file {}: {}", name, e);
+    if backup_created && let Err(e) = fs::remove_dir_all(&dst_backup) {
+        log::warn!("Failed to clean up backup for {}: {}", module.id, e);
+    }
+
+    journal.clear(&module.id)
+}
+
+fn discard_staging(module_id: &str, tmp_dst: &Path, journal: &Journal) {
+    let _ = fs::remove_dir_all(tmp_dst);
+    if let Err(e) = journal.clear(module_id) {
+        log::warn!("{:#}", e);
+    }
+}
+
+/// Builds the next copy of a module in `tmp_dst` without touching `dst`.
+///
+/// Unchanged entries are hard links into `dst`, changed and retimed ones are fresh copies,
+/// so nothing visible through `dst` is modified before the swap.
+fn stage_incremental(
+    src: &Path,
+    dst: &Path,
+    tmp_dst: &Path,
+    diff: &SyncDiff,
+    repair_root: Option<&Path>,
+) -> Result<()> {
+    utils::copy_dir_attributes(dst, tmp_dst)?;
+
+    let mut walker = WalkDir::new(dst).min_depth(1).into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(dst)?;
+        let key = relative.to_string_lossy();
+        let is_dir = entry.file_type().is_dir();
+
+        if diff.removed.contains(key.as_ref()) || (!is_dir && diff.changed.contains(key.as_ref())) {
+            if is_dir {
+                walker.skip_current_dir();
             }
+            continue;
         }
-    });
 
-    Ok(())
-}
+        let target = tmp_dst.join(relative);
+        if is_dir {
+            utils::copy_dir_attributes(entry.path(), &target)?;
+        } else if relative.as_os_str() == defs::SYNC_MANIFEST_FILE_NAME {
+            continue;
+        } else if diff.retimed.contains(key.as_ref())
+            || fs::hard_link(entry.path(), &target).is_err()
+        {
+            // A linked inode is shared with `dst` and the store, retiming it would leak out.
+            utils::copy_entry(entry.path(), &target, &entry.path().symlink_metadata()?)?;
+        }
+    }
 
-fn should_sync(src: &Path, dst: &Path) -> bool {
-    if !dst.exists() {
-        return true;
+    for path in &diff.retimed {
+        if let Ok(metadata) = src.join(path).symlink_metadata() {
+            let _ = utils::copy_timestamps(&tmp_dst.join(path), &metadata);
+        }
     }
 
-    let src_prop = src.join("module.prop");
+    let mut links = utils::LinkMap::default();
+    for path in &diff.changed {
+        let src_path = src.join(path);
+        let target = tmp_dst.join(path);
+        let metadata = src_path
+            .symlink_metadata()
+            .with_context(|| format!("Failed to stat {}", src_path.display()))?;
 
-    let dst_prop = dst.join("module.prop");
+        if let Some(parent) = Path::new(path).parent() {
+            restore_parents(src, tmp_dst, parent)?;
+        }
 
-    if !src_prop.exists() || !dst_prop.exists() {
-        return true;
+        if metadata.is_dir() {
+            utils::copy_dir_attributes(&src_path, &target)?;
+        } else {
+            links.copy_entry(&src_path, &target, &metadata)?;
+        }
+
+        if let Some(root) = repair_root {
+            utils::repair_context(&target, root, Path::new(path));
+        }
     }
 
-    match (fs::read(&src_prop), fs::read(&dst_prop)) {
-        (Ok(s), Ok(d)) => s != d,
-        _ => true,
+    Ok(())
+}
+
+// Parents pruned from the previous copy while empty come back with source attributes.
+fn restore_parents(src: &Path, tmp_dst: &Path, relative: &Path) -> Result<()> {
+    let ancestors: Vec<&Path> = relative
+        .ancestors()
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .collect();
+
+    for ancestor in ancestors.into_iter().rev() {
+        let target = tmp_dst.join(ancestor);
+        if !target.exists() {
+            utils::copy_dir_attributes(&src.join(ancestor), &target)?;
+        }
     }
+
+    Ok(())
+}
+
+fn apply_overlay_opaque_flags(root: &Path) -> Result<()> {
+    for entry in WalkDir::new(root).min_depth(1).into_iter().flatten() {
+        if entry.file_type().is_file()
+            && entry.file_name() == defs::REPLACE_DIR_FILE_NAME
+            && let Some(parent) = entry.path().parent()
+        {
+            utils::set_overlay_opaque(parent)?;
+            log::debug!("Set overlay opaque xattr on: {}", parent.display());
+        }
+    }
+    Ok(())
 }
 
 fn has_files_recursive(path: &Path) -> bool {
@@ -0,0 +1,713 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, Metadata},
+    io::{Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use extattr::{lgetxattr, llistxattr};
+use flate2::{Compression, write::ZlibEncoder};
+use rayon::prelude::*;
+
+use crate::defs;
+
+const SUPER_MAGIC: u32 = 0x7371_7368;
+const SUPER_SIZE: u64 = 96;
+const BLOCK_LOG: u16 = 17;
+const BLOCK_SIZE: usize = 1 << BLOCK_LOG;
+const COMPRESSION_ZLIB: u16 = 1;
+const FLAG_NOI: u16 = 0x0001;
+const FLAG_NO_FRAGMENTS: u16 = 0x0010;
+const FLAG_NO_XATTRS: u16 = 0x0200;
+
+const METADATA_SIZE: usize = 8192;
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+const INVALID: u64 = u64::MAX;
+const NO_XATTR: u32 = u32::MAX;
+const NO_FRAGMENT: u32 = u32::MAX;
+// Data blocks compressed in parallel per batch.
+const BATCH_BLOCKS: usize = 16;
+
+const DIR_TYPE: u16 = 1;
+const REG_TYPE: u16 = 2;
+const SYMLINK_TYPE: u16 = 3;
+const BLKDEV_TYPE: u16 = 4;
+const CHRDEV_TYPE: u16 = 5;
+const FIFO_TYPE: u16 = 6;
+const SOCKET_TYPE: u16 = 7;
+// Extended inode types are the basic ones plus this offset.
+const EXTENDED_OFFSET: u16 = 7;
+
+const DIR_MAX_ENTRIES: usize = 256;
+const DIR_EMPTY_SIZE: usize = 3;
+
+const XATTR_TRUSTED: u16 = 1;
+const XATTR_SECURITY: u16 = 2;
+
+type Xattrs = Vec<(u16, Vec<u8>, Vec<u8>)>;
+
+enum Kind {
+    Dir(Vec<(OsString, usize)>),
+    File,
+    Symlink(Vec<u8>),
+    Special(u16),
+}
+
+struct Inode {
+    path: PathBuf,
+    parent: usize,
+    kind: Kind,
+    mode: u16,
+    uid: u16,
+    gid: u16,
+    mtime: u32,
+    rdev: u64,
+    size: u64,
+    nlink: u32,
+    xattr: u32,
+    start_block: u64,
+    blocks: Vec<u32>,
+    pos: usize,
+    dir_ref: (u32, u16),
+    dir_size: usize,
+}
+
+struct Builder {
+    image: File,
+    root: PathBuf,
+    overlay_root: Option<PathBuf>,
+    offset: u64,
+    inodes: Vec<Inode>,
+    hardlinks: HashMap<(u64, u64), usize>,
+    ids: Vec<u32>,
+    id_index: HashMap<u32, u16>,
+    xattr_sets: Vec<Xattrs>,
+    xattr_index: HashMap<Xattrs, u32>,
+}
+
+/// Packs `src_dir` into a zlib compressed squashfs image.
+///
+/// `overlay_root` mirrors `src_dir` and supplies trusted.overlay xattrs the staging
+/// filesystem could not hold.
+pub fn build_image(src_dir: &Path, image_path: &Path, overlay_root: Option<&Path>) -> Result<()> {
+    let image = File::create(image_path)
+        .with_context(|| format!("Failed to create {}", image_path.display()))?;
+
+    let mut builder = Builder {
+        image,
+        root: src_dir.to_path_buf(),
+        overlay_root: overlay_root.map(Path::to_path_buf),
+        offset: SUPER_SIZE,
+        inodes: Vec::new(),
+        hardlinks: HashMap::new(),
+        ids: Vec::new(),
+        id_index: HashMap::new(),
+        xattr_sets: Vec::new(),
+        xattr_index: HashMap::new(),
+    };
+
+    builder.scan(src_dir)?;
+
+    for idx in 0..builder.inodes.len() {
+        if matches!(builder.inodes[idx].kind, Kind::File) {
+            builder.write_data(idx).with_context(|| {
+                format!("Failed to pack {}", builder.inodes[idx].path.display())
+            })?;
+        }
+    }
+
+    // Inodes stay uncompressed so every reference is known before the tables are encoded.
+    let mut pos = 0;
+    for inode in &mut builder.inodes {
+        inode.pos = pos;
+        pos += inode.record_size();
+    }
+
+    let directory_table = builder.build_directory_table()?;
+
+    let mut inode_table = Vec::with_capacity(pos);
+    for idx in 0..builder.inodes.len() {
+        inode_table.extend_from_slice(&builder.encode_inode(idx));
+    }
+
+    let inode_table_start = builder.write_metadata(&inode_table, false)?.0;
+    let directory_table_start = builder.write_bytes(&directory_table)?;
+
+    let id_table_start = builder.write_id_table()?;
+    let xattr_id_table_start = builder.write_xattr_tables()?;
+
+    let bytes_used = builder.offset;
+    builder.image.set_len(bytes_used.next_multiple_of(4096))?;
+
+    let mut flags = FLAG_NOI | FLAG_NO_FRAGMENTS;
+    if xattr_id_table_start == INVALID {
+        flags |= FLAG_NO_XATTRS;
+    }
+
+    let mkfs_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or_default();
+
+    let mut sb = Vec::with_capacity(SUPER_SIZE as usize);
+    sb.extend_from_slice(&SUPER_MAGIC.to_le_bytes());
+    sb.extend_from_slice(&(builder.inodes.len() as u32).to_le_bytes());
+    sb.extend_from_slice(&mkfs_time.to_le_bytes());
+    sb.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    sb.extend_from_slice(&0u32.to_le_bytes());
+    sb.extend_from_slice(&COMPRESSION_ZLIB.to_le_bytes());
+    sb.extend_from_slice(&BLOCK_LOG.to_le_bytes());
+    sb.extend_from_slice(&flags.to_le_bytes());
+    sb.extend_from_slice(&(builder.ids.len() as u16).to_le_bytes());
+    sb.extend_from_slice(&4u16.to_le_bytes());
+    sb.extend_from_slice(&0u16.to_le_bytes());
+    sb.extend_from_slice(&inode_ref(builder.inodes[0].pos).to_le_bytes());
+    sb.extend_from_slice(&bytes_used.to_le_bytes());
+    sb.extend_from_slice(&id_table_start.to_le_bytes());
+    sb.extend_from_slice(&xattr_id_table_start.to_le_bytes());
+    sb.extend_from_slice(&inode_table_start.to_le_bytes());
+    sb.extend_from_slice(&directory_table_start.to_le_bytes());
+    sb.extend_from_slice(&INVALID.to_le_bytes());
+    sb.extend_from_slice(&INVALID.to_le_bytes());
+
+    builder.image.write_all_at(&sb, 0)?;
+    builder.image.sync_all()?;
+
+    log::info!(
+        "Packed {} inodes into {} bytes ({})",
+        builder.inodes.len(),
+        bytes_used,
+        image_path.display()
+    );
+
+    Ok(())
+}
+
+impl Builder {
+    fn scan(&mut self, root: &Path) -> Result<()> {
+        let metadata = root
+            .symlink_metadata()
+            .with_context(|| format!("Failed to stat {}", root.display()))?;
+        ensure!(metadata.is_dir(), "{} is not a directory", root.display());
+
+        self.add_inode(root, &metadata, 0)?;
+        self.scan_dir(0)
+    }
+
+    fn scan_dir(&mut self, idx: usize) -> Result<()> {
+        let dir = self.inodes[idx].path.clone();
+
+        let mut names: Vec<OsString> = fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<Result<_, _>>()?;
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+        let mut children = Vec::with_capacity(names.len());
+        let mut subdirs = 0;
+
+        for name in names {
+            let path = dir.join(&name);
+            let metadata = path.symlink_metadata()?;
+
+            let child = if !metadata.is_dir()
+                && metadata.nlink() > 1
+                && let Some(&existing) = self.hardlinks.get(&(metadata.dev(), metadata.ino()))
+            {
+                self.inodes[existing].nlink += 1;
+                existing
+            } else {
+                let child = self.add_inode(&path, &metadata, idx)?;
+                if metadata.is_dir() {
+                    subdirs += 1;
+                    self.scan_dir(child)?;
+                } else if metadata.nlink() > 1 {
+                    self.hardlinks
+                        .insert((metadata.dev(), metadata.ino()), child);
+                }
+                child
+            };
+
+            children.push((name, child));
+        }
+
+        self.inodes[idx].nlink = 2 + subdirs;
+        self.inodes[idx].kind = Kind::Dir(children);
+        Ok(())
+    }
+
+    fn add_inode(&mut self, path: &Path, metadata: &Metadata, parent: usize) -> Result<usize> {
+        let ft = metadata.file_type();
+        let kind = if ft.is_dir() {
+            Kind::Dir(Vec::new())
+        } else if ft.is_file() {
+            Kind::File
+        } else if ft.is_symlink() {
+            Kind::Symlink(fs::read_link(path)?.as_os_str().as_bytes().to_vec())
+        } else if ft.is_char_device() {
+            Kind::Special(CHRDEV_TYPE)
+        } else if ft.is_block_device() {
+            Kind::Special(BLKDEV_TYPE)
+        } else if ft.is_fifo() {
+            Kind::Special(FIFO_TYPE)
+        } else if ft.is_socket() {
+            Kind::Special(SOCKET_TYPE)
+        } else {
+            bail!("Unsupported file type: {}", path.display());
+        };
+
+        let size = match &kind {
+            Kind::File => metadata.len(),
+            Kind::Symlink(target) => target.len() as u64,
+            _ => 0,
+        };
+
+        let uid = self.id(metadata.uid())?;
+        let gid = self.id(metadata.gid())?;
+        let xattrs = self.collect_xattrs(path, ft.is_dir());
+        let xattr = self.xattr_id(xattrs);
+
+        self.inodes.push(Inode {
+            path: path.to_path_buf(),
+            parent,
+            kind,
+            mode: (metadata.mode() & 0o7777) as u16,
+            uid,
+            gid,
+            mtime: metadata.mtime().clamp(0, u32::MAX as i64) as u32,
+            rdev: metadata.rdev(),
+            size,
+            nlink: 1,
+            xattr,
+            start_block: 0,
+            blocks: Vec::new(),
+            pos: 0,
+            dir_ref: (0, 0),
+            dir_size: 0,
+        });
+
+        Ok(self.inodes.len() - 1)
+    }
+
+    fn id(&mut self, id: u32) -> Result<u16> {
+        if let Some(&index) = self.id_index.get(&id) {
+            return Ok(index);
+        }
+
+        ensure!(
+            self.ids.len() < u16::MAX as usize,
+            "Too many distinct uid/gid values"
+        );
+        let index = self.ids.len() as u16;
+        self.ids.push(id);
+        self.id_index.insert(id, index);
+        Ok(index)
+    }
+
+    fn collect_xattrs(&self, path: &Path, is_dir: bool) -> Xattrs {
+        let mut xattrs = read_xattrs(path);
+
+        let has_overlay = xattrs.iter().any(|(prefix, ..)| *prefix == XATTR_TRUSTED);
+        if !has_overlay
+            && let Some(overlay_root) = &self.overlay_root
+            && let Ok(relative) = path.strip_prefix(&self.root)
+        {
+            xattrs.extend(
+                read_xattrs(&overlay_root.join(relative))
+                    .into_iter()
+                    .filter(|(prefix, ..)| *prefix == XATTR_TRUSTED),
+            );
+        }
+
+        let opaque = &defs::REPLACE_DIR_XATTR.as_bytes()[b"trusted.".len()..];
+        if is_dir
+            && !xattrs
+                .iter()
+                .any(|(prefix, name, _)| *prefix == XATTR_TRUSTED && name == opaque)
+            && path.join(defs::REPLACE_DIR_FILE_NAME).is_file()
+        {
+            xattrs.push((XATTR_TRUSTED, opaque.to_vec(), b"y".to_vec()));
+        }
+
+        xattrs.sort();
+        xattrs
+    }
+
+    fn xattr_id(&mut self, xattrs: Xattrs) -> u32 {
+        if xattrs.is_empty() {
+            return NO_XATTR;
+        }
+
+        if let Some(&id) = self.xattr_index.get(&xattrs) {
+            return id;
+        }
+
+        let id = self.xattr_sets.len() as u32;
+        self.xattr_sets.push(xattrs.clone());
+        self.xattr_index.insert(xattrs, id);
+        id
+    }
+
+    fn write_data(&mut self, idx: usize) -> Result<()> {
+        let path = self.inodes[idx].path.clone();
+        let size = self.inodes[idx].size;
+
+        let mut file = File::open(&path)?;
+        let mut blocks = Vec::with_capacity(size.div_ceil(BLOCK_SIZE as u64) as usize);
+        let mut read_total = 0u64;
+        let start_block = self.offset;
+
+        loop {
+            let mut batch = Vec::with_capacity(BLOCK_SIZE * BATCH_BLOCKS);
+            (&mut file)
+                .take((BLOCK_SIZE * BATCH_BLOCKS) as u64)
+                .read_to_end(&mut batch)?;
+            if batch.is_empty() {
+                break;
+            }
+            read_total += batch.len() as u64;
+
+            let packed: Vec<(Option<Vec<u8>>, &[u8])> = batch
+                .par_chunks(BLOCK_SIZE)
+                .map(|chunk| Ok((compress(chunk)?, chunk)))
+                .collect::<Result<_>>()?;
+
+            for (compressed, raw) in packed {
+                let (bytes, header) = match &compressed {
+                    Some(bytes) => (bytes.as_slice(), bytes.len() as u32),
+                    None => (raw, raw.len() as u32 | DATA_UNCOMPRESSED),
+                };
+                self.image.write_all_at(bytes, self.offset)?;
+                self.offset += bytes.len() as u64;
+                blocks.push(header);
+            }
+        }
+
+        ensure!(
+            read_total == size,
+            "{} changed while packing",
+            path.display()
+        );
+
+        let inode = &mut self.inodes[idx];
+        inode.start_block = start_block;
+        inode.blocks = blocks;
+        Ok(())
+    }
+
+    fn build_directory_table(&mut self) -> Result<Vec<u8>> {
+        let mut table = Vec::new();
+        let mut listings = Vec::new();
+
+        for (idx, inode) in self.inodes.iter().enumerate() {
+            let Kind::Dir(children) = &inode.kind else {
+                continue;
+            };
+
+            let start = table.len();
+            let mut i = 0;
+
+            while i < children.len() {
+                let base_ref = inode_ref(self.inodes[children[i].1].pos);
+                let base_block = (base_ref >> 16) as u32;
+                let base_number = children[i].1 as i64 + 1;
+
+                let mut count = 0;
+                for (_, child) in &children[i..] {
+                    let child_ref = inode_ref(self.inodes[*child].pos);
+                    let delta = *child as i64 + 1 - base_number;
+                    if count == DIR_MAX_ENTRIES
+                        || (child_ref >> 16) as u32 != base_block
+                        || !(i16::MIN as i64..=i16::MAX as i64).contains(&delta)
+                    {
+                        break;
+                    }
+                    count += 1;
+                }
+
+                table.extend_from_slice(&(count as u32 - 1).to_le_bytes());
+                table.extend_from_slice(&base_block.to_le_bytes());
+                table.extend_from_slice(&(base_number as u32).to_le_bytes());
+
+                for (name, child) in &children[i..i + count] {
+                    ensure!(
+                        !name.is_empty() && name.len() <= 256,
+                        "Invalid file name: {}",
+                        name.to_string_lossy()
+                    );
+
+                    let child_inode = &self.inodes[*child];
+                    let delta = (*child as i64 + 1 - base_number) as i16;
+                    table.extend_from_slice(
+                        &((inode_ref(child_inode.pos) & 0xffff) as u16).to_le_bytes(),
+                    );
+                    table.extend_from_slice(&delta.to_le_bytes());
+                    table.extend_from_slice(&child_inode.basic_type().to_le_bytes());
+                    table.extend_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+                    table.extend_from_slice(name.as_bytes());
+                }
+
+                i += count;
+            }
+
+            listings.push((idx, start, table.len() - start));
+        }
+
+        let (packed, offsets) = pack_metadata(&table, true)?;
+        for (idx, start, len) in listings {
+            let dir_ref = metadata_ref(&offsets, packed.len(), start);
+            let inode = &mut self.inodes[idx];
+            inode.dir_ref = ((dir_ref >> 16) as u32, dir_ref as u16);
+            inode.dir_size = len;
+        }
+
+        Ok(packed)
+    }
+
+    fn encode_inode(&self, idx: usize) -> Vec<u8> {
+        let inode = &self.inodes[idx];
+        let mut buf = Vec::with_capacity(inode.record_size());
+
+        buf.extend_from_slice(&(inode.basic_type() + EXTENDED_OFFSET).to_le_bytes());
+        buf.extend_from_slice(&inode.mode.to_le_bytes());
+        buf.extend_from_slice(&inode.uid.to_le_bytes());
+        buf.extend_from_slice(&inode.gid.to_le_bytes());
+        buf.extend_from_slice(&inode.mtime.to_le_bytes());
+        buf.extend_from_slice(&(idx as u32 + 1).to_le_bytes());
+
+        match &inode.kind {
+            Kind::Dir(_) => {
+                let parent = if idx == 0 {
+                    self.inodes.len() as u32 + 1
+                } else {
+                    inode.parent as u32 + 1
+                };
+                buf.extend_from_slice(&inode.nlink.to_le_bytes());
+                buf.extend_from_slice(&((inode.dir_size + DIR_EMPTY_SIZE) as u32).to_le_bytes());
+                buf.extend_from_slice(&inode.dir_ref.0.to_le_bytes());
+                buf.extend_from_slice(&parent.to_le_bytes());
+                buf.extend_from_slice(&0u16.to_le_bytes());
+                buf.extend_from_slice(&inode.dir_ref.1.to_le_bytes());
+                buf.extend_from_slice(&inode.xattr.to_le_bytes());
+            }
+            Kind::File => {
+                buf.extend_from_slice(&inode.start_block.to_le_bytes());
+                buf.extend_from_slice(&inode.size.to_le_bytes());
+                buf.extend_from_slice(&0u64.to_le_bytes());
+                buf.extend_from_slice(&inode.nlink.to_le_bytes());
+                buf.extend_from_slice(&NO_FRAGMENT.to_le_bytes());
+                buf.extend_from_slice(&0u32.to_le_bytes());
+                buf.extend_from_slice(&inode.xattr.to_le_bytes());
+                for block in &inode.blocks {
+                    buf.extend_from_slice(&block.to_le_bytes());
+                }
+            }
+            Kind::Symlink(target) => {
+                buf.extend_from_slice(&inode.nlink.to_le_bytes());
+                buf.extend_from_slice(&(target.len() as u32).to_le_bytes());
+                buf.extend_from_slice(target);
+                buf.extend_from_slice(&inode.xattr.to_le_bytes());
+            }
+            Kind::Special(CHRDEV_TYPE | BLKDEV_TYPE) => {
+                buf.extend_from_slice(&inode.nlink.to_le_bytes());
+                buf.extend_from_slice(&encode_dev(inode.rdev).to_le_bytes());
+                buf.extend_from_slice(&inode.xattr.to_le_bytes());
+            }
+            Kind::Special(_) => {
+                buf.extend_from_slice(&inode.nlink.to_le_bytes());
+                buf.extend_from_slice(&inode.xattr.to_le_bytes());
+            }
+        }
+
+        buf
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<u64> {
+        let start = self.offset;
+        self.image.write_all_at(bytes, start)?;
+        self.offset += bytes.len() as u64;
+        Ok(start)
+    }
+
+    fn write_metadata(&mut self, stream: &[u8], compress: bool) -> Result<(u64, Vec<u64>, usize)> {
+        let (packed, offsets) = pack_metadata(stream, compress)?;
+        let start = self.write_bytes(&packed)?;
+        Ok((start, offsets, packed.len()))
+    }
+
+    fn write_index(&mut self, start: u64, offsets: &[u64]) -> Result<u64> {
+        let index: Vec<u8> = offsets
+            .iter()
+            .flat_map(|offset| (start + offset).to_le_bytes())
+            .collect();
+        self.write_bytes(&index)
+    }
+
+    fn write_id_table(&mut self) -> Result<u64> {
+        let ids: Vec<u8> = self.ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+
+        let (start, offsets, _) = self.write_metadata(&ids, true)?;
+        self.write_index(start, &offsets)
+    }
+
+    fn write_xattr_tables(&mut self) -> Result<u64> {
+        if self.xattr_sets.is_empty() {
+            return Ok(INVALID);
+        }
+
+        let mut kv = Vec::new();
+        let mut sets = Vec::with_capacity(self.xattr_sets.len());
+
+        for xattrs in &self.xattr_sets {
+            let start = kv.len();
+            let mut size = 0usize;
+
+            for (prefix, name, value) in xattrs {
+                kv.extend_from_slice(&prefix.to_le_bytes());
+                kv.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                kv.extend_from_slice(name);
+                kv.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                kv.extend_from_slice(value);
+                size += prefix_len(*prefix) + name.len() + 1 + value.len();
+            }
+
+            sets.push((start, xattrs.len() as u32, size as u32));
+        }
+
+        let (kv_start, kv_offsets, kv_len) = self.write_metadata(&kv, true)?;
+
+        let mut ids = Vec::with_capacity(sets.len() * 16);
+        for (start, count, size) in sets {
+            let xattr_ref = metadata_ref(&kv_offsets, kv_len, start);
+            ids.extend_from_slice(&xattr_ref.to_le_bytes());
+            ids.extend_from_slice(&count.to_le_bytes());
+            ids.extend_from_slice(&size.to_le_bytes());
+        }
+
+        let (ids_start, id_offsets, _) = self.write_metadata(&ids, true)?;
+
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(&kv_start.to_le_bytes());
+        header.extend_from_slice(&(self.xattr_sets.len() as u32).to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        let table_start = self.write_bytes(&header)?;
+
+        self.write_index(ids_start, &id_offsets)?;
+        Ok(table_start)
+    }
+}
+
+impl Inode {
+    fn basic_type(&self) -> u16 {
+        match self.kind {
+            Kind::Dir(_) => DIR_TYPE,
+            Kind::File => REG_TYPE,
+            Kind::Symlink(_) => SYMLINK_TYPE,
+            Kind::Special(ty) => ty,
+        }
+    }
+
+    fn record_size(&self) -> usize {
+        16 + match &self.kind {
+            Kind::Dir(_) => 24,
+            Kind::File => 40 + 4 * self.size.div_ceil(BLOCK_SIZE as u64) as usize,
+            Kind::Symlink(target) => 12 + target.len(),
+            Kind::Special(CHRDEV_TYPE | BLKDEV_TYPE) => 12,
+            Kind::Special(_) => 8,
+        }
+    }
+}
+
+fn read_xattrs(path: &Path) -> Xattrs {
+    let Ok(names) = llistxattr(path) else {
+        return Vec::new();
+    };
+
+    names
+        .iter()
+        .filter_map(|name| {
+            let bytes = name.as_bytes();
+            let (prefix, suffix) = if let Some(suffix) = bytes.strip_prefix(b"security.") {
+                (XATTR_SECURITY, suffix)
+            } else if bytes.starts_with(b"trusted.overlay.") {
+                (XATTR_TRUSTED, &bytes[b"trusted.".len()..])
+            } else {
+                return None;
+            };
+
+            let value = lgetxattr(path, name).ok()?;
+            Some((prefix, suffix.to_vec(), value))
+        })
+        .collect()
+}
+
+fn prefix_len(prefix: u16) -> usize {
+    match prefix {
+        XATTR_TRUSTED => b"trusted.".len(),
+        _ => b"security.".len(),
+    }
+}
+
+fn compress(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut encoder = ZlibEncoder::new(Vec::with_capacity(data.len()), Compression::default());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+    Ok((compressed.len() < data.len()).then_some(compressed))
+}
+
+fn pack_metadata(stream: &[u8], compress: bool) -> Result<(Vec<u8>, Vec<u64>)> {
+    let mut packed = Vec::with_capacity(stream.len() + 2 * stream.len().div_ceil(METADATA_SIZE));
+    let mut offsets = Vec::new();
+
+    for chunk in stream.chunks(METADATA_SIZE) {
+        offsets.push(packed.len() as u64);
+
+        let compressed = if compress {
+            self::compress(chunk)?
+        } else {
+            None
+        };
+        match compressed {
+            Some(bytes) => {
+                packed.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+                packed.extend_from_slice(&bytes);
+            }
+            None => {
+                packed
+                    .extend_from_slice(&(chunk.len() as u16 | METADATA_UNCOMPRESSED).to_le_bytes());
+                packed.extend_from_slice(chunk);
+            }
+        }
+    }
+
+    Ok((packed, offsets))
+}
+
+/// Reference to byte `pos` of an unpacked metadata stream, as block offset and block position.
+fn metadata_ref(offsets: &[u64], packed_len: usize, pos: usize) -> u64 {
+    // Empty listings may point just past the last block, which is never read.
+    let block = offsets
+        .get(pos / METADATA_SIZE)
+        .copied()
+        .unwrap_or(packed_len as u64);
+    (block << 16) | (pos % METADATA_SIZE) as u64
+}
+
+fn inode_ref(pos: usize) -> u64 {
+    let block = (pos / METADATA_SIZE) as u64 * (METADATA_SIZE as u64 + 2);
+    (block << 16) | (pos % METADATA_SIZE) as u64
+}
+
+fn encode_dev(rdev: u64) -> u32 {
+    let major = ((rdev >> 8) & 0xfff) as u32;
+    let minor = ((rdev & 0xff) | ((rdev >> 12) & 0xfff00)) as u32;
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
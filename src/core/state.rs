@@ -7,13 +7,19 @@ use std::{
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use crate::{defs, utils::fs::xattr};
+use crate::{
+    conf::config::{OverlayMode, PoaceaeRules},
+    defs,
+    mount::umount_mgr,
+    sys::loopdev::{self, LoopRecord},
+    utils::fs::xattr,
+};
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct RuntimeState {
     pub timestamp: u64,
     pub pid: u32,
-    pub storage_mode: String,
+    pub storage_mode: OverlayMode,
     pub mount_point: PathBuf,
     pub overlay_modules: Vec<String>,
     pub magic_modules: Vec<String>,
@@ -23,12 +29,22 @@ pub struct RuntimeState {
     pub zygisksu_enforce: bool,
     #[serde(default)]
     pub tmpfs_xattr_supported: bool,
+    #[serde(default)]
+    pub umount_targets: Vec<String>,
+    #[serde(default)]
+    pub loop_devices: Vec<LoopRecord>,
+    #[serde(default)]
+    pub poaceae_modules: Vec<String>,
+    #[serde(default)]
+    pub poaceae: PoaceaeRules,
+    #[serde(default)]
+    pub skipped_modules: Vec<String>,
 }
 
 impl RuntimeState {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        storage_mode: String,
+        storage_mode: OverlayMode,
         mount_point: PathBuf,
         overlay_modules: Vec<String>,
         magic_modules: Vec<String>,
@@ -45,6 +61,8 @@ impl RuntimeState {
 
         let zygisksu_enforce = crate::utils::check_zygisksu_enforce_status();
         let tmpfs_xattr_supported = xattr::is_overlay_xattr_supported().unwrap_or(false);
+        let umount_targets = umount_mgr::registered();
+        let loop_devices = loopdev::attached();
 
         Self {
             timestamp,
@@ -56,6 +74,11 @@ impl RuntimeState {
             active_mounts,
             zygisksu_enforce,
             tmpfs_xattr_supported,
+            umount_targets,
+            loop_devices,
+            poaceae_modules: Vec::new(),
+            poaceae: PoaceaeRules::default(),
+            skipped_modules: Vec::new(),
         }
     }
 
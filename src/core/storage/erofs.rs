@@ -0,0 +1,235 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result, bail};
+use rustix::mount::MountFlags;
+
+use super::{
+    StorageBackend, StorageContext, grow_tmpfs, make_private, prepare_staging, try_hide,
+    unmount_if_mounted,
+};
+use crate::{
+    conf::config::OverlayMode,
+    core::erofs as writer,
+    defs,
+    sys::{caps::Capabilities, loopdev, nuke},
+    utils::{self, ensure_dir_exists, lsetfilecon},
+};
+
+pub struct ErofsBackend {
+    image: PathBuf,
+    staging: PathBuf,
+    target: PathBuf,
+    mount_point: PathBuf,
+    mount_source: String,
+    disable_umount: bool,
+    fingerprint: Option<String>,
+    current: bool,
+}
+
+impl ErofsBackend {
+    pub fn new(ctx: &StorageContext) -> Self {
+        let staging = Path::new(defs::RUN_DIR).join("erofs_staging");
+
+        Self {
+            image: image_path(&ctx.img_path),
+            mount_point: staging.clone(),
+            staging,
+            target: ctx.mnt_base.clone(),
+            mount_source: ctx.mount_source.clone(),
+            disable_umount: ctx.disable_umount,
+            fingerprint: ctx.fingerprint.clone(),
+            current: false,
+        }
+    }
+
+    fn finish_mount(&mut self) {
+        nuke::nuke_path(&self.image);
+        make_private(&self.target);
+        try_hide(&self.target, self.disable_umount);
+
+        self.mount_point = self.target.clone();
+    }
+}
+
+impl StorageBackend for ErofsBackend {
+    fn mode(&self) -> OverlayMode {
+        OverlayMode::Erofs
+    }
+
+    fn is_supported(&self) -> bool {
+        Capabilities::probe().supports_fs("erofs")
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        if let Some(fingerprint) = &self.fingerprint
+            && cached_image_matches(&self.image, fingerprint)
+        {
+            match mount_erofs_image(&self.image, &self.target) {
+                Ok(()) => {
+                    log::info!("Reusing cached EROFS image (fingerprint {})", fingerprint);
+                    self.current = true;
+                    self.finish_mount();
+                    return Ok(());
+                }
+                Err(e) => log::warn!("Cached EROFS image unusable, rebuilding: {:#}", e),
+            }
+        }
+
+        prepare_staging(&self.staging, &self.mount_source, self.disable_umount)
+    }
+
+    fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    fn is_current(&self) -> bool {
+        self.current
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    fn grow(&mut self, required: u64) -> Result<bool> {
+        grow_tmpfs(&self.staging, required)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.current {
+            return Ok(());
+        }
+
+        let fingerprint_path = fingerprint_path(&self.image);
+        if fingerprint_path.exists() {
+            let _ = fs::remove_file(&fingerprint_path);
+        }
+
+        create_erofs_image(&self.staging, &self.image).context("Failed to pack EROFS image")?;
+
+        if let Err(e) = unmount_if_mounted(&self.staging) {
+            log::warn!("Failed to unmount staging tmpfs: {}", e);
+        }
+
+        if let Err(e) = fs::remove_dir(&self.staging) {
+            log::debug!("Failed to remove staging dir: {}", e);
+        }
+
+        mount_erofs_image(&self.image, &self.target)
+            .context("Failed to mount finalized EROFS image")?;
+
+        if let Some(fingerprint) = &self.fingerprint
+            && let Err(e) = utils::atomic_write(&fingerprint_path, fingerprint)
+        {
+            log::warn!("Failed to save EROFS image fingerprint: {:#}", e);
+        }
+
+        self.current = true;
+        self.finish_mount();
+        Ok(())
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        unmount_if_mounted(&self.target)?;
+        unmount_if_mounted(&self.staging)?;
+        Ok(())
+    }
+}
+
+pub fn image_path(img_path: &Path) -> PathBuf {
+    img_path.with_extension("erofs")
+}
+
+pub fn fingerprint_path(image_path: &Path) -> PathBuf {
+    let mut path = image_path.as_os_str().to_owned();
+    path.push(".fingerprint");
+    PathBuf::from(path)
+}
+
+pub fn cached_image_matches(image_path: &Path, fingerprint: &str) -> bool {
+    image_path.is_file()
+        && fs::read_to_string(fingerprint_path(image_path))
+            .map(|stored| stored.trim() == fingerprint)
+            .unwrap_or(false)
+}
+
+pub fn invalidate_image_cache(img_path: &Path) -> Result<bool> {
+    let fingerprint_path = fingerprint_path(&image_path(img_path));
+    if !fingerprint_path.exists() {
+        return Ok(false);
+    }
+
+    fs::remove_file(&fingerprint_path)
+        .with_context(|| format!("Failed to remove {}", fingerprint_path.display()))?;
+    Ok(true)
+}
+
+fn create_erofs_image(src_dir: &Path, image_path: &Path) -> Result<()> {
+    let mkfs_bin = Path::new(defs::MKFS_EROFS_PATH);
+    let cmd_name = if mkfs_bin.exists() {
+        mkfs_bin.as_os_str()
+    } else {
+        std::ffi::OsStr::new("mkfs.erofs")
+    };
+
+    if image_path.exists() {
+        let _ = fs::remove_file(image_path);
+    }
+
+    let output = Command::new(cmd_name)
+        .arg("-z")
+        .arg("lz4hc")
+        .arg("-x")
+        .arg("256")
+        .arg(image_path)
+        .arg(src_dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => bail!(
+            "Failed to create EROFS image: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("mkfs.erofs not found, using the built-in EROFS writer");
+            writer::build_image(src_dir, image_path, true)
+                .context("Built-in EROFS writer failed")?;
+        }
+        Err(e) => return Err(e).context("Failed to execute mkfs.erofs"),
+    }
+
+    let _ = fs::set_permissions(image_path, fs::Permissions::from_mode(0o644));
+    lsetfilecon(image_path, "u:object_r:ksu_file:s0")?;
+    Ok(())
+}
+
+fn mount_erofs_image(image_path: &Path, target: &Path) -> Result<()> {
+    ensure_dir_exists(target)?;
+    lsetfilecon(image_path, "u:object_r:ksu_file:s0").ok();
+
+    let record = loopdev::mount_image(
+        image_path,
+        target,
+        c"erofs",
+        MountFlags::RDONLY | MountFlags::NODEV | MountFlags::NOATIME,
+        None,
+    )?;
+    log::info!(
+        "EROFS image mounted via {} (loop{})",
+        record.device.display(),
+        record.number
+    );
+
+    if fs::read_dir(target)?.next().is_none() {
+        bail!("EROFS mount success but directory is empty (Loop device failure?)");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use jwalk::WalkDir;
+
+use super::{
+    StorageBackend, StorageContext, calculate_total_size, make_private, try_hide,
+    unmount_if_mounted,
+};
+use crate::{
+    conf::config::OverlayMode,
+    mount::overlayfs::utils as overlay_utils,
+    sys::nuke,
+    utils::{self, ensure_dir_exists},
+};
+
+const DEFAULT_SELINUX_CONTEXT: &str = "u:object_r:system_file:s0";
+const EXT4_MIN_IMAGE_SIZE: u64 = 64 * 1024 * 1024;
+const EXT4_SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT4_MAGIC: u16 = 0xEF53;
+const EXT4_VALID_FS: u16 = 0x0001;
+const EXT4_ERROR_FS: u16 = 0x0002;
+
+pub struct Ext4Backend {
+    mount_point: PathBuf,
+    img_path: PathBuf,
+    moduledir: PathBuf,
+    disable_umount: bool,
+}
+
+impl Ext4Backend {
+    pub fn new(ctx: &StorageContext) -> Self {
+        Self {
+            mount_point: ctx.mnt_base.clone(),
+            img_path: ctx.img_path.clone(),
+            moduledir: ctx.moduledir.clone(),
+            disable_umount: ctx.disable_umount,
+        }
+    }
+}
+
+impl StorageBackend for Ext4Backend {
+    fn mode(&self) -> OverlayMode {
+        OverlayMode::Ext4
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        setup_ext4_image(&self.mount_point, &self.img_path, &self.moduledir)?;
+
+        make_private(&self.mount_point);
+        try_hide(&self.mount_point, self.disable_umount);
+
+        Ok(())
+    }
+
+    fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        unmount_if_mounted(&self.mount_point)
+    }
+}
+
+fn check_image<P>(img: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path = img.as_ref();
+    let path_str = path.to_str().context("Invalid path string")?;
+    let result = Command::new("e2fsck")
+        .args(["-yf", path_str])
+        .status()
+        .with_context(|| format!("Failed to exec e2fsck {}", path.display()))?;
+    let code = result.code();
+
+    log::info!("e2fsck exit code: {}", code.unwrap_or(-1));
+    Ok(())
+}
+
+fn ext4_image_size(moduledir: &Path) -> Result<u64> {
+    let total_size = calculate_total_size(moduledir)?;
+    Ok(std::cmp::max(
+        (total_size as f64 * 1.2) as u64,
+        EXT4_MIN_IMAGE_SIZE,
+    ))
+}
+
+fn ext4_state(img_path: &Path) -> Result<u16> {
+    let mut file = fs::File::open(img_path)?;
+    let mut sb = [0u8; 64];
+    file.seek(SeekFrom::Start(EXT4_SUPERBLOCK_OFFSET))?;
+    file.read_exact(&mut sb)?;
+
+    let magic = u16::from_le_bytes([sb[56], sb[57]]);
+    ensure!(
+        magic == EXT4_MAGIC,
+        "{} is not an ext4 image",
+        img_path.display()
+    );
+
+    Ok(u16::from_le_bytes([sb[58], sb[59]]))
+}
+
+fn resize2fs(img_path: &Path, size: Option<u64>) -> Result<()> {
+    let mut cmd = Command::new("resize2fs");
+    cmd.arg("-f").arg(img_path);
+    if let Some(size) = size {
+        cmd.arg(format!("{}K", size / 1024));
+    }
+
+    let output = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .context("Failed to execute resize2fs")?;
+
+    ensure!(
+        output.status.success(),
+        "resize2fs failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+fn prepare_existing_ext4_image(img_path: &Path, required: u64) -> Result<()> {
+    let state = ext4_state(img_path)?;
+
+    if state & EXT4_VALID_FS == 0 || state & EXT4_ERROR_FS != 0 {
+        log::warn!("modules.img was not cleanly unmounted (state={:#x})", state);
+        crate::sys::mount::repair_image(img_path)?;
+    }
+
+    let current = fs::metadata(img_path)?.len();
+
+    if required > current {
+        log::info!("Growing modules.img: {} -> {} bytes", current, required);
+        fs::OpenOptions::new()
+            .write(true)
+            .open(img_path)?
+            .set_len(required)?;
+        resize2fs(img_path, None)?;
+    } else if current > required.saturating_mul(2) {
+        log::info!("Shrinking modules.img: {} -> {} bytes", current, required);
+        check_image(img_path)?;
+        match resize2fs(img_path, Some(required)) {
+            Ok(()) => fs::OpenOptions::new()
+                .write(true)
+                .open(img_path)?
+                .set_len(required)?,
+            Err(e) => log::warn!("Keeping current image size: {:#}", e),
+        }
+    }
+
+    Ok(())
+}
+
+fn create_ext4_image(img_path: &Path, size: u64) -> Result<()> {
+    if img_path.exists()
+        && let Err(e) = fs::remove_file(img_path)
+    {
+        log::warn!("Failed to remove old image: {}", e);
+    }
+
+    fs::File::create(img_path)
+        .context("Failed to create ext4 image file")?
+        .set_len(size)
+        .context("Failed to extend ext4 image")?;
+
+    let result = Command::new("mkfs.ext4")
+        .arg("-b")
+        .arg("1024")
+        .arg(img_path)
+        .stdout(std::process::Stdio::piped())
+        .output()?;
+
+    ensure!(
+        result.status.success(),
+        "Failed to format ext4 image: {}",
+        String::from_utf8(result.stderr)?
+    );
+
+    check_image(img_path)
+}
+
+fn setup_ext4_image(target: &Path, img_path: &Path, moduledir: &Path) -> Result<()> {
+    let required = ext4_image_size(moduledir)?;
+
+    let reused = img_path.exists()
+        && match prepare_existing_ext4_image(img_path, required) {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Existing modules.img unusable, recreating: {:#}", e);
+                false
+            }
+        };
+
+    if !reused {
+        create_ext4_image(img_path, required)?;
+    }
+
+    utils::lsetfilecon(img_path, "u:object_r:ksu_file:s0").ok();
+
+    ensure_dir_exists(target)?;
+    if overlay_utils::AutoMountExt4::try_new(img_path, target, false).is_err() {
+        if crate::sys::mount::repair_image(img_path).is_ok() {
+            overlay_utils::AutoMountExt4::try_new(img_path, target, false)
+                .context("Failed to mount modules.img after repair")
+                .map(|_| ())?;
+        } else {
+            bail!("Failed to repair modules.img");
+        }
+    }
+
+    nuke::nuke_path(img_path);
+
+    // Content synced into a reused image already carries its copied contexts.
+    if !reused {
+        for dir_entry in WalkDir::new(target).parallelism(jwalk::Parallelism::Serial) {
+            if let Some(path) = dir_entry.ok().map(|dir_entry| dir_entry.path()) {
+                let _ = utils::lsetfilecon(&path, DEFAULT_SELINUX_CONTEXT);
+            }
+        }
+    }
+
+    log::info!("Ext4 image {}", if reused { "reused" } else { "created" });
+
+    Ok(())
+}
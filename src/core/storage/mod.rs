@@ -0,0 +1,232 @@
+mod erofs;
+mod ext4;
+pub mod placement;
+mod squashfs;
+mod tmpfs;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use rustix::{
+    fs::statvfs,
+    mount::{
+        MountFlags, MountPropagationFlags, UnmountFlags, mount_change, mount_remount,
+        unmount as umount,
+    },
+};
+
+pub use self::erofs::invalidate_image_cache;
+use self::{
+    erofs::ErofsBackend, ext4::Ext4Backend, squashfs::SquashfsBackend, tmpfs::TmpfsBackend,
+};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::mount::umount_mgr::send_umountable;
+use crate::{
+    conf::config::OverlayMode,
+    sys::mount::{is_mounted, mount_tmpfs},
+    utils::ensure_dir_exists,
+};
+
+#[derive(Debug, Clone)]
+pub struct StorageContext {
+    pub mnt_base: PathBuf,
+    pub img_path: PathBuf,
+    pub moduledir: PathBuf,
+    pub mount_source: String,
+    pub disable_umount: bool,
+    pub fingerprint: Option<String>,
+}
+
+pub trait StorageBackend {
+    fn mode(&self) -> OverlayMode;
+
+    fn is_supported(&self) -> bool {
+        true
+    }
+
+    /// Mounts the area modules get synced into.
+    fn prepare(&mut self) -> Result<()>;
+
+    fn capacity(&self) -> Option<u64> {
+        available_space(self.mount_point())
+    }
+
+    /// Tries to make at least `required` bytes available, returning whether capacity grew.
+    fn grow(&mut self, _required: u64) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn mount_point(&self) -> &Path;
+
+    /// The prepared content already matches the enabled modules, so sync can be skipped.
+    fn is_current(&self) -> bool {
+        false
+    }
+
+    /// Content is frozen by `commit`, so anything needed later must exist before it.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn teardown(&mut self) -> Result<()>;
+}
+
+fn backend_for(mode: OverlayMode, ctx: &StorageContext) -> Box<dyn StorageBackend> {
+    match mode {
+        OverlayMode::Tmpfs => Box::new(TmpfsBackend::new(ctx)),
+        OverlayMode::Ext4 => Box::new(Ext4Backend::new(ctx)),
+        OverlayMode::Erofs => Box::new(ErofsBackend::new(ctx)),
+        OverlayMode::Squashfs => Box::new(SquashfsBackend::new(ctx)),
+    }
+}
+
+pub fn setup(ctx: &StorageContext, chain: &[OverlayMode]) -> Result<Box<dyn StorageBackend>> {
+    if is_mounted(&ctx.mnt_base) {
+        let _ = umount(&ctx.mnt_base, UnmountFlags::DETACH);
+    }
+
+    let mut errors = Vec::new();
+
+    for mode in chain {
+        let mut backend = backend_for(*mode, ctx);
+
+        if !backend.is_supported() {
+            log::info!("Storage backend [{}] not supported, skipping.", mode);
+            continue;
+        }
+
+        match backend.prepare() {
+            Ok(()) => return Ok(backend),
+            Err(e) => {
+                log::warn!("Storage backend [{}] failed: {:#}", mode, e);
+                if let Err(e) = backend.teardown() {
+                    log::debug!("Teardown of [{}] failed: {:#}", mode, e);
+                }
+                errors.push(format!("{}: {:#}", mode, e));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        bail!("No supported storage backend in chain {:?}", chain);
+    }
+    bail!("All storage backends failed ({})", errors.join("; "))
+}
+
+fn calculate_total_size(path: &Path) -> Result<u64> {
+    let mut total_size = 0;
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_file() {
+                total_size += entry.metadata()?.len();
+            } else if file_type.is_dir() {
+                total_size += calculate_total_size(&entry.path())?;
+            }
+        }
+    }
+    Ok(total_size)
+}
+
+fn available_space(path: &Path) -> Option<u64> {
+    statvfs(path)
+        .ok()
+        .map(|stat| stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+fn mem_available() -> Option<u64> {
+    fs::read_to_string("/proc/meminfo")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| {
+            value
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .map(|kb| kb * 1024)
+}
+
+/// Raises the size limit of the tmpfs at `path` so `required` bytes fit, as long as
+/// the extra room stays within half of the memory currently available.
+fn grow_tmpfs(path: &Path, required: u64) -> Result<bool> {
+    let stat = statvfs(path).context("Failed to stat tmpfs")?;
+    let total = stat.f_blocks.saturating_mul(stat.f_frsize);
+    let available = stat.f_bavail.saturating_mul(stat.f_frsize);
+
+    if required <= available {
+        return Ok(false);
+    }
+
+    let extra = required - available;
+    let budget = mem_available().unwrap_or(0) / 2;
+    if extra > budget {
+        log::warn!(
+            "Cannot grow tmpfs by {} MiB, only {} MiB of memory to spare",
+            extra / 1024 / 1024,
+            budget / 1024 / 1024
+        );
+        return Ok(false);
+    }
+
+    let size = total + extra;
+    mount_remount(path, MountFlags::empty(), format!("size={}", size).as_str())
+        .context("Failed to remount tmpfs with a larger size")?;
+    log::info!(
+        "Grew tmpfs at {} to {} MiB",
+        path.display(),
+        size / 1024 / 1024
+    );
+
+    Ok(true)
+}
+
+fn make_private(path: &Path) {
+    if let Err(e) = mount_change(path, MountPropagationFlags::PRIVATE) {
+        log::warn!("Failed to make storage private: {}", e);
+    }
+}
+
+fn try_hide(path: &Path, disable_umount: bool) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if !disable_umount {
+        let _ = send_umountable(path);
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = (path, disable_umount);
+}
+
+/// Mounts a fresh tmpfs that read-only backends sync into before packing.
+fn prepare_staging(staging: &Path, mount_source: &str, disable_umount: bool) -> Result<()> {
+    unmount_if_mounted(staging)?;
+    if staging.exists() {
+        let _ = fs::remove_dir_all(staging);
+    }
+    ensure_dir_exists(staging)?;
+
+    mount_tmpfs(staging, mount_source)?;
+
+    make_private(staging);
+    try_hide(staging, disable_umount);
+
+    Ok(())
+}
+
+fn unmount_if_mounted(path: &Path) -> Result<()> {
+    if is_mounted(path) {
+        umount(path, UnmountFlags::DETACH)?;
+    }
+    Ok(())
+}
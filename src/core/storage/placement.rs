@@ -0,0 +1,443 @@
+use std::{
+    collections::HashSet,
+    ffi::CStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use rustix::mount::MountFlags;
+
+use super::{StorageBackend, calculate_total_size, erofs, make_private, try_hide};
+use crate::{
+    conf::config::{OverlayMode, PlacementConfig, StoragePlacement},
+    core::{
+        erofs as erofs_writer,
+        inventory::{Module, fingerprint},
+        ops::{exclude::ExcludeSet, manifest::SyncManifest},
+        squashfs as squashfs_writer,
+    },
+    defs,
+    sys::{caps::Capabilities, loopdev},
+    utils::{self, ensure_dir_exists, lsetfilecon},
+};
+
+const IMAGES_MOUNT_DIR: &str = ".images";
+const CAPACITY_HEADROOM_PERCENT: u64 = 10;
+
+/// Settles where each module is served from for the chosen storage backend.
+pub fn resolve(modules: &mut [Module], mode: OverlayMode, policy: &PlacementConfig) {
+    let threshold = policy.large_module_threshold_mb.saturating_mul(1024 * 1024);
+
+    for module in modules {
+        let placement = match module.rules.storage {
+            Some(placement) => placement,
+            None if mode == OverlayMode::Tmpfs && threshold > 0 => {
+                let size = calculate_total_size(&module.source_path).unwrap_or(0);
+                if size >= threshold {
+                    log::info!(
+                        "Module {} is {} MiB, placing it on {:?} storage",
+                        module.id,
+                        size / 1024 / 1024,
+                        policy.large_module_storage
+                    );
+                    policy.large_module_storage
+                } else {
+                    StoragePlacement::Tmpfs
+                }
+            }
+            None => StoragePlacement::Tmpfs,
+        };
+
+        // Only tmpfs storage lives in RAM, other backends already are images.
+        module.placement = match placement {
+            StoragePlacement::Image if mode != OverlayMode::Tmpfs => StoragePlacement::Tmpfs,
+            placement => placement,
+        };
+    }
+}
+
+pub fn content_path(storage_root: &Path, module: &Module) -> PathBuf {
+    match module.placement {
+        StoragePlacement::Tmpfs => storage_root.join(&module.id),
+        StoragePlacement::Image => storage_root.join(IMAGES_MOUNT_DIR).join(&module.id),
+        StoragePlacement::Source => module.source_path.clone(),
+    }
+}
+
+/// Checks that modules synced into storage fit it before any copying starts.
+///
+/// The backend is grown first. If that is not enough, the largest modules without a
+/// `storage` rule move off it. Modules pinned to it that still do not fit are dropped
+/// from `modules` and their ids returned.
+pub fn fit_to_capacity(
+    storage: &mut dyn StorageBackend,
+    modules: &mut Vec<Module>,
+    policy: &PlacementConfig,
+) -> Vec<String> {
+    let Some(capacity) = storage.capacity() else {
+        return Vec::new();
+    };
+
+    let mut needed: Vec<(usize, u64)> = modules
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.placement == StoragePlacement::Tmpfs)
+        .map(|(i, m)| (i, required_space(storage.mount_point(), m)))
+        .collect();
+
+    let required: u64 = needed.iter().map(|(_, size)| size).sum();
+    let required = required + required * CAPACITY_HEADROOM_PERCENT / 100;
+    if required <= capacity {
+        return Vec::new();
+    }
+
+    log::warn!(
+        "Modules need about {} MiB but storage has {} MiB free",
+        required / 1024 / 1024,
+        capacity / 1024 / 1024
+    );
+
+    match storage.grow(required) {
+        Ok(true) if storage.capacity().is_some_and(|grown| required <= grown) => {
+            return Vec::new();
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Failed to grow storage: {:#}", e),
+    }
+
+    let capacity = storage.capacity().unwrap_or(capacity);
+    let mut required = required;
+    let fallback = match policy.large_module_storage {
+        StoragePlacement::Image if storage.mode() == OverlayMode::Tmpfs => StoragePlacement::Image,
+        _ => StoragePlacement::Source,
+    };
+
+    needed.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    for (index, size) in &needed {
+        if required <= capacity {
+            break;
+        }
+
+        let module = &mut modules[*index];
+        if module.rules.storage.is_some() {
+            continue;
+        }
+
+        log::warn!(
+            "Module {} ({} MiB) does not fit storage, placing it on {:?}",
+            module.id,
+            size / 1024 / 1024,
+            fallback
+        );
+        module.placement = fallback;
+        required = required.saturating_sub(size + size * CAPACITY_HEADROOM_PERCENT / 100);
+    }
+
+    if required <= capacity {
+        return Vec::new();
+    }
+
+    let pinned_required = required;
+    let mut skipped = Vec::new();
+    for (index, size) in &needed {
+        if required <= capacity {
+            break;
+        }
+
+        let module = &modules[*index];
+        if module.placement == StoragePlacement::Tmpfs {
+            skipped.push(module.id.clone());
+            required = required.saturating_sub(size + size * CAPACITY_HEADROOM_PERCENT / 100);
+        }
+    }
+
+    log::error!(
+        "Storage too small: {} MiB needed, {} MiB free, skipping modules pinned to it: {}",
+        pinned_required / 1024 / 1024,
+        capacity / 1024 / 1024,
+        skipped.join(", ")
+    );
+
+    modules.retain(|m| !skipped.contains(&m.id));
+    skipped
+}
+
+// Content already synced for a module is mostly reused, so only the difference is needed.
+fn required_space(storage_root: &Path, module: &Module) -> u64 {
+    let source = SyncManifest::scan(&module.source_path, &ExcludeSet::for_module(module))
+        .map_or(0, |manifest| manifest.total_size());
+    let synced = SyncManifest::load(&storage_root.join(&module.id))
+        .map_or(0, |manifest| manifest.total_size());
+    source.saturating_sub(synced)
+}
+
+/// Mounts a read-only image for every module placed on one, falling back to its source.
+pub fn mount_images(modules: &mut [Module], storage_root: &Path, disable_umount: bool) {
+    let images_dir = Path::new(defs::MODULE_IMAGES_DIR);
+    let mut kept = HashSet::new();
+
+    for module in modules
+        .iter_mut()
+        .filter(|m| m.placement == StoragePlacement::Image)
+    {
+        match mount_module_image(module, images_dir, storage_root, disable_umount) {
+            Ok(image) => {
+                kept.insert(image);
+            }
+            Err(e) => {
+                log::warn!(
+                    "Image for module {} unavailable, using its source: {:#}",
+                    module.id,
+                    e
+                );
+                module.placement = StoragePlacement::Source;
+            }
+        }
+    }
+
+    prune_images(images_dir, &kept);
+}
+
+fn mount_module_image(
+    module: &Module,
+    images_dir: &Path,
+    storage_root: &Path,
+    disable_umount: bool,
+) -> Result<PathBuf> {
+    let capabilities = Capabilities::probe();
+    let (fstype, extension): (&CStr, &str) = if capabilities.supports_fs("erofs") {
+        (c"erofs", "erofs")
+    } else if capabilities.supports_fs("squashfs") {
+        (c"squashfs", "squashfs")
+    } else {
+        bail!("Kernel supports neither erofs nor squashfs");
+    };
+
+    let image = images_dir.join(format!("{}.{}", module.id, extension));
+    let fingerprint_path = erofs::fingerprint_path(&image);
+    let fingerprint = fingerprint::compute(std::slice::from_ref(module));
+
+    if !erofs::cached_image_matches(&image, &fingerprint) {
+        ensure_dir_exists(images_dir)?;
+        let _ = fs::remove_file(&fingerprint_path);
+
+        log::info!("Packing module {} into {}", module.id, image.display());
+        // Pack a filtered copy so the image exposes the same tree a synced module would.
+        let staging = images_dir.join(format!(".staging_{}", module.id));
+        let _ = fs::remove_dir_all(&staging);
+        let excludes = ExcludeSet::for_module(module);
+        let built = utils::sync_dir_filtered(&module.source_path, &staging, None, &|relative| {
+            excludes.is_excluded(relative)
+        })
+        .and_then(|()| {
+            if extension == "erofs" {
+                erofs_writer::build_image(&staging, &image, true)
+            } else {
+                squashfs_writer::build_image(&staging, &image, None)
+            }
+        });
+        let _ = fs::remove_dir_all(&staging);
+        built?;
+
+        if let Err(e) = utils::atomic_write(&fingerprint_path, &fingerprint) {
+            log::warn!(
+                "Failed to save image fingerprint for {}: {:#}",
+                module.id,
+                e
+            );
+        }
+    }
+
+    lsetfilecon(&image, "u:object_r:ksu_file:s0").ok();
+
+    let target = storage_root.join(IMAGES_MOUNT_DIR).join(&module.id);
+    ensure_dir_exists(&target)?;
+
+    loopdev::mount_image(
+        &image,
+        &target,
+        fstype,
+        MountFlags::RDONLY | MountFlags::NODEV | MountFlags::NOATIME,
+        None,
+    )
+    .with_context(|| format!("Failed to mount image for module {}", module.id))?;
+
+    make_private(&target);
+    try_hide(&target, disable_umount);
+
+    Ok(image)
+}
+
+fn prune_images(images_dir: &Path, kept: &HashSet<PathBuf>) {
+    let Ok(entries) = fs::read_dir(images_dir) else {
+        return;
+    };
+
+    for path in entries.flatten().map(|entry| entry.path()) {
+        let image = if path.extension().is_some_and(|ext| ext == "fingerprint") {
+            path.with_extension("")
+        } else {
+            path.clone()
+        };
+
+        if !kept.contains(&image) {
+            log::debug!("Removing stale module image {}", path.display());
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::config::ModuleRules;
+
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("placement-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("storage")).unwrap();
+            Self(dir)
+        }
+
+        fn module(&self, id: &str, size: usize, pinned: bool) -> Module {
+            let source_path = self.0.join("modules").join(id);
+            fs::create_dir_all(source_path.join("system/etc")).unwrap();
+            fs::write(source_path.join("system/etc/blob"), vec![0u8; size]).unwrap();
+            Module {
+                id: id.to_string(),
+                source_path,
+                rules: ModuleRules {
+                    storage: pinned.then_some(StoragePlacement::Tmpfs),
+                    ..Default::default()
+                },
+                placement: StoragePlacement::Tmpfs,
+            }
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    struct FakeStorage {
+        mount_point: PathBuf,
+        capacity: u64,
+        growable_to: Option<u64>,
+    }
+
+    impl StorageBackend for FakeStorage {
+        fn mode(&self) -> OverlayMode {
+            OverlayMode::Tmpfs
+        }
+
+        fn prepare(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn capacity(&self) -> Option<u64> {
+            Some(self.capacity)
+        }
+
+        fn grow(&mut self, _required: u64) -> Result<bool> {
+            match self.growable_to {
+                Some(size) => {
+                    self.capacity = size;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+
+        fn mount_point(&self) -> &Path {
+            &self.mount_point
+        }
+
+        fn teardown(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn fixture(scratch: &Scratch) -> Vec<Module> {
+        vec![
+            scratch.module("large", 4000, false),
+            scratch.module("pinned", 3000, true),
+            scratch.module("small", 1000, false),
+        ]
+    }
+
+    fn storage(scratch: &Scratch, capacity: u64, growable_to: Option<u64>) -> FakeStorage {
+        FakeStorage {
+            mount_point: scratch.0.join("storage"),
+            capacity,
+            growable_to,
+        }
+    }
+
+    fn placements(modules: &[Module]) -> Vec<(&str, StoragePlacement)> {
+        modules
+            .iter()
+            .map(|m| (m.id.as_str(), m.placement))
+            .collect()
+    }
+
+    #[test]
+    fn growing_storage_keeps_every_module() {
+        let scratch = Scratch::new("grow");
+        let mut modules = fixture(&scratch);
+        let mut storage = storage(&scratch, 100, Some(1 << 20));
+
+        let skipped = fit_to_capacity(&mut storage, &mut modules, &PlacementConfig::default());
+
+        assert!(skipped.is_empty());
+        assert_eq!(storage.capacity, 1 << 20);
+        assert!(
+            modules
+                .iter()
+                .all(|m| m.placement == StoragePlacement::Tmpfs)
+        );
+    }
+
+    #[test]
+    fn unpinned_modules_move_off_largest_first() {
+        let scratch = Scratch::new("move");
+        let mut modules = fixture(&scratch);
+        let mut storage = storage(&scratch, 5000, None);
+
+        let skipped = fit_to_capacity(&mut storage, &mut modules, &PlacementConfig::default());
+
+        assert!(skipped.is_empty());
+        assert_eq!(
+            placements(&modules),
+            [
+                ("large", StoragePlacement::Image),
+                ("pinned", StoragePlacement::Tmpfs),
+                ("small", StoragePlacement::Tmpfs),
+            ]
+        );
+    }
+
+    #[test]
+    fn pinned_modules_that_do_not_fit_are_skipped() {
+        let scratch = Scratch::new("skip");
+        let mut modules = fixture(&scratch);
+        let mut storage = storage(&scratch, 2000, None);
+
+        let skipped = fit_to_capacity(&mut storage, &mut modules, &PlacementConfig::default());
+
+        assert_eq!(skipped, ["pinned"]);
+        assert_eq!(
+            placements(&modules),
+            [
+                ("large", StoragePlacement::Image),
+                ("small", StoragePlacement::Image),
+            ]
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use rustix::mount::MountFlags;
+
+use super::{
+    StorageBackend, StorageContext, grow_tmpfs, make_private, prepare_staging, try_hide,
+    unmount_if_mounted,
+};
+use crate::{
+    conf::config::OverlayMode,
+    core::squashfs as writer,
+    defs,
+    sys::{caps::Capabilities, loopdev, nuke},
+    utils::{ensure_dir_exists, lsetfilecon},
+};
+
+pub struct SquashfsBackend {
+    image: PathBuf,
+    staging: PathBuf,
+    target: PathBuf,
+    mount_point: PathBuf,
+    moduledir: PathBuf,
+    mount_source: String,
+    disable_umount: bool,
+    committed: bool,
+}
+
+impl SquashfsBackend {
+    pub fn new(ctx: &StorageContext) -> Self {
+        let staging = Path::new(defs::RUN_DIR).join("squashfs_staging");
+
+        Self {
+            image: image_path(&ctx.img_path),
+            mount_point: staging.clone(),
+            staging,
+            target: ctx.mnt_base.clone(),
+            moduledir: ctx.moduledir.clone(),
+            mount_source: ctx.mount_source.clone(),
+            disable_umount: ctx.disable_umount,
+            committed: false,
+        }
+    }
+}
+
+impl StorageBackend for SquashfsBackend {
+    fn mode(&self) -> OverlayMode {
+        OverlayMode::Squashfs
+    }
+
+    fn is_supported(&self) -> bool {
+        Capabilities::probe().supports_fs("squashfs")
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        prepare_staging(&self.staging, &self.mount_source, self.disable_umount)
+    }
+
+    fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    fn grow(&mut self, required: u64) -> Result<bool> {
+        grow_tmpfs(&self.staging, required)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.committed {
+            return Ok(());
+        }
+
+        if self.image.exists() {
+            let _ = fs::remove_file(&self.image);
+        }
+
+        // Staging tmpfs may lack trusted xattrs, so overlay markers are read back from the modules.
+        writer::build_image(&self.staging, &self.image, Some(&self.moduledir))
+            .context("Failed to pack SquashFS image")?;
+
+        let _ = fs::set_permissions(&self.image, fs::Permissions::from_mode(0o644));
+        lsetfilecon(&self.image, "u:object_r:ksu_file:s0")?;
+
+        if let Err(e) = unmount_if_mounted(&self.staging) {
+            log::warn!("Failed to unmount staging tmpfs: {}", e);
+        }
+
+        if let Err(e) = fs::remove_dir(&self.staging) {
+            log::debug!("Failed to remove staging dir: {}", e);
+        }
+
+        mount_squashfs_image(&self.image, &self.target)
+            .context("Failed to mount finalized SquashFS image")?;
+
+        nuke::nuke_path(&self.image);
+        make_private(&self.target);
+        try_hide(&self.target, self.disable_umount);
+
+        self.mount_point = self.target.clone();
+        self.committed = true;
+        Ok(())
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        unmount_if_mounted(&self.target)?;
+        unmount_if_mounted(&self.staging)?;
+        Ok(())
+    }
+}
+
+pub fn image_path(img_path: &Path) -> PathBuf {
+    img_path.with_extension("squashfs")
+}
+
+fn mount_squashfs_image(image_path: &Path, target: &Path) -> Result<()> {
+    ensure_dir_exists(target)?;
+
+    let record = loopdev::mount_image(
+        image_path,
+        target,
+        c"squashfs",
+        MountFlags::RDONLY | MountFlags::NODEV | MountFlags::NOATIME,
+        None,
+    )?;
+    log::info!(
+        "SquashFS image mounted via {} (loop{})",
+        record.device.display(),
+        record.number
+    );
+
+    if fs::read_dir(target)?.next().is_none() {
+        bail!("SquashFS mount success but directory is empty (Loop device failure?)");
+    }
+
+    Ok(())
+}
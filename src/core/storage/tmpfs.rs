@@ -0,0 +1,73 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use super::{
+    StorageBackend, StorageContext, erofs, grow_tmpfs, make_private, squashfs, try_hide,
+    unmount_if_mounted,
+};
+use crate::{conf::config::OverlayMode, sys::mount::mount_tmpfs, utils};
+
+pub struct TmpfsBackend {
+    mount_point: PathBuf,
+    img_path: PathBuf,
+    mount_source: String,
+    disable_umount: bool,
+}
+
+impl TmpfsBackend {
+    pub fn new(ctx: &StorageContext) -> Self {
+        Self {
+            mount_point: ctx.mnt_base.clone(),
+            img_path: ctx.img_path.clone(),
+            mount_source: ctx.mount_source.clone(),
+            disable_umount: ctx.disable_umount,
+        }
+    }
+}
+
+impl StorageBackend for TmpfsBackend {
+    fn mode(&self) -> OverlayMode {
+        OverlayMode::Tmpfs
+    }
+
+    fn is_supported(&self) -> bool {
+        utils::is_overlay_xattr_supported().unwrap_or(false)
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        mount_tmpfs(&self.mount_point, &self.mount_source)?;
+        log::info!("Tmpfs mounted and supports xattrs (CONFIG_TMPFS_XATTR=y).");
+
+        make_private(&self.mount_point);
+        try_hide(&self.mount_point, self.disable_umount);
+
+        let erofs_path = erofs::image_path(&self.img_path);
+        if erofs_path.exists() {
+            let _ = fs::remove_file(&erofs_path);
+            let _ = fs::remove_file(erofs::fingerprint_path(&erofs_path));
+        }
+
+        let squashfs_path = squashfs::image_path(&self.img_path);
+        if squashfs_path.exists() {
+            let _ = fs::remove_file(&squashfs_path);
+        }
+
+        Ok(())
+    }
+
+    fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    fn grow(&mut self, required: u64) -> Result<bool> {
+        grow_tmpfs(&self.mount_point, required)
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        unmount_if_mounted(&self.mount_point)
+    }
+}
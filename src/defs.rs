@@ -1,6 +1,13 @@
 pub const MODULES_IMG_FILE: &str = "/data/adb/meta-hybrid/modules.img";
+pub const MODULE_IMAGES_DIR: &str = "/data/adb/meta-hybrid/module_images/";
+pub const MODULE_VERSIONS_DIR: &str = "/data/adb/meta-hybrid/versions/";
 pub const RUN_DIR: &str = "/data/adb/meta-hybrid/run/";
 pub const STATE_FILE: &str = "/data/adb/meta-hybrid/run/daemon_state.json";
+pub const MAGIC_TREE_FILE: &str = "/data/adb/meta-hybrid/run/magic_tree.json";
+pub const SYNC_PROGRESS_FILE: &str = "/data/adb/meta-hybrid/run/sync_progress.json";
+pub const POACEAE_LEDGER_FILE: &str = "/data/adb/meta-hybrid/run/poaceae_rules.json";
+pub const DAEMON_LOG_FILE: &str = "/data/adb/meta-hybrid/daemon.log";
+pub const BUGREPORT_DIR: &str = "/sdcard/Download";
 pub const DISABLE_FILE_NAME: &str = "disable";
 pub const REMOVE_FILE_NAME: &str = "remove";
 pub const SKIP_MOUNT_FILE_NAME: &str = "skip_mount";
@@ -61,3 +68,23 @@ pub const SENSITIVE_PARTITIONS: &[&str] = &[
 
 pub const REPLACE_DIR_FILE_NAME: &str = ".replace";
 pub const REPLACE_DIR_XATTR: &str = "trusted.overlay.opaque";
+pub const SYNC_MANIFEST_FILE_NAME: &str = ".sync_manifest.json";
+pub const DEDUP_STORE_DIR_NAME: &str = ".store";
+pub const SYNC_JOURNAL_FILE_NAME: &str = ".sync_journal.json";
+
+// module.prop stays, magic mount reads it back from storage.
+pub const SYNC_EXCLUDES: &[&str] = &[
+    "/META-INF",
+    "/webroot",
+    "/zygisk",
+    "/riru",
+    "/common",
+    "/*.sh",
+    "/*.md",
+    "/LICENSE",
+    "/system.prop",
+    "/sepolicy.rule",
+    "/hybrid_rules.json",
+    ".git",
+    ".github",
+];
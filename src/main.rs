@@ -52,14 +52,16 @@ fn load_final_config(cli: &Cli) -> Result<Config> {
         cli.moduledir.clone(),
         cli.mountsource.clone(),
         cli.partitions.clone(),
+        cli.root.clone(),
     );
     Ok(config)
 }
 
 fn main() -> Result<()> {
     // [Change] Create RUN_DIR immediately as it now hosts critical state files (boot_counter)
-    utils::ensure_dir_exists(defs::RUN_DIR)
-        .with_context(|| format!("Failed to create run directory: {}", defs::RUN_DIR))?;
+    // Subcommands may run off-device (e.g. --replay), so only the daemon treats failure as fatal.
+    let run_dir_ready = utils::ensure_dir_exists(defs::RUN_DIR)
+        .with_context(|| format!("Failed to create run directory: {}", defs::RUN_DIR));
 
     let threads = std::thread::available_parallelism()
         .map(|n| n.get())
@@ -80,14 +82,32 @@ fn main() -> Result<()> {
                 cli_handlers::handle_save_module_rules(module, payload)?
             }
             Commands::Modules => cli_handlers::handle_modules(&cli)?,
+            Commands::Plan => cli_handlers::handle_plan(&cli)?,
             Commands::Conflicts => cli_handlers::handle_conflicts(&cli)?,
             Commands::Diagnostics => cli_handlers::handle_diagnostics(&cli)?,
-            Commands::Poaceae { target, action } => cli_handlers::handle_poaceae(target, action)?,
+            Commands::Bake { out } => cli_handlers::handle_bake(&cli, out)?,
+            Commands::Capture { out, full } => cli_handlers::handle_capture(&cli, out, *full)?,
+            Commands::MagicTree { json } => cli_handlers::handle_magic_tree(*json)?,
+            Commands::Bugreport { out } => cli_handlers::handle_bugreport(&cli, out.as_deref())?,
+            Commands::RebuildImage => cli_handlers::handle_rebuild_image()?,
+            Commands::StorageStats => cli_handlers::handle_storage_stats()?,
+            Commands::VerifyStorage => cli_handlers::handle_verify_storage(&cli)?,
+            Commands::Gc { dry_run } => cli_handlers::handle_gc(&cli, *dry_run)?,
+            Commands::Rollback { module, to } => {
+                cli_handlers::handle_rollback(&cli, module, to.as_deref())?
+            }
+            Commands::Poaceae {
+                target,
+                fake,
+                action,
+            } => cli_handlers::handle_poaceae(target, *fake, action)?,
         }
 
         return Ok(());
     }
 
+    run_dir_ready?;
+
     let mut config = load_final_config(&cli)?;
 
     if utils::check_zygisksu_enforce_status() {
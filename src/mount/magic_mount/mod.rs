@@ -3,10 +3,10 @@
 mod utils;
 
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
-    sync::atomic::AtomicU32,
+    sync::{LazyLock, Mutex, atomic::AtomicU32},
 };
 
 use anyhow::{Context, Result, bail};
@@ -18,15 +18,26 @@ use rustix::mount::{
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use crate::mount::umount_mgr::{self, send_umountable};
 use crate::{
+    defs,
     mount::{
-        magic_mount::utils::{clone_symlink, collect_module_files, mount_mirror},
-        node::{Node, NodeFileType},
+        magic_mount::utils::{clone_symlink, mount_mirror},
+        node::{MountOutcome, Node, NodeFileType},
     },
-    utils::ensure_dir_exists,
+    utils::{atomic_write, ensure_dir_exists},
 };
 
+pub use self::utils::collect_module_files;
+
 static MOUNTED_FILES: AtomicU32 = AtomicU32::new(0);
 static MOUNTED_SYMBOLS_FILES: AtomicU32 = AtomicU32::new(0);
+static OUTCOMES: LazyLock<Mutex<HashMap<PathBuf, MountOutcome>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn record(path: &Path, outcome: MountOutcome) {
+    if let Ok(mut outcomes) = OUTCOMES.lock() {
+        outcomes.insert(path.to_path_buf(), outcome);
+    }
+}
 
 struct MagicMount {
     node: Node,
@@ -65,6 +76,7 @@ impl MagicMount {
             NodeFileType::Directory => self.directory(),
             NodeFileType::Whiteout => {
                 log::debug!("file {} is removed", self.path.display());
+                record(&self.path, MountOutcome::Whiteout);
                 Ok(())
             }
         }
@@ -88,6 +100,7 @@ impl MagicMount {
             })?;
             let mounted = MOUNTED_SYMBOLS_FILES.load(std::sync::atomic::Ordering::Relaxed) + 1;
             MOUNTED_SYMBOLS_FILES.store(mounted, std::sync::atomic::Ordering::Relaxed);
+            record(&self.path, MountOutcome::Symlink);
             Ok(())
         } else {
             bail!("cannot mount root symlink {}!", self.path.display());
@@ -132,6 +145,7 @@ impl MagicMount {
 
         let mounted = MOUNTED_FILES.load(std::sync::atomic::Ordering::Relaxed) + 1;
         MOUNTED_FILES.store(mounted, std::sync::atomic::Ordering::Relaxed);
+        record(&self.path, MountOutcome::Bind);
         Ok(())
     }
 
@@ -162,6 +176,7 @@ impl MagicMount {
                             self.path.display()
                         );
                         node.skip = true;
+                        record(&real_path, MountOutcome::Skipped);
                         continue;
                     }
                     tmpfs = true;
@@ -173,6 +188,7 @@ impl MagicMount {
 
         if has_tmpfs {
             utils::tmpfs_skeleton(&self.path, &self.work_dir_path, &self.node)?;
+            record(&self.path, MountOutcome::Tmpfs);
         }
 
         if tmpfs {
@@ -223,6 +239,7 @@ impl MagicMount {
                 }
 
                 log::error!("mount child {}/{name} failed: {e:#?}", self.path.display());
+                record(&self.path.join(name), MountOutcome::Failed);
             }
         }
 
@@ -281,6 +298,7 @@ impl MagicMount {
                     .do_mount()
                     .with_context(|| format!("magic mount {}/{name}", self.path.display()))
                 } else if has_tmpfs {
+                    record(&self.path.join(&name), MountOutcome::Mirror);
                     mount_mirror(&self.path, &self.work_dir_path, &entry)
                         .with_context(|| format!("mount mirror {}/{name}", self.path.display()))
                 } else {
@@ -293,6 +311,7 @@ impl MagicMount {
                     return Err(e);
                 }
                 log::error!("mount child {}/{name} failed: {e:#?}", self.path.display());
+                record(&self.path.join(name), MountOutcome::Failed);
             }
         }
 
@@ -300,9 +319,23 @@ impl MagicMount {
     }
 }
 
+fn save_tree(root: &Node, root_dir: &Path) {
+    let outcomes = OUTCOMES.lock().map(|o| o.clone()).unwrap_or_default();
+    let snapshot = root.snapshot(root_dir, &outcomes);
+
+    let result = serde_json::to_string_pretty(&snapshot)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| atomic_write(defs::MAGIC_TREE_FILE, json));
+
+    if let Err(e) = result {
+        log::warn!("Failed to save magic mount tree: {:#}", e);
+    }
+}
+
 pub fn magic_mount<P>(
     tmp_path: P,
-    module_dir: &Path,
+    module_paths: &BTreeMap<String, PathBuf>,
+    root_dir: &Path,
     mount_source: &str,
     extra_partitions: &[String],
     need_id: HashSet<String>,
@@ -312,7 +345,7 @@ pub fn magic_mount<P>(
 where
     P: AsRef<Path>,
 {
-    if let Some(root) = collect_module_files(module_dir, extra_partitions, need_id)? {
+    if let Some(root) = collect_module_files(module_paths, root_dir, extra_partitions, need_id)? {
         log::debug!("collected: {root:?}");
         let tmp_root = tmp_path.as_ref();
         let tmp_dir = tmp_root.join("workdir");
@@ -323,7 +356,7 @@ where
 
         let ret = MagicMount::new(
             &root,
-            Path::new("/"),
+            root_dir,
             tmp_dir.as_path(),
             false,
             #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -338,6 +371,8 @@ where
         umount_mgr::commit()?;
         fs::remove_dir(tmp_dir).ok();
 
+        save_tree(&root, root_dir);
+
         let mounted_symbols = MOUNTED_SYMBOLS_FILES.load(std::sync::atomic::Ordering::Relaxed);
         let mounted_files = MOUNTED_FILES.load(std::sync::atomic::Ordering::Relaxed);
         log::info!("mounted files: {mounted_files}, mounted symlinks: {mounted_symbols}");
@@ -1,7 +1,7 @@
 // Copyright 2026 https://github.com/Tools-cx-app/meta-magic_mount
 
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     fs::{self, DirEntry, Metadata, create_dir, create_dir_all, read_link},
     os::unix::fs::{MetadataExt, symlink},
     path::{Path, PathBuf},
@@ -106,31 +106,30 @@ where
 }
 
 pub fn collect_module_files(
-    module_dir: &Path,
+    module_paths: &BTreeMap<String, PathBuf>,
+    root_dir: &Path,
     extra_partitions: &[String],
     need_id: HashSet<String>,
 ) -> Result<Option<Node>> {
     let mut root = Node::new_root("");
     let mut system = Node::new_root("system");
-    let module_root = module_dir;
     let mut has_file = HashSet::new();
 
-    log::debug!("begin collect module files: {}", module_root.display());
+    log::debug!("begin collect module files");
 
-    for entry in module_root.read_dir()?.flatten() {
-        if !entry.file_type()?.is_dir() {
+    for (id, module_path) in module_paths {
+        if !module_path.is_dir() {
             continue;
         }
 
-        let id = entry.file_name().to_str().unwrap().to_string();
         log::debug!("processing new module: {id}");
 
-        if !need_id.contains(&id) {
+        if !need_id.contains(id) {
             log::debug!("module {id} was blocked.");
             continue;
         }
 
-        let prop = entry.path().join("module.prop");
+        let prop = module_path.join("module.prop");
         if !prop.exists() {
             log::debug!("skipped module {id}, because not found module.prop");
             continue;
@@ -144,9 +143,9 @@ pub fn collect_module_files(
             }
         }
 
-        if entry.path().join(DISABLE_FILE_NAME).exists()
-            || entry.path().join(REMOVE_FILE_NAME).exists()
-            || entry.path().join(SKIP_MOUNT_FILE_NAME).exists()
+        if module_path.join(DISABLE_FILE_NAME).exists()
+            || module_path.join(REMOVE_FILE_NAME).exists()
+            || module_path.join(SKIP_MOUNT_FILE_NAME).exists()
         {
             log::debug!("skipped module {id}, due to disable/remove/skip_mount");
             continue;
@@ -158,7 +157,7 @@ pub fn collect_module_files(
         partitions.extend(extra_partitions.iter().cloned());
 
         for p in &partitions {
-            if entry.path().join(p).is_dir() {
+            if module_path.join(p).is_dir() {
                 modified = true;
                 break;
             }
@@ -169,14 +168,14 @@ pub fn collect_module_files(
             continue;
         }
 
-        log::debug!("collecting {}", entry.path().display());
+        log::debug!("collecting {}", module_path.display());
 
         for p in partitions {
-            if !entry.path().join(&p).exists() {
+            if !module_path.join(&p).exists() {
                 continue;
             }
 
-            has_file.insert(system.collect_module_files(entry.path().join(&p))?);
+            has_file.insert(system.collect_module_files(module_path.join(&p), id)?);
         }
     }
 
@@ -189,8 +188,8 @@ pub fn collect_module_files(
         ];
 
         for (partition, require_symlink) in BUILTIN_PARTITIONS {
-            let path_of_root = Path::new("/").join(partition);
-            let path_of_system = Path::new("/system").join(partition);
+            let path_of_root = root_dir.join(partition);
+            let path_of_system = root_dir.join("system").join(partition);
             if path_of_root.is_dir() && (!require_symlink || path_of_system.is_symlink()) {
                 let name = partition.to_string();
                 if let Some(node) = system.children.remove(&name) {
@@ -207,8 +206,8 @@ pub fn collect_module_files(
                 continue;
             }
 
-            let path_of_root = Path::new("/").join(partition);
-            let path_of_system = Path::new("/system").join(partition);
+            let path_of_root = root_dir.join(partition);
+            let path_of_system = root_dir.join("system").join(partition);
             let require_symlink = false;
 
             if path_of_root.is_dir() && (!require_symlink || path_of_system.is_symlink()) {
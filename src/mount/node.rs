@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use std::{
-    collections::{HashMap, hash_map::Entry},
+    collections::{BTreeMap, HashMap, hash_map::Entry},
     fmt,
     fs::{DirEntry, FileType},
     os::unix::fs::{FileTypeExt, MetadataExt},
@@ -11,10 +11,12 @@ use std::{
 
 use anyhow::Result;
 use extattr::lgetxattr;
+use serde::{Deserialize, Serialize};
 
-use crate::defs::{REPLACE_DIR_FILE_NAME, REPLACE_DIR_XATTR};
+use crate::defs::{MAGIC_TREE_FILE, REPLACE_DIR_FILE_NAME, REPLACE_DIR_XATTR};
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum NodeFileType {
     RegularFile,
     Directory,
@@ -36,6 +38,18 @@ impl From<FileType> for NodeFileType {
     }
 }
 
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MountOutcome {
+    Bind,
+    Symlink,
+    Tmpfs,
+    Mirror,
+    Whiteout,
+    Skipped,
+    Failed,
+}
+
 #[derive(Debug, Clone)]
 pub struct Node {
     pub name: String,
@@ -43,6 +57,7 @@ pub struct Node {
     pub children: HashMap<String, Self>,
     // the module that owned this node
     pub module_path: Option<PathBuf>,
+    pub module_id: Option<String>,
     pub replace: bool,
     pub skip: bool,
 }
@@ -51,13 +66,113 @@ impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "u need to send '/data/adb/magic_mount/tree' to developer "
+            "u need to send '{}' to developer (or run `meta-hybrid magic-tree`)",
+            MAGIC_TREE_FILE
         )
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeSnapshot {
+    pub name: String,
+    pub file_type: NodeFileType,
+    pub module: Option<String>,
+    pub module_path: Option<PathBuf>,
+    pub replace: bool,
+    pub skip: bool,
+    pub outcome: Option<MountOutcome>,
+    pub children: BTreeMap<String, Self>,
+}
+
+impl NodeSnapshot {
+    fn mirror(name: &str, path: &Path) -> Self {
+        let file_type = path
+            .symlink_metadata()
+            .map(|m| NodeFileType::from(m.file_type()))
+            .unwrap_or(NodeFileType::RegularFile);
+
+        Self {
+            name: name.to_string(),
+            file_type,
+            module: None,
+            module_path: None,
+            replace: false,
+            skip: false,
+            outcome: Some(MountOutcome::Mirror),
+            children: BTreeMap::new(),
+        }
+    }
+
+    fn fmt_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let name = if self.name.is_empty() {
+            "/"
+        } else {
+            &self.name
+        };
+        write!(f, "{}{} [{:?}]", "  ".repeat(depth), name, self.file_type)?;
+
+        if let Some(outcome) = self.outcome {
+            write!(f, " {:?}", outcome)?;
+        }
+        if let Some(module) = &self.module {
+            write!(f, " <{}>", module)?;
+        }
+        if self.replace {
+            write!(f, " (replace)")?;
+        }
+        if self.skip {
+            write!(f, " (skip)")?;
+        }
+        writeln!(f)?;
+
+        for child in self.children.values() {
+            child.fmt_tree(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for NodeSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_tree(f, 0)
+    }
+}
+
 impl Node {
-    pub fn collect_module_files<P>(&mut self, module_dir: P) -> Result<bool>
+    pub fn snapshot(&self, path: &Path, outcomes: &HashMap<PathBuf, MountOutcome>) -> NodeSnapshot {
+        let mut children: BTreeMap<String, NodeSnapshot> = self
+            .children
+            .iter()
+            .map(|(name, child)| (name.clone(), child.snapshot(&path.join(name), outcomes)))
+            .collect();
+
+        for (mirror_path, outcome) in outcomes {
+            if *outcome == MountOutcome::Mirror
+                && mirror_path.parent() == Some(path)
+                && let Some(name) = mirror_path.file_name()
+            {
+                let name = name.to_string_lossy().to_string();
+                children
+                    .entry(name.clone())
+                    .or_insert_with(|| NodeSnapshot::mirror(&name, mirror_path));
+            }
+        }
+
+        let outcome = outcomes.get(path).copied();
+
+        NodeSnapshot {
+            name: self.name.clone(),
+            file_type: self.file_type.clone(),
+            module: self.module_id.clone(),
+            module_path: self.module_path.clone(),
+            replace: self.replace,
+            skip: self.skip || outcome == Some(MountOutcome::Skipped),
+            outcome,
+            children,
+        }
+    }
+
+    pub fn collect_module_files<P>(&mut self, module_dir: P, module_id: &str) -> Result<bool>
     where
         P: AsRef<Path>,
     {
@@ -68,12 +183,14 @@ impl Node {
 
             let node = match self.children.entry(name.clone()) {
                 Entry::Occupied(o) => Some(o.into_mut()),
-                Entry::Vacant(v) => Self::new_module(&name, &entry).map(|it| v.insert(it)),
+                Entry::Vacant(v) => {
+                    Self::new_module(&name, &entry, module_id).map(|it| v.insert(it))
+                }
             };
 
             if let Some(node) = node {
                 has_file |= if node.file_type == NodeFileType::Directory {
-                    node.collect_module_files(dir.join(&node.name))? || node.replace
+                    node.collect_module_files(dir.join(&node.name), module_id)? || node.replace
                 } else {
                     true
                 }
@@ -83,7 +200,7 @@ impl Node {
         Ok(has_file)
     }
 
-    fn dir_is_replace<P>(path: P) -> bool
+    pub fn dir_is_replace<P>(path: P) -> bool
     where
         P: AsRef<Path>,
     {
@@ -105,12 +222,13 @@ impl Node {
             file_type: NodeFileType::Directory,
             children: HashMap::default(),
             module_path: None,
+            module_id: None,
             replace: false,
             skip: false,
         }
     }
 
-    pub fn new_module<S>(name: &S, entry: &DirEntry) -> Option<Self>
+    pub fn new_module<S>(name: &S, entry: &DirEntry, module_id: &str) -> Option<Self>
     where
         S: ToString,
     {
@@ -131,6 +249,7 @@ impl Node {
                     file_type,
                     children: HashMap::default(),
                     module_path: Some(path),
+                    module_id: Some(module_id.to_string()),
                     replace,
                     skip: false,
                 });
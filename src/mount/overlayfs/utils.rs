@@ -1,12 +1,15 @@
 // Copyright 2026 https://github.com/KernelSU-Modules-Repo/meta-overlayfs and https://github.com/bmax121/APatch
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
-use std::{fs, os::unix::fs::PermissionsExt, path::Path, process::Command};
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result};
 #[cfg(any(target_os = "linux", target_os = "android"))]
-use rustix::mount::{UnmountFlags, unmount};
+use rustix::mount::{MountFlags, UnmountFlags, unmount};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::sys::loopdev;
 
 pub struct AutoMountExt4 {
     target: String,
@@ -72,16 +75,18 @@ impl Drop for AutoMountExt4 {
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn mount_ext4(source: impl AsRef<Path>, target: impl AsRef<Path>) -> Result<()> {
-    let status = Command::new("mount")
-        .args(["-t", "ext4", "-o", "loop,rw,noatime"])
-        .arg(source.as_ref())
-        .arg(target.as_ref())
-        .status()
-        .context("Failed to execute mount command")?;
-
-    if !status.success() {
-        return Err(anyhow!("Mount command failed"));
-    }
+    let record = loopdev::mount_image(
+        source.as_ref(),
+        target.as_ref(),
+        c"ext4",
+        MountFlags::NOATIME,
+        None,
+    )?;
+    log::info!(
+        "Ext4 image mounted via {} (loop{})",
+        record.device.display(),
+        record.number
+    );
     Ok(())
 }
 
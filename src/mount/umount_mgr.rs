@@ -10,6 +10,7 @@ use ksu::TryUmount;
 pub static TMPFS: OnceLock<String> = OnceLock::new();
 pub static LIST: LazyLock<Mutex<TryUmount>> = LazyLock::new(|| Mutex::new(TryUmount::new()));
 static HISTORY: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+static REGISTERED: LazyLock<Mutex<Vec<String>>> = LazyLock::new(|| Mutex::new(Vec::new()));
 
 pub fn send_umountable<P>(target: P) -> Result<()>
 where
@@ -29,13 +30,26 @@ where
         return Ok(());
     }
 
-    history.insert(path_str);
+    history.insert(path_str.clone());
+    // Kept across commits, which clear the history so later requests can be sent again.
+    if let Ok(mut registered) = REGISTERED.lock()
+        && !registered.contains(&path_str)
+    {
+        registered.push(path_str);
+    }
     LIST.lock()
         .map_err(|_| anyhow::anyhow!("Failed to lock umount list"))?
         .add(target);
     Ok(())
 }
 
+pub fn registered() -> Vec<String> {
+    REGISTERED
+        .lock()
+        .map(|registered| registered.clone())
+        .unwrap_or_default()
+}
+
 pub fn commit() -> Result<()> {
     if !crate::utils::KSU.load(std::sync::atomic::Ordering::Relaxed) {
         return Ok(());
@@ -0,0 +1,42 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub kernel_release: String,
+    pub filesystems: Vec<String>,
+    pub tmpfs_xattr: bool,
+    pub ksu: bool,
+}
+
+impl Capabilities {
+    pub fn probe() -> Self {
+        let kernel_release = fs::read_to_string("/proc/sys/kernel/osrelease")
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default();
+
+        let filesystems = fs::read_to_string("/proc/filesystems")
+            .map(|content| {
+                content
+                    .lines()
+                    .filter_map(|line| line.split_whitespace().last())
+                    .map(|s| s.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            kernel_release,
+            filesystems,
+            tmpfs_xattr: utils::is_overlay_xattr_supported().unwrap_or(false),
+            ksu: ksu::version().is_some(),
+        }
+    }
+
+    pub fn supports_fs(&self, name: &str) -> bool {
+        self.filesystems.iter().any(|fs| fs == name)
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::{Context, Result, bail};
+use nix::{errno::Errno, ioctl_none_bad, ioctl_write_int_bad, ioctl_write_ptr_bad};
+use rustix::mount::{MountFlags, mount};
+use serde::{Deserialize, Serialize};
+
+const LOOP_CONTROL: &str = "/dev/loop-control";
+const LOOP_DEVICE_DIRS: &[&str] = &["/dev/block", "/dev"];
+
+const LOOP_SET_FD: u32 = 0x4C00;
+const LOOP_CLR_FD: u32 = 0x4C01;
+const LOOP_SET_STATUS64: u32 = 0x4C04;
+const LOOP_CONFIGURE: u32 = 0x4C0A;
+const LOOP_CTL_GET_FREE: u32 = 0x4C82;
+
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+
+const ATTACH_RETRIES: usize = 5;
+
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+const _: () = assert!(std::mem::size_of::<LoopInfo64>() == 232);
+const _: () = assert!(std::mem::size_of::<LoopConfig>() == 304);
+
+ioctl_none_bad!(loop_ctl_get_free, LOOP_CTL_GET_FREE);
+ioctl_write_int_bad!(loop_set_fd, LOOP_SET_FD);
+ioctl_none_bad!(loop_clr_fd, LOOP_CLR_FD);
+ioctl_write_ptr_bad!(loop_set_status64, LOOP_SET_STATUS64, LoopInfo64);
+ioctl_write_ptr_bad!(loop_configure, LOOP_CONFIGURE, LoopConfig);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoopRecord {
+    pub number: u32,
+    pub device: PathBuf,
+    pub backing: PathBuf,
+    pub mount_point: PathBuf,
+}
+
+static ATTACHED: LazyLock<Mutex<Vec<LoopRecord>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+pub struct LoopDevice {
+    pub number: u32,
+    pub path: PathBuf,
+    device: File,
+}
+
+impl LoopDevice {
+    pub fn attach(image: &Path, read_only: bool) -> Result<Self> {
+        let backing = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(image)
+            .with_context(|| format!("Failed to open loop backing file {}", image.display()))?;
+
+        let control =
+            File::open(LOOP_CONTROL).with_context(|| format!("Failed to open {}", LOOP_CONTROL))?;
+
+        let mut last_err = Errno::EBUSY;
+        for _ in 0..ATTACH_RETRIES {
+            let number = unsafe { loop_ctl_get_free(control.as_raw_fd()) }
+                .context("LOOP_CTL_GET_FREE failed")? as u32;
+
+            let path = device_path(number)?;
+            let device = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+
+            match configure(&device, &backing, image, read_only) {
+                Ok(()) => {
+                    log::debug!("Attached {} to {}", image.display(), path.display());
+                    return Ok(Self {
+                        number,
+                        path,
+                        device,
+                    });
+                }
+                // Another process grabbed the same free device first.
+                Err(Errno::EBUSY) => last_err = Errno::EBUSY,
+                Err(e) => {
+                    bail!(
+                        "Failed to bind {} to {}: {}",
+                        image.display(),
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+
+        bail!(
+            "Failed to find a free loop device for {}: {}",
+            image.display(),
+            last_err
+        )
+    }
+
+    pub fn detach(self) -> Result<()> {
+        unsafe { loop_clr_fd(self.device.as_raw_fd()) }
+            .with_context(|| format!("LOOP_CLR_FD failed on {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+fn device_path(number: u32) -> Result<PathBuf> {
+    LOOP_DEVICE_DIRS
+        .iter()
+        .map(|dir| Path::new(dir).join(format!("loop{}", number)))
+        .find(|path| path.exists())
+        .with_context(|| format!("Device node for loop{} not found", number))
+}
+
+fn configure(device: &File, backing: &File, image: &Path, read_only: bool) -> nix::Result<()> {
+    let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+    info.lo_flags = LO_FLAGS_AUTOCLEAR | if read_only { LO_FLAGS_READ_ONLY } else { 0 };
+
+    let name = image.as_os_str().as_bytes();
+    let len = name.len().min(info.lo_file_name.len() - 1);
+    info.lo_file_name[..len].copy_from_slice(&name[..len]);
+
+    let config = LoopConfig {
+        fd: backing.as_raw_fd() as u32,
+        block_size: 0,
+        info,
+        reserved: [0; 8],
+    };
+
+    match unsafe { loop_configure(device.as_raw_fd(), &config) } {
+        Ok(_) => Ok(()),
+        // LOOP_CONFIGURE arrived in 5.8, older kernels need SET_FD + SET_STATUS64.
+        Err(Errno::EINVAL | Errno::ENOTTY) => {
+            unsafe { loop_set_fd(device.as_raw_fd(), backing.as_raw_fd()) }?;
+
+            // SET_STATUS64 cannot change READ_ONLY, it follows the backing fd mode.
+            if let Err(e) = unsafe { loop_set_status64(device.as_raw_fd(), &config.info) } {
+                let _ = unsafe { loop_clr_fd(device.as_raw_fd()) };
+                return Err(e);
+            }
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub fn mount_image(
+    image: &Path,
+    target: &Path,
+    fstype: &CStr,
+    flags: MountFlags,
+    data: Option<&CStr>,
+) -> Result<LoopRecord> {
+    let read_only = flags.contains(MountFlags::RDONLY);
+    let device = LoopDevice::attach(image, read_only)?;
+
+    if let Err(e) = mount(&device.path, target, fstype, flags, data) {
+        let path = device.path.clone();
+        if let Err(detach_err) = device.detach() {
+            log::warn!("{:#}", detach_err);
+        }
+        bail!(
+            "Failed to mount {} ({}) on {} as {}: {}",
+            image.display(),
+            path.display(),
+            target.display(),
+            fstype.to_string_lossy(),
+            e
+        );
+    }
+
+    // Autoclear releases the device once the mount is gone and our fd is closed.
+    let record = LoopRecord {
+        number: device.number,
+        device: device.path.clone(),
+        backing: image.to_path_buf(),
+        mount_point: target.to_path_buf(),
+    };
+
+    if let Ok(mut attached) = ATTACHED.lock() {
+        attached.push(record.clone());
+    }
+
+    Ok(record)
+}
+
+pub fn attached() -> Vec<LoopRecord> {
+    ATTACHED
+        .lock()
+        .map(|attached| attached.clone())
+        .unwrap_or_default()
+}
@@ -1,3 +1,5 @@
+pub mod caps;
+pub mod loopdev;
 pub mod mount;
 pub mod nuke;
 pub mod poaceae;
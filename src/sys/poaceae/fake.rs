@@ -0,0 +1,252 @@
+// Copyright 2026 Hybrid Mount Developers
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{
+    cell::RefCell,
+    collections::btree_map::Entry,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+
+use super::{Device, Request, decode, decode_payload};
+use crate::{
+    conf::config::{PoaceaeRules, SpoofRule},
+    utils,
+};
+
+/// Userspace stand-in for PoaceaeFS.
+///
+/// Requests are decoded from the same buffers the kernel receives and answered with the errno
+/// it would return: `EINVAL` for malformed arguments, `EEXIST` when adding a rule for a path
+/// that has one, `ENOENT` when deleting one that does not. Rules are kept in a JSON file so
+/// separate CLI invocations see the same filesystem.
+pub struct FakePoaceae {
+    path: PathBuf,
+    rules: RefCell<PoaceaeRules>,
+}
+
+impl FakePoaceae {
+    /// Opens the state at `path`, a missing or empty file being a freshly mounted filesystem.
+    pub fn open(path: &Path) -> Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read PoaceaeFS state {}", path.display()));
+            }
+        };
+        let rules = if content.trim().is_empty() {
+            PoaceaeRules::default()
+        } else {
+            serde_json::from_str(&content).context("Failed to parse PoaceaeFS state")?
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            rules: RefCell::new(rules),
+        })
+    }
+}
+
+impl Device for FakePoaceae {
+    fn submit(&self, request: &Request) -> Result<()> {
+        let mut rules = self.rules.borrow_mut();
+        handle(&mut rules, request)?;
+
+        let content = serde_json::to_string_pretty(&*rules)?;
+        utils::atomic_write(&self.path, content).context("Failed to write PoaceaeFS state")
+    }
+
+    fn list(&self) -> Option<PoaceaeRules> {
+        Some(self.rules.borrow().clone())
+    }
+}
+
+fn handle(rules: &mut PoaceaeRules, request: &Request) -> Result<(), Errno> {
+    let name = |buf: &[u8]| {
+        decode(buf)
+            .ok()
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .ok_or(Errno::EINVAL)
+    };
+    let payload = |buf: &[u8]| {
+        decode_payload(buf)
+            .ok()
+            .filter(|(src, dst)| !src.is_empty() && !dst.is_empty())
+            .map(|(src, dst)| (src.to_string(), dst.to_string()))
+            .ok_or(Errno::EINVAL)
+    };
+
+    match request {
+        Request::AddHide(buf) => {
+            if !rules.hide.insert(name(buf)?) {
+                return Err(Errno::EEXIST);
+            }
+        }
+        Request::DelHide(buf) => {
+            if !rules.hide.remove(&name(buf)?) {
+                return Err(Errno::ENOENT);
+            }
+        }
+        Request::AddRedirect(buf) => {
+            let (src, dst) = payload(buf)?;
+            match rules.redirect.entry(src) {
+                Entry::Occupied(_) => return Err(Errno::EEXIST),
+                Entry::Vacant(entry) => {
+                    entry.insert(dst);
+                }
+            }
+        }
+        Request::DelRedirect(buf) => {
+            rules.redirect.remove(&name(buf)?).ok_or(Errno::ENOENT)?;
+        }
+        Request::AddSpoof(args) => {
+            let rule = SpoofRule {
+                uid: args.uid,
+                gid: args.gid,
+                mode: args.mode,
+                mtime: args.mtime,
+            };
+            match rules.spoof.entry(name(&args.name)?) {
+                Entry::Occupied(_) => return Err(Errno::EEXIST),
+                Entry::Vacant(entry) => {
+                    entry.insert(rule);
+                }
+            }
+        }
+        Request::DelSpoof(buf) => {
+            rules.spoof.remove(&name(buf)?).ok_or(Errno::ENOENT)?;
+        }
+        Request::AddMerge(buf) => {
+            let (src, dst) = payload(buf)?;
+            match rules.merge.entry(src) {
+                Entry::Occupied(_) => return Err(Errno::EEXIST),
+                Entry::Vacant(entry) => {
+                    entry.insert(dst);
+                }
+            }
+        }
+        Request::DelMerge(buf) => {
+            rules.merge.remove(&name(buf)?).ok_or(Errno::ENOENT)?;
+        }
+        Request::SetTrustedGid(gid) => rules.trusted_gid = Some(*gid),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys::poaceae;
+
+    struct State(PathBuf);
+
+    impl State {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "poaceae-fake-{}-{}.json",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for State {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn errno(result: Result<()>) -> Option<Errno> {
+        result.err()?.downcast_ref::<Errno>().copied()
+    }
+
+    #[test]
+    fn adding_a_rule_twice_is_eexist() {
+        let state = State::new("eexist");
+        let dev = FakePoaceae::open(&state.0).unwrap();
+
+        poaceae::hide(&dev, "/system/xbin/su").unwrap();
+        assert_eq!(
+            errno(poaceae::hide(&dev, "/system/xbin/su")),
+            Some(Errno::EEXIST)
+        );
+
+        poaceae::redirect(&dev, "/system/etc/hosts", "/data/hosts").unwrap();
+        assert_eq!(
+            errno(poaceae::redirect(&dev, "/system/etc/hosts", "/data/other")),
+            Some(Errno::EEXIST)
+        );
+
+        poaceae::spoof(&dev, "/system/bin/app", 0, 2000, 0o755, 0).unwrap();
+        assert_eq!(
+            errno(poaceae::spoof(&dev, "/system/bin/app", 0, 0, 0o644, 0)),
+            Some(Errno::EEXIST)
+        );
+
+        poaceae::merge(&dev, "/system/fonts", "/data/fonts").unwrap();
+        assert_eq!(
+            errno(poaceae::merge(&dev, "/system/fonts", "/data/fonts")),
+            Some(Errno::EEXIST)
+        );
+
+        // The trusted gid is replaced, not added.
+        poaceae::set_trust(&dev, 3005).unwrap();
+        poaceae::set_trust(&dev, 3006).unwrap();
+        assert_eq!(dev.list().unwrap().trusted_gid, Some(3006));
+    }
+
+    #[test]
+    fn removing_a_missing_rule_is_enoent() {
+        let state = State::new("enoent");
+        let dev = FakePoaceae::open(&state.0).unwrap();
+
+        assert_eq!(errno(poaceae::unhide(&dev, "/a")), Some(Errno::ENOENT));
+        assert_eq!(errno(poaceae::unredirect(&dev, "/a")), Some(Errno::ENOENT));
+        assert_eq!(errno(poaceae::unspoof(&dev, "/a")), Some(Errno::ENOENT));
+        assert_eq!(errno(poaceae::unmerge(&dev, "/a")), Some(Errno::ENOENT));
+
+        poaceae::hide(&dev, "/a").unwrap();
+        poaceae::unhide(&dev, "/a").unwrap();
+        assert_eq!(errno(poaceae::unhide(&dev, "/a")), Some(Errno::ENOENT));
+    }
+
+    #[test]
+    fn empty_arguments_are_einval() {
+        let state = State::new("einval");
+        let dev = FakePoaceae::open(&state.0).unwrap();
+
+        assert_eq!(errno(poaceae::hide(&dev, "")), Some(Errno::EINVAL));
+        assert_eq!(
+            errno(poaceae::redirect(&dev, "", "/x")),
+            Some(Errno::EINVAL)
+        );
+        assert_eq!(errno(poaceae::merge(&dev, "/x", "")), Some(Errno::EINVAL));
+        assert!(dev.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rules_persist_across_opens() {
+        let state = State::new("persist");
+        let dev = FakePoaceae::open(&state.0).unwrap();
+        poaceae::hide(&dev, "/system/xbin/su").unwrap();
+        poaceae::merge(&dev, "/system/fonts", "/data/fonts").unwrap();
+        let before = dev.list().unwrap();
+        drop(dev);
+
+        let dev = FakePoaceae::open(&state.0).unwrap();
+        assert_eq!(dev.list().unwrap(), before);
+        assert_eq!(
+            errno(poaceae::hide(&dev, "/system/xbin/su")),
+            Some(Errno::EEXIST)
+        );
+    }
+}
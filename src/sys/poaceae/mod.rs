@@ -0,0 +1,233 @@
+// Copyright 2026 Hybrid Mount Developers
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+mod fake;
+
+use std::{fs::File, os::unix::io::AsRawFd, path::Path};
+
+use anyhow::{Context, Result, bail};
+use nix::ioctl_write_ptr;
+
+pub use self::fake::FakePoaceae;
+use crate::conf::config::PoaceaeRules;
+
+const MAGIC: u8 = 0x43;
+
+/// Size of a name argument, NUL terminator included.
+pub const NAME_LEN: usize = 256;
+/// Size of a `src|dst` payload argument, NUL terminator included.
+pub const PAYLOAD_LEN: usize = 512;
+
+#[repr(C)]
+pub struct IoctlSpoofArgs {
+    pub name: [u8; NAME_LEN],
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub mtime: u64,
+}
+
+const _: () = assert!(std::mem::size_of::<IoctlSpoofArgs>() == 256 + 4 + 4 + 2 + 8 + 6);
+
+ioctl_write_ptr!(add_hide, MAGIC, 1, [u8; NAME_LEN]);
+ioctl_write_ptr!(del_hide, MAGIC, 2, [u8; NAME_LEN]);
+ioctl_write_ptr!(add_redirect, MAGIC, 4, [u8; PAYLOAD_LEN]);
+ioctl_write_ptr!(del_redirect, MAGIC, 5, [u8; NAME_LEN]);
+ioctl_write_ptr!(add_spoof, MAGIC, 7, IoctlSpoofArgs);
+ioctl_write_ptr!(del_spoof, MAGIC, 8, [u8; NAME_LEN]);
+ioctl_write_ptr!(add_merge, MAGIC, 10, [u8; PAYLOAD_LEN]);
+ioctl_write_ptr!(del_merge, MAGIC, 11, [u8; NAME_LEN]);
+ioctl_write_ptr!(set_trusted_gid, MAGIC, 13, u32);
+
+/// A PoaceaeFS ioctl with its argument encoded as the kernel receives it.
+pub enum Request {
+    AddHide([u8; NAME_LEN]),
+    DelHide([u8; NAME_LEN]),
+    AddRedirect([u8; PAYLOAD_LEN]),
+    DelRedirect([u8; NAME_LEN]),
+    AddSpoof(IoctlSpoofArgs),
+    DelSpoof([u8; NAME_LEN]),
+    AddMerge([u8; PAYLOAD_LEN]),
+    DelMerge([u8; NAME_LEN]),
+    SetTrustedGid(u32),
+}
+
+pub trait Device {
+    fn submit(&self, request: &Request) -> Result<()>;
+
+    /// Rules the filesystem reports as active, `None` when it cannot be queried.
+    fn list(&self) -> Option<PoaceaeRules> {
+        None
+    }
+}
+
+impl Device for File {
+    fn submit(&self, request: &Request) -> Result<()> {
+        let fd = self.as_raw_fd();
+        unsafe {
+            match request {
+                Request::AddHide(buf) => add_hide(fd, buf),
+                Request::DelHide(buf) => del_hide(fd, buf),
+                Request::AddRedirect(buf) => add_redirect(fd, buf),
+                Request::DelRedirect(buf) => del_redirect(fd, buf),
+                Request::AddSpoof(args) => add_spoof(fd, args),
+                Request::DelSpoof(buf) => del_spoof(fd, buf),
+                Request::AddMerge(buf) => add_merge(fd, buf),
+                Request::DelMerge(buf) => del_merge(fd, buf),
+                Request::SetTrustedGid(gid) => set_trusted_gid(fd, gid),
+            }
+        }?;
+        Ok(())
+    }
+}
+
+/// Opens the PoaceaeFS root at `target`.
+pub fn open(target: &Path) -> Result<Box<dyn Device>> {
+    let file = File::open(target)
+        .with_context(|| format!("Failed to open PoaceaeFS root at {}", target.display()))?;
+    Ok(Box::new(file))
+}
+
+fn encode<const N: usize>(value: &str, what: &str) -> Result<[u8; N]> {
+    let bytes = value.as_bytes();
+    if bytes.contains(&0) {
+        bail!("{} contains a NUL byte", what);
+    }
+    // The last byte stays NUL, the kernel reads up to it.
+    if bytes.len() >= N {
+        bail!("{} too long", what);
+    }
+
+    let mut buf = [0u8; N];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    Ok(buf)
+}
+
+fn encode_name(name: &str) -> Result<[u8; NAME_LEN]> {
+    encode(name, "Name")
+}
+
+fn encode_payload(src: &str, target: &str) -> Result<[u8; PAYLOAD_LEN]> {
+    // The kernel splits at the first separator, so only the target may contain one.
+    if src.contains('|') {
+        bail!("Source path contains '|'");
+    }
+    encode(&format!("{}|{}", src, target), "Payload")
+}
+
+/// Reads a NUL terminated argument back, as the kernel does.
+pub fn decode(buf: &[u8]) -> Result<&str> {
+    let end = buf
+        .iter()
+        .position(|&b| b == 0)
+        .context("Argument is not NUL terminated")?;
+    std::str::from_utf8(&buf[..end]).context("Argument is not valid UTF-8")
+}
+
+pub fn decode_payload(buf: &[u8]) -> Result<(&str, &str)> {
+    decode(buf)?
+        .split_once('|')
+        .context("Payload has no '|' separator")
+}
+
+pub fn hide(dev: &dyn Device, name: &str) -> Result<()> {
+    dev.submit(&Request::AddHide(encode_name(name)?))
+}
+
+pub fn unhide(dev: &dyn Device, name: &str) -> Result<()> {
+    dev.submit(&Request::DelHide(encode_name(name)?))
+}
+
+pub fn redirect(dev: &dyn Device, src: &str, target: &str) -> Result<()> {
+    dev.submit(&Request::AddRedirect(encode_payload(src, target)?))
+}
+
+pub fn unredirect(dev: &dyn Device, src: &str) -> Result<()> {
+    dev.submit(&Request::DelRedirect(encode_name(src)?))
+}
+
+pub fn spoof(
+    dev: &dyn Device,
+    name: &str,
+    uid: u32,
+    gid: u32,
+    mode: u16,
+    mtime: u64,
+) -> Result<()> {
+    let args = IoctlSpoofArgs {
+        name: encode_name(name)?,
+        uid,
+        gid,
+        mode,
+        mtime,
+    };
+    dev.submit(&Request::AddSpoof(args))
+}
+
+pub fn unspoof(dev: &dyn Device, name: &str) -> Result<()> {
+    dev.submit(&Request::DelSpoof(encode_name(name)?))
+}
+
+pub fn merge(dev: &dyn Device, src: &str, target: &str) -> Result<()> {
+    dev.submit(&Request::AddMerge(encode_payload(src, target)?))
+}
+
+pub fn unmerge(dev: &dyn Device, src: &str) -> Result<()> {
+    dev.submit(&Request::DelMerge(encode_name(src)?))
+}
+
+pub fn set_trust(dev: &dyn Device, gid: u32) -> Result<()> {
+    dev.submit(&Request::SetTrustedGid(gid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_fill_the_buffer_up_to_the_terminator() {
+        let name = "a".repeat(NAME_LEN - 1);
+        let buf = encode_name(&name).unwrap();
+        assert_eq!(buf[NAME_LEN - 1], 0);
+        assert_eq!(decode(&buf).unwrap(), name);
+
+        let err = encode_name(&"a".repeat(NAME_LEN)).unwrap_err();
+        assert_eq!(err.to_string(), "Name too long");
+    }
+
+    #[test]
+    fn payloads_fill_the_buffer_up_to_the_terminator() {
+        // Room left for the target once the source, separator and terminator are in.
+        let target = "b".repeat(PAYLOAD_LEN - "/src|".len() - 1);
+        let buf = encode_payload("/src", &target).unwrap();
+        assert_eq!(buf[PAYLOAD_LEN - 1], 0);
+        assert_eq!(decode_payload(&buf).unwrap(), ("/src", target.as_str()));
+
+        let err = encode_payload("/src", &format!("{}b", target)).unwrap_err();
+        assert_eq!(err.to_string(), "Payload too long");
+    }
+
+    #[test]
+    fn nul_bytes_are_rejected() {
+        assert!(encode_name("/system/a\0b").is_err());
+        assert!(encode_payload("/system/a\0b", "/data/x").is_err());
+        assert!(encode_payload("/system/a", "/data/\0x").is_err());
+    }
+
+    #[test]
+    fn separator_is_only_allowed_in_the_target() {
+        let err = encode_payload("/system/a|b", "/data/x").unwrap_err();
+        assert_eq!(err.to_string(), "Source path contains '|'");
+
+        let buf = encode_payload("/system/a", "/data/x|y").unwrap();
+        assert_eq!(decode_payload(&buf).unwrap(), ("/system/a", "/data/x|y"));
+    }
+
+    #[test]
+    fn decode_needs_a_terminated_separated_payload() {
+        assert!(decode(b"/system/a").is_err());
+        assert!(decode(b"/system/\xff\0").is_err());
+        assert!(decode_payload(b"/system/a\0").is_err());
+        assert_eq!(decode_payload(b"|/x\0").unwrap(), ("", "/x"));
+    }
+}
@@ -1,18 +1,20 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     ffi::CString,
     fs::{self, File, OpenOptions},
-    io::Write,
-    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt, symlink},
-    path::Path,
+    io::{self, Read, Write},
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt, lchown, symlink},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Result, bail};
-use rustix::fs::ioctl_ficlone;
+use anyhow::{Context, Result, bail, ensure};
+use rustix::fs::{AtFlags, CWD, Timespec, Timestamps, copy_file_range, ioctl_ficlone, utimensat};
+use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
-use super::xattr::internal_copy_extended_attributes;
+use super::xattr::{internal_copy_extended_attributes, lgetcaps, lgetfilecon, lsetfilecon};
 
 pub fn atomic_write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, content: C) -> Result<()> {
     let path = path.as_ref();
@@ -51,19 +53,88 @@ pub fn ensure_dir_exists<T: AsRef<Path>>(dir: T) -> Result<()> {
     Ok(())
 }
 
+static COPY_CHUNK_SIZE: AtomicU64 = AtomicU64::new(8 * 1024 * 1024);
+static COPIED_BYTES: AtomicU64 = AtomicU64::new(0);
+static HASHED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_copy_chunk_size(bytes: u64) {
+    COPY_CHUNK_SIZE.store(bytes.max(64 * 1024), Ordering::Relaxed);
+}
+
+/// Bytes written by [`reflink_or_copy`] so far, for progress reporting.
+pub fn copied_bytes() -> u64 {
+    COPIED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Bytes read by [`hash_file`] so far, for progress reporting.
+pub fn hashed_bytes() -> u64 {
+    HASHED_BYTES.load(Ordering::Relaxed)
+}
+
 pub fn reflink_or_copy(src: &Path, dest: &Path) -> Result<u64> {
     let src_file = File::open(src)?;
     let dest_file = File::create(dest)?;
+    let metadata = src_file.metadata()?;
+    let len = metadata.len();
+
+    if ioctl_ficlone(&dest_file, &src_file).is_err() {
+        copy_chunked(&src_file, &dest_file, len)
+            .with_context(|| format!("Failed to copy {}", src.display()))?;
+    } else {
+        COPIED_BYTES.fetch_add(len, Ordering::Relaxed);
+    }
+
+    dest_file.set_permissions(metadata.permissions())?;
+    Ok(len)
+}
+
+// Bounded pieces keep one large file from monopolising the disk and let progress advance.
+fn copy_chunked(src: &File, dst: &File, len: u64) -> Result<()> {
+    let chunk = COPY_CHUNK_SIZE.load(Ordering::Relaxed);
+    let mut copied = 0;
+
+    while copied < len {
+        let want = chunk.min(len - copied);
+        let written = match copy_file_range(src, None, dst, None, want as usize) {
+            Ok(written) => written as u64,
+            Err(_) => io::copy(&mut src.take(want), &mut &*dst)?,
+        };
+        if written == 0 {
+            break;
+        }
+
+        copied += written;
+        COPIED_BYTES.fetch_add(written, Ordering::Relaxed);
+    }
+
+    ensure!(
+        copied == len,
+        "Short copy: {} of {} bytes written",
+        copied,
+        len
+    );
+    Ok(())
+}
+
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 128 * 1024];
 
-    if ioctl_ficlone(&dest_file, &src_file).is_ok() {
-        let metadata = src_file.metadata()?;
-        let len = metadata.len();
-        dest_file.set_permissions(metadata.permissions())?;
-        return Ok(len);
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        HASHED_BYTES.fetch_add(read as u64, Ordering::Relaxed);
     }
-    drop(dest_file);
-    drop(src_file);
-    fs::copy(src, dest).map_err(|e| e.into())
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
 }
 
 fn make_device_node(path: &Path, mode: u32, rdev: u64) -> Result<()> {
@@ -82,8 +153,9 @@ fn native_cp_r(
     src: &Path,
     dst: &Path,
     relative: &Path,
-    _repair: bool,
-    visited: &mut HashSet<(u64, u64)>,
+    repair_root: Option<&Path>,
+    state: &mut CopyState,
+    skip: &dyn Fn(&Path) -> bool,
 ) -> Result<()> {
     if !dst.exists() {
         if src.is_dir() {
@@ -101,6 +173,9 @@ fn native_cp_r(
         let file_name = entry.file_name();
         let dst_path = dst.join(&file_name);
         let next_relative = relative.join(&file_name);
+        if skip(&next_relative) {
+            continue;
+        }
 
         let metadata = entry.metadata()?;
         let ft = metadata.file_type();
@@ -108,39 +183,151 @@ fn native_cp_r(
         let ino = metadata.ino();
 
         if ft.is_dir() {
-            if !visited.insert((dev, ino)) {
+            if !state.visited.insert((dev, ino)) {
                 continue;
             }
-            native_cp_r(&src_path, &dst_path, &next_relative, _repair, visited)?;
-        } else if ft.is_symlink() {
-            if dst_path.exists() {
-                fs::remove_file(&dst_path)?;
+            native_cp_r(
+                &src_path,
+                &dst_path,
+                &next_relative,
+                repair_root,
+                state,
+                skip,
+            )?;
+            // Children are in place, so the directory's own times are no longer disturbed.
+            apply_metadata(&src_path, &dst_path, &metadata);
+        } else {
+            state.links.copy_entry(&src_path, &dst_path, &metadata)?;
+        }
+
+        if let Some(root) = repair_root {
+            repair_context(&dst_path, root, &next_relative);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct CopyState {
+    visited: HashSet<(u64, u64)>,
+    links: LinkMap,
+}
+
+/// Remembers copies of multiply linked files so their other names become hard links.
+#[derive(Default)]
+pub struct LinkMap(HashMap<(u64, u64), PathBuf>);
+
+impl LinkMap {
+    pub fn copy_entry(&mut self, src: &Path, dst: &Path, metadata: &fs::Metadata) -> Result<()> {
+        if !metadata.is_file() || metadata.nlink() < 2 {
+            return copy_entry(src, dst, metadata);
+        }
+
+        let key = (metadata.dev(), metadata.ino());
+        if let Some(first) = self.0.get(&key) {
+            if dst.symlink_metadata().is_ok() {
+                fs::remove_file(dst)?;
             }
-            let link_target = fs::read_link(&src_path)?;
-            symlink(&link_target, &dst_path)?;
-        } else if ft.is_char_device() || ft.is_block_device() || ft.is_fifo() {
-            if dst_path.exists() {
-                fs::remove_file(&dst_path)?;
+            if fs::hard_link(first, dst).is_ok() {
+                return Ok(());
             }
-            let mode = metadata.permissions().mode();
-            let rdev = metadata.rdev();
-            make_device_node(&dst_path, mode, rdev)?;
-        } else {
-            reflink_or_copy(&src_path, &dst_path)?;
         }
 
-        let _ = internal_copy_extended_attributes(&src_path, &dst_path);
+        copy_entry(src, dst, metadata)?;
+        self.0.insert(key, dst.to_path_buf());
+        Ok(())
+    }
+}
+
+/// Copies one non-directory entry, replacing anything already at `dst`.
+pub fn copy_entry(src: &Path, dst: &Path, metadata: &fs::Metadata) -> Result<()> {
+    let ft = metadata.file_type();
+
+    if dst.symlink_metadata().is_ok() {
+        fs::remove_file(dst)?;
+    }
+
+    if ft.is_symlink() {
+        let link_target = fs::read_link(src)?;
+        symlink(&link_target, dst)?;
+    } else if ft.is_char_device() || ft.is_block_device() || ft.is_fifo() {
+        let mode = metadata.permissions().mode();
+        let rdev = metadata.rdev();
+        make_device_node(dst, mode, rdev)?;
+    } else {
+        reflink_or_copy(src, dst)?;
+    }
+
+    apply_metadata(src, dst, metadata);
+    Ok(())
+}
+
+/// Creates `dst` if needed and gives it the permissions and attributes of `src`.
+pub fn copy_dir_attributes(src: &Path, dst: &Path) -> Result<()> {
+    if !dst.exists() {
+        fs::create_dir_all(dst)?;
+    }
+    apply_metadata(src, dst, &src.symlink_metadata()?);
+    Ok(())
+}
+
+// Ownership goes first: chown clears setuid bits and file capabilities, which are restored after it.
+fn apply_metadata(src: &Path, dst: &Path, metadata: &fs::Metadata) {
+    let _ = lchown(dst, Some(metadata.uid()), Some(metadata.gid()));
+    if !metadata.file_type().is_symlink() {
+        let _ = fs::set_permissions(dst, metadata.permissions());
     }
+    let _ = internal_copy_extended_attributes(src, dst);
+    let _ = copy_timestamps(dst, metadata);
+}
+
+pub fn copy_timestamps(dst: &Path, metadata: &fs::Metadata) -> Result<()> {
+    let times = Timestamps {
+        last_access: Timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec() as _,
+        },
+        last_modification: Timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec() as _,
+        },
+    };
+    utimensat(CWD, dst, &times, AtFlags::SYMLINK_NOFOLLOW)?;
     Ok(())
 }
 
-pub fn sync_dir(src: &Path, dst: &Path, repair_context: bool) -> Result<()> {
+/// Relabels `dst` with the SELinux context of the stock file it will cover, if there is one.
+pub fn repair_context(dst: &Path, stock_root: &Path, relative: &Path) {
+    let stock = stock_root.join(relative);
+    if stock.symlink_metadata().is_err() {
+        return;
+    }
+
+    if let Ok(con) = lgetfilecon(&stock)
+        && lgetfilecon(dst).ok().as_deref() != Some(con.as_str())
+    {
+        log::debug!("Repairing context of {} to {}", relative.display(), con);
+        let _ = lsetfilecon(dst, &con);
+    }
+}
+
+pub fn sync_dir(src: &Path, dst: &Path, repair_root: Option<&Path>) -> Result<()> {
+    sync_dir_filtered(src, dst, repair_root, &|_| false)
+}
+
+/// Like [`sync_dir`], leaving out entries whose path relative to `src` matches `skip`.
+pub fn sync_dir_filtered(
+    src: &Path,
+    dst: &Path,
+    repair_root: Option<&Path>,
+    skip: &dyn Fn(&Path) -> bool,
+) -> Result<()> {
     if !src.exists() {
         return Ok(());
     }
     ensure_dir_exists(dst)?;
-    let mut visited = HashSet::new();
-    native_cp_r(src, dst, Path::new(""), repair_context, &mut visited).with_context(|| {
+    let mut state = CopyState::default();
+    native_cp_r(src, dst, Path::new(""), repair_root, &mut state, skip).with_context(|| {
         format!(
             "Failed to natively sync {} to {}",
             src.display(),
@@ -149,6 +336,49 @@ pub fn sync_dir(src: &Path, dst: &Path, repair_context: bool) -> Result<()> {
     })
 }
 
+/// Lists entries of `dst` whose owner, mode, timestamps or file capabilities differ from `src`.
+///
+/// Directory times are skipped since pruning and staging legitimately touch them, as are
+/// times of deduplicated files and SELinux contexts, which may have been repaired on purpose.
+pub fn verify_metadata(src: &Path, dst: &Path) -> Result<Vec<String>> {
+    let mut mismatches = Vec::new();
+
+    for entry in WalkDir::new(dst).min_depth(1) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(dst)?;
+        let Ok(expected) = src.join(relative).symlink_metadata() else {
+            continue;
+        };
+        let actual = entry.path().symlink_metadata()?;
+
+        let mut fields = Vec::new();
+        if expected.file_type() != actual.file_type() {
+            fields.push("type");
+        }
+        if expected.uid() != actual.uid() || expected.gid() != actual.gid() {
+            fields.push("owner");
+        }
+        if !expected.is_symlink() && expected.mode() != actual.mode() {
+            fields.push("mode");
+        }
+        if !expected.is_dir()
+            && actual.nlink() == expected.nlink()
+            && (expected.mtime() != actual.mtime() || expected.mtime_nsec() != actual.mtime_nsec())
+        {
+            fields.push("mtime");
+        }
+        if !expected.is_symlink() && lgetcaps(src.join(relative)) != lgetcaps(entry.path()) {
+            fields.push("capabilities");
+        }
+
+        if !fields.is_empty() {
+            mismatches.push(format!("{} ({})", relative.display(), fields.join(", ")));
+        }
+    }
+
+    Ok(mismatches)
+}
+
 pub fn prune_empty_dirs<P: AsRef<Path>>(root: P) -> Result<()> {
     let root = root.as_ref();
     if !root.exists() {
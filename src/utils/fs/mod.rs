@@ -1,5 +1,7 @@
 pub mod file;
+pub mod path;
 pub mod xattr;
 
 pub use file::*;
+pub use path::*;
 pub use xattr::*;
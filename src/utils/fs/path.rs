@@ -0,0 +1,73 @@
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+const MAX_SYMLINK_HOPS: usize = 40;
+
+pub fn is_host_root(root: &Path) -> bool {
+    root == Path::new("/")
+}
+
+pub fn reroot(root: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix("/") {
+        Ok(relative) => root.join(relative),
+        Err(_) => root.join(path),
+    }
+}
+
+fn push_components(queue: &mut VecDeque<OsString>, path: &Path, front: bool) {
+    let parts: Vec<OsString> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect();
+
+    if front {
+        for part in parts.into_iter().rev() {
+            queue.push_front(part);
+        }
+    } else {
+        queue.extend(parts);
+    }
+}
+
+/// Resolves symlinks in `path` as if `root` were `/`, so absolute links inside an
+/// extracted tree never escape to the host filesystem.
+pub fn resolve_in_root(root: &Path, path: &Path) -> PathBuf {
+    if is_host_root(root) {
+        return path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    }
+
+    let mut pending = VecDeque::new();
+    push_components(&mut pending, path.strip_prefix(root).unwrap_or(path), false);
+
+    let mut resolved = PathBuf::new();
+    let mut hops = 0;
+
+    while let Some(part) = pending.pop_front() {
+        if part == ".." {
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = root.join(&resolved).join(&part);
+        match fs::read_link(&candidate) {
+            Ok(target) if hops < MAX_SYMLINK_HOPS => {
+                hops += 1;
+                if target.is_absolute() {
+                    resolved.clear();
+                }
+                push_components(&mut pending, &target, true);
+            }
+            _ => resolved.push(&part),
+        }
+    }
+
+    root.join(resolved)
+}
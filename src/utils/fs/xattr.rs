@@ -8,6 +8,7 @@ use extattr::{Flags as XattrFlags, lgetxattr, llistxattr, lsetxattr};
 
 const SELINUX_XATTR: &str = "security.selinux";
 const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+const CAPABILITY_XATTR: &str = "security.capability";
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 fn copy_extended_attributes(src: &Path, dst: &Path) -> Result<()> {
@@ -18,6 +19,10 @@ fn copy_extended_attributes(src: &Path, dst: &Path) -> Result<()> {
     if let Ok(opaque) = lgetxattr(src, OVERLAY_OPAQUE_XATTR) {
         let _ = lsetxattr(dst, OVERLAY_OPAQUE_XATTR, &opaque, XattrFlags::empty());
     }
+
+    if let Ok(caps) = lgetxattr(src, CAPABILITY_XATTR) {
+        let _ = lsetxattr(dst, CAPABILITY_XATTR, &caps, XattrFlags::empty());
+    }
     if let Ok(xattrs) = llistxattr(src) {
         for xattr_name in xattrs {
             let name_bytes = xattr_name.as_bytes();
@@ -91,6 +96,16 @@ pub fn lgetfilecon<P: AsRef<Path>>(_path: P) -> Result<String> {
     unimplemented!();
 }
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn lgetcaps<P: AsRef<Path>>(path: P) -> Option<Vec<u8>> {
+    lgetxattr(path.as_ref(), CAPABILITY_XATTR).ok()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn lgetcaps<P: AsRef<Path>>(_path: P) -> Option<Vec<u8>> {
+    unimplemented!();
+}
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn is_overlay_xattr_supported() -> Result<bool> {
     use flate2::read::GzDecoder;
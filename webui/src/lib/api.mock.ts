@@ -5,6 +5,7 @@ import type {
   DeviceInfo,
   Module,
   StorageStatus,
+  SyncProgress,
   SystemInfo,
   ModuleRules,
 } from "./types";
@@ -91,6 +92,20 @@ export const MockAPI = {
       type: "erofs",
     };
   },
+  async getSyncProgress(): Promise<SyncProgress | null> {
+    await delay(300);
+    return {
+      total_bytes: 104857600,
+      copied_bytes: 52428800,
+      hashed_bytes: 104857600,
+      modules_total: 4,
+      modules_done: 2,
+      current: ["mock_module"],
+      elapsed_secs: 6,
+      eta_secs: 3,
+      finished: false,
+    };
+  },
   async getSystemInfo(): Promise<SystemInfo> {
     await delay(300);
     return {
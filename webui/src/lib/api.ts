@@ -5,6 +5,7 @@ import type {
   AppConfig,
   Module,
   StorageStatus,
+  SyncProgress,
   SystemInfo,
   DeviceInfo,
   ModuleRules,
@@ -58,6 +59,7 @@ interface AppAPI {
   saveModules: (modules: Module[]) => Promise<void>;
   saveModuleRules: (moduleId: string, rules: ModuleRules) => Promise<void>;
   getStorageUsage: () => Promise<StorageStatus>;
+  getSyncProgress: () => Promise<SyncProgress | null>;
   getSystemInfo: () => Promise<SystemInfo>;
   getDeviceStatus: () => Promise<DeviceInfo>;
   getVersion: () => Promise<string>;
@@ -143,6 +145,17 @@ const RealAPI: AppAPI = {
     } catch {}
     return { type: null };
   },
+  getSyncProgress: async (): Promise<SyncProgress | null> => {
+    if (!ksuExec) return null;
+    try {
+      const progressFile =
+        (PATHS as Record<string, string>).SYNC_PROGRESS ||
+        "/data/adb/meta-hybrid/run/sync_progress.json";
+      const { errno, stdout } = await ksuExec(`cat "${progressFile}"`);
+      if (errno === 0 && stdout) return JSON.parse(stdout);
+    } catch {}
+    return null;
+  },
   getSystemInfo: async (): Promise<SystemInfo> => {
     if (!ksuExec)
       return { kernel: "-", selinux: "-", mountBase: "-", activeMounts: [] };
@@ -3,12 +3,30 @@
  * SPDX-License-Identifier: GPL-3.0-or-later
  */
 
+export interface SpoofRule {
+  uid?: number;
+  gid?: number;
+  mode?: number;
+  mtime?: number;
+}
+
+export interface PoaceaeRules {
+  hide?: string[];
+  redirect?: Record<string, string>;
+  spoof?: Record<string, SpoofRule>;
+  merge?: Record<string, string>;
+  trusted_gid?: number;
+}
+
 export interface ModuleRules {
   default_mode: MountMode;
   paths: Record<string, string>;
+  storage?: "tmpfs" | "image" | "source";
+  exclude?: string[];
+  poaceae?: PoaceaeRules;
 }
 
-export type OverlayMode = "tmpfs" | "ext4" | "erofs";
+export type OverlayMode = "tmpfs" | "ext4" | "erofs" | "squashfs";
 
 export interface AppConfig {
   moduledir: string;
@@ -17,10 +35,12 @@ export interface AppConfig {
   overlay_mode: OverlayMode;
   disable_umount: boolean;
   allow_umount_coexistence: boolean;
+  repair_context?: boolean;
+  poaceae?: PoaceaeRules;
   logfile?: string;
 }
 
-export type MountMode = "overlay" | "magic" | "ignore";
+export type MountMode = "overlay" | "magic" | "ignore" | "poaceae";
 
 export interface Module {
   id: string;
@@ -36,10 +56,22 @@ export interface Module {
 }
 
 export interface StorageStatus {
-  type: "tmpfs" | "ext4" | "erofs" | "unknown" | null;
+  type: "tmpfs" | "ext4" | "erofs" | "squashfs" | "unknown" | null;
   error?: string;
 }
 
+export interface SyncProgress {
+  total_bytes: number;
+  copied_bytes: number;
+  hashed_bytes: number;
+  modules_total: number;
+  modules_done: number;
+  current: string[];
+  elapsed_secs: number;
+  eta_secs: number | null;
+  finished: boolean;
+}
+
 export interface SystemInfo {
   kernel: string;
   selinux: string;
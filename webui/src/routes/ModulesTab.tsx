@@ -268,6 +268,18 @@ export default function ModulesTab() {
                                 </span>
                                 <span class="opt-sub">Compat</span>
                               </button>
+                              <button
+                                class={`strategy-option ${mod.rules.default_mode === "poaceae" ? "selected" : ""}`}
+                                onClick={() =>
+                                  updateDefaultMode(mod, "poaceae")
+                                }
+                              >
+                                <span class="opt-title">
+                                  {store.L.modules?.modes?.short?.poaceae ??
+                                    "Poaceae"}
+                                </span>
+                                <span class="opt-sub">Mount-free</span>
+                              </button>
                               <button
                                 class={`strategy-option ${mod.rules.default_mode === "ignore" ? "selected" : ""}`}
                                 onClick={() => updateDefaultMode(mod, "ignore")}
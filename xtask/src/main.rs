@@ -280,6 +280,7 @@ export const RUST_PATHS = {{
   MODE_CONFIG: "/data/adb/meta-hybrid/module_mode.conf",
   IMAGE_MNT: "/data/adb/meta-hybrid/mnt",
   DAEMON_STATE: "/data/adb/meta-hybrid/run/daemon_state.json",
+  SYNC_PROGRESS: "/data/adb/meta-hybrid/run/sync_progress.json",
   DAEMON_LOG: "/data/adb/meta-hybrid/daemon.log",
 }} as const;
 export const BUILTIN_PARTITIONS = ["system", "vendor", "product", "system_ext", "odm", "oem", "apex"] as const;